@@ -1,16 +1,108 @@
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate chrono;
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+use std::sync::Mutex;
 use chrono::prelude::*;
+use chrono::Duration;
 
 // Data types
 // -------------------------------------------------------------------------------------------------
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Event {
-    pub timestamp: DateTime<Utc>
+    pub timestamp: DateTime<Utc>,
+    pub sensor_id: String,
+    pub value: Value,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub quality: Quality,
+    // Id of the message the event arrived in, lets a store tell a redelivered message from a new
+    // one. The compact stores do not keep it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>
+}
+
+// Untagged so values read naturally on the wire, variant order decides how json numbers parse
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String)
+}
+
+// OPC DA style quality, the code keeps the two status bits of the OPC quality byte
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    #[default]
+    Good,
+    Uncertain,
+    Bad
+}
+
+// Limits a store enforces by evicting its oldest events, unset limits are not enforced
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub max_events: Option<usize>,
+    pub max_age_ms: Option<i64>,
+    pub max_bytes: Option<usize>
+}
+
+// When a disk backed store forces its writes out to the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    EveryWrite,
+    EveryN(usize),
+    IntervalMs(i64),
+    Never
+}
+
+// What makes two events the same reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DedupKey {
+    // The same series, sensor id and tags, at the same timestamp
+    SensorTime,
+    // The same series and message id, whatever the timestamp. Events without a message id are
+    // never duplicates, two messages may report the same timestamp.
+    MsgId
+}
+
+// What happens when a duplicate carries a different value or quality than the stored event. A
+// duplicate that matches the stored event exactly is always dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    KeepFirst,
+    KeepLast,
+    // Drops the new event and counts the conflict
+    Reject
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dedup {
+    pub key: DedupKey,
+    pub policy: ConflictPolicy
 }
 
 #[derive(Clone, Debug)]
 pub enum StoreType {
     InProcessMemory,
-    Redis
+    CompressedMemory { block_ms: i64 },
+    Redis { url: String },
+    File { path: String, sync: SyncPolicy },
+    Sqlite { path: String },
+    // A ring of capacity events per sensor in files under path
+    Mmap { path: String, capacity: usize },
+    // The last hot_ms in memory, then a file store under path, archived to a sqlite database under
+    // path past warm_ms
+    Tiered { path: String, sync: SyncPolicy, hot_ms: i64, warm_ms: i64 }
 }
 
 #[derive(Clone, Debug)]
@@ -27,19 +119,188 @@ pub struct ServiceInfo {
     pub deserializer: DeserializerType
 }
 
-#[derive(Clone)]
-pub struct Protocol {
-    pub name: String,
-    pub port: u32,
-    pub pub_topic: String,
-    pub sub_topics: Vec<String>
+// The transport a service receives messages over, with the settings of that transport
+#[derive(Clone, Debug)]
+pub enum Protocol {
+    Mqtt { port: u32, pub_topic: String, sub_topics: Vec<String> },
+    // POSTs of one msg or an array of them to any of paths. A {sensor_id} segment in the path, or
+    // else the sensor_header when set, names the stream in place of the msg's own sensor_id.
+    Http { port: u32, paths: Vec<String>, sensor_header: Option<String> }
 }
 
 #[derive(Clone)]
 pub struct StreamInfo {
     pub name: String,
     pub sensor_id: String,
-    pub store_type: StoreType
+    pub store_type: StoreType,
+    pub retention: Retention,
+    // Duplicates are dropped before they reach the store when set
+    pub dedup: Option<Dedup>
+}
+
+
+// Clocks
+// -------------------------------------------------------------------------------------------------
+// Anything that needs the current time asks a clock for it, so windows can be tested exactly and
+// recorded data can be replayed as if it were live
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+// Only moves when told to
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>
+}
+
+// Runs at the speed of its base clock but shifted, e.g. to start a replay at the recorded time
+pub struct OffsetClock {
+    base: Arc<dyn Clock>,
+    offset: Duration
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(now)
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        match self.now.lock() {
+            Ok(mut current) => *current = now,
+            Err(poisoned) => *poisoned.into_inner() = now
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let now = self.now();
+        self.set(now + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(poisoned) => *poisoned.into_inner()
+        }
+    }
+}
+
+impl OffsetClock {
+    pub fn new(base: Arc<dyn Clock>, offset: Duration) -> OffsetClock {
+        OffsetClock {
+            base,
+            offset
+        }
+    }
+
+    pub fn starting_at(base: Arc<dyn Clock>, start: DateTime<Utc>) -> OffsetClock {
+        let offset = start - base.now();
+        OffsetClock::new(base, offset)
+    }
+}
+
+impl Clock for OffsetClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.base.now() + self.offset
+    }
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Event {
+    pub fn new(timestamp: DateTime<Utc>, sensor_id: &str, value: Value) -> Event {
+        Event {
+            timestamp,
+            sensor_id: sensor_id.to_string(),
+            value,
+            tags: BTreeMap::new(),
+            quality: Quality::Good,
+            msg_id: None
+        }
+    }
+
+    pub fn with_tag(mut self, key: &str, value: &str) -> Event {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn with_quality(mut self, quality: Quality) -> Event {
+        self.quality = quality;
+        self
+    }
+
+    pub fn with_msg_id(mut self, msg_id: &str) -> Event {
+        self.msg_id = Some(msg_id.to_string());
+        self
+    }
+
+    // Approximate heap and inline footprint, used for byte budgets
+    pub fn size_bytes(&self) -> usize {
+        let tags: usize = self.tags.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let text = match &self.value {
+            Value::Text(text) => text.len(),
+            _ => 0
+        };
+
+        let msg_id = self.msg_id.as_ref().map_or(0, |msg_id| msg_id.len());
+
+        mem::size_of::<Event>() + self.sensor_id.len() + tags + text + msg_id
+    }
+}
+
+impl Value {
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(value) => Some(*value as f64),
+            Value::Float(value) => Some(*value),
+            Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Value::Text(_) => None
+        }
+    }
+}
+
+impl Quality {
+    pub fn code(self) -> u8 {
+        match self {
+            Quality::Good => 0xC0,
+            Quality::Uncertain => 0x40,
+            Quality::Bad => 0x00
+        }
+    }
+
+    pub fn from_code(code: u8) -> Quality {
+        match code & 0xC0 {
+            0xC0 => Quality::Good,
+            0x40 => Quality::Uncertain,
+            _ => Quality::Bad
+        }
+    }
+}
+
+impl Dedup {
+    pub fn new(key: DedupKey, policy: ConflictPolicy) -> Dedup {
+        Dedup { key, policy }
+    }
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Mqtt { .. } => "mqtt",
+            Protocol::Http { .. } => "http"
+        }
+    }
 }
 
 
@@ -47,8 +308,56 @@ pub struct StreamInfo {
 // -------------------------------------------------------------------------------------------------
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn quality_codes() {
+        assert_eq!(Quality::from_code(Quality::Good.code()), Quality::Good);
+        assert_eq!(Quality::from_code(Quality::Uncertain.code()), Quality::Uncertain);
+        assert_eq!(Quality::from_code(Quality::Bad.code()), Quality::Bad);
+        assert_eq!(Quality::from_code(0xD8), Quality::Good);
+        assert_eq!(Quality::from_code(0x1C), Quality::Bad);
+    }
+
+    #[test]
+    fn value_as_f64() {
+        assert_eq!(Value::Int(3).as_f64(), Some(3.0));
+        assert_eq!(Value::Float(2.5).as_f64(), Some(2.5));
+        assert_eq!(Value::Bool(true).as_f64(), Some(1.0));
+        assert_eq!(Value::Text(String::from("on")).as_f64(), None);
+    }
+
+    #[test]
+    fn manual_and_offset_clocks() {
+        let start = Utc.timestamp_opt(1_000, 0).unwrap();
+        let manual = Arc::new(ManualClock::new(start));
+        let offset = OffsetClock::starting_at(manual.clone(), Utc.timestamp_opt(5_000, 0).unwrap());
+
+        manual.advance(Duration::seconds(10));
+        assert_eq!(manual.now(), Utc.timestamp_opt(1_010, 0).unwrap());
+        assert_eq!(offset.now(), Utc.timestamp_opt(5_010, 0).unwrap());
+
+        manual.set(start);
+        assert_eq!(offset.now(), Utc.timestamp_opt(5_000, 0).unwrap());
+    }
+
+    #[test]
+    fn event_json() {
+        let event = Event::new(Utc::now(), "temp_sensor_1", Value::Int(10))
+            .with_tag("building", "3")
+            .with_quality(Quality::Uncertain);
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+
+        let json = r#"{"timestamp":"2019-05-01T12:00:00Z","sensor_id":"temp_sensor_1","value":10.5}"#;
+        let event = serde_json::from_str::<Event>(json).unwrap();
+        assert_eq!(event.value, Value::Float(10.5));
+        assert_eq!(event.quality, Quality::Good);
+        assert!(event.tags.is_empty());
+    }
 }
@@ -0,0 +1,106 @@
+extern crate chrono;
+extern crate criterion;
+extern crate edge_core;
+extern crate edge_data_store;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BatchSize;
+use criterion::Criterion;
+use criterion::Throughput;
+
+use edge_core::Event;
+use edge_core::Value;
+use edge_data_store::CompressedStore;
+use edge_data_store::InMemory;
+use edge_data_store::Store;
+
+const DAY_S: i64 = 24 * 60 * 60;
+const BATCH: usize = 60;
+
+
+// Data
+// -------------------------------------------------------------------------------------------------
+// A day of 1 Hz temperature readings with 0.1 degree resolution and a little timestamp jitter
+fn one_day() -> Vec<Event> {
+    let start = Utc.timestamp_opt(1_556_712_000, 0).unwrap();
+
+    (0..DAY_S)
+        .map(|i| {
+            let jitter_ms = (i * 7_919) % 23;
+            let value = 20.0 + ((i as f64 / 900.0).sin() * 40.0 + (i % 5) as f64 * 0.3).round() / 10.0;
+            Event::new(start + Duration::seconds(i) + Duration::milliseconds(jitter_ms), "temp_sensor_1",
+                       Value::Float(value))
+        })
+        .collect()
+}
+
+fn fill<S: Store>(store: &mut S, events: &[Event]) {
+    for batch in events.chunks(BATCH) {
+        store.add_events(batch.to_vec());
+    }
+}
+
+fn report_memory(events: &[Event]) {
+    let mut in_memory = InMemory::new();
+    let mut compressed = CompressedStore::new();
+    fill(&mut in_memory, events);
+    fill(&mut compressed, events);
+    compressed.close_block();
+
+    println!("Memory for {} events:", events.len());
+    println!("  in_memory:  {:>10} bytes", in_memory.num_bytes());
+    println!("  compressed: {:>10} bytes ({:.1}x smaller, {:.2} bytes per event)",
+             compressed.num_bytes(),
+             in_memory.num_bytes() as f64 / compressed.num_bytes() as f64,
+             compressed.num_bytes() as f64 / events.len() as f64);
+}
+
+
+// Benchmarks
+// -------------------------------------------------------------------------------------------------
+fn add_events(c: &mut Criterion) {
+    let events = one_day();
+    report_memory(&events);
+
+    let mut group = c.benchmark_group("add_events");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(events.len() as u64));
+
+    group.bench_function("in_memory", |b| {
+        b.iter_batched(InMemory::new, |mut store| fill(&mut store, &events), BatchSize::LargeInput)
+    });
+    group.bench_function("compressed", |b| {
+        b.iter_batched(CompressedStore::new, |mut store| fill(&mut store, &events), BatchSize::LargeInput)
+    });
+
+    group.finish();
+}
+
+fn queries(c: &mut Criterion) {
+    let events = one_day();
+    let mut in_memory = InMemory::new();
+    let mut compressed = CompressedStore::new();
+    fill(&mut in_memory, &events);
+    fill(&mut compressed, &events);
+
+    let start = events[0].timestamp + Duration::hours(6);
+    let end = start + Duration::hours(1);
+
+    let mut group = c.benchmark_group("get_window_of_n");
+    group.throughput(Throughput::Elements(3_600));
+    group.bench_function("in_memory", |b| b.iter(|| in_memory.get_window_of_n(3_600)));
+    group.bench_function("compressed", |b| b.iter(|| compressed.get_window_of_n(3_600)));
+    group.finish();
+
+    let mut group = c.benchmark_group("get_range");
+    group.throughput(Throughput::Elements(3_600));
+    group.bench_function("in_memory", |b| b.iter(|| in_memory.get_range(start, end)));
+    group.bench_function("compressed", |b| b.iter(|| compressed.get_range(start, end)));
+    group.finish();
+}
+
+criterion_group!(benches, add_events, queries);
+criterion_main!(benches);
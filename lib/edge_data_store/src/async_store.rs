@@ -0,0 +1,171 @@
+use std::panic;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use tokio::runtime::Handle;
+
+use edge_core::Event;
+use super::AsyncStore;
+use super::Change;
+use super::Filter;
+use super::Stats;
+use super::Store;
+use super::StoreFuture;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Runs the calls of a blocking store on the blocking thread pool of a tokio runtime, so awaiting
+// them never holds up the runtime's own threads. Calls take turns on the store in the order the
+// pool picks them up.
+pub struct Blocking<S> {
+    store: Arc<Mutex<S>>,
+    runtime: Handle
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl<S: Store + Send + 'static> Blocking<S> {
+    pub fn new(store: S, runtime: Handle) -> Blocking<S> {
+        Blocking {
+            store: Arc::new(Mutex::new(store)),
+            runtime
+        }
+    }
+
+    // A panic in the store is raised again in the awaiting task
+    fn run<T: Send + 'static>(&self, call: impl FnOnce(&mut S) -> T + Send + 'static) -> StoreFuture<'_, T> {
+        let store = self.store.clone();
+        let task = self.runtime.spawn_blocking(move || {
+            let mut store = store.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            call(&mut store)
+        });
+
+        Box::pin(async move {
+            match task.await {
+                Ok(result) => result,
+                Err(e) => match e.try_into_panic() {
+                    Ok(payload) => panic::resume_unwind(payload),
+                    Err(e) => panic!("Store call cancelled: {:?}", e)
+                }
+            }
+        })
+    }
+}
+
+impl<S: Store + Send + 'static> AsyncStore for Blocking<S> {
+    fn add_events(&self, events: Vec<Event>) -> StoreFuture<'_, ()> {
+        self.run(move |store| store.add_events(events))
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> StoreFuture<'_, Vec<Event>> {
+        self.run(move |store| store.get_window(win_len_ms))
+    }
+
+    fn get_window_of_n(&self, n: u64) -> StoreFuture<'_, Vec<Event>> {
+        self.run(move |store| store.get_window_of_n(n))
+    }
+
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> StoreFuture<'_, Vec<Event>> {
+        self.run(move |store| store.get_range(start, end))
+    }
+
+    fn get_before(&self, timestamp: DateTime<Utc>, n: usize) -> StoreFuture<'_, Vec<Event>> {
+        self.run(move |store| store.get_before(timestamp, n))
+    }
+
+    fn get_after(&self, timestamp: DateTime<Utc>, n: usize) -> StoreFuture<'_, Vec<Event>> {
+        self.run(move |store| store.get_after(timestamp, n))
+    }
+
+    // Waits for a call already running on the store to finish, as does stats
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.store.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        self.store.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).stats()
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::runtime::Builder;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    // Stands in for a store waiting on the network
+    struct Slow(InMemory);
+
+    impl Store for Slow {
+        fn add_events(&mut self, events: Vec<Event>) {
+            thread::sleep(Duration::from_millis(200));
+            self.0.add_events(events)
+        }
+
+        fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+            self.0.get_window(win_len_ms)
+        }
+
+        fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+            self.0.get_window_of_n(n)
+        }
+
+        fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+            self.0.iter_from(start)
+        }
+
+        fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+            self.0.iter_before(end)
+        }
+
+        fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+            self.0.subscribe(filter, capacity)
+        }
+    }
+
+    fn events(seconds: std::ops::Range<i64>) -> Vec<Event> {
+        seconds.map(|s| Event::new(Utc.timestamp_opt(s, 0).unwrap(), "temp_sensor_1", Value::Int(s))).collect()
+    }
+
+    #[test]
+    fn slow_writes_leave_the_runtime_free() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let store = Blocking::new(Slow(InMemory::new()), runtime.handle().clone());
+        let changes = store.subscribe(Filter::new(), 10);
+        let started = Instant::now();
+
+        let (_, other_task) = runtime.block_on(async {
+            tokio::join!(store.add_events(events(0..3)), async { started.elapsed() })
+        });
+
+        assert!(other_task < Duration::from_millis(100));
+        assert_eq!(runtime.block_on(store.get_range(MIN_DATETIME, MAX_DATETIME)), events(0..3));
+        assert_eq!(changes.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn wraps_boxed_stores() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let boxed: Box<dyn Store + Send> = Box::new(InMemory::new());
+        let store: Arc<dyn AsyncStore> = Arc::new(Blocking::new(boxed, runtime.handle().clone()));
+
+        runtime.block_on(async {
+            store.add_events(events(0..10)).await;
+            assert_eq!(store.get_window_of_n(2).await, vec![events(9..10).remove(0), events(8..9).remove(0)]);
+            assert_eq!(store.get_before(Utc.timestamp_opt(5, 0).unwrap(), 2).await, events(3..5));
+            assert_eq!(store.get_after(Utc.timestamp_opt(5, 0).unwrap(), 2).await, events(6..8));
+        });
+    }
+}
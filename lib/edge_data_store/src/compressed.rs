@@ -0,0 +1,790 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Quality;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use edge_core::Value;
+use super::Evictions;
+use super::Change;
+use super::Feed;
+use super::Filter;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+use super::stats;
+
+const DEFAULT_BLOCK_MS: i64 = 2 * 60 * 60 * 1000;
+const MAX_BLOCK_MS: i64 = 365 * 24 * 60 * 60 * 1000;
+
+// Payload widths of the delta of delta classes, a class is written as its index + 1 one bits
+// followed by a zero, except the last class which needs no terminating zero
+const DOD_WIDTHS: [u32; 5] = [7, 14, 24, 32, 64];
+
+const KIND_INT: u64 = 0;
+const KIND_FLOAT: u64 = 1;
+const KIND_BOOL: u64 = 2;
+const KIND_TEXT: u64 = 3;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Events are grouped into blocks covering aligned spans of block_ms. The newest block is kept as
+// plain events, older blocks are closed and packed Gorilla style, delta of delta timestamps and
+// xor'd values, and are only unpacked while a query walks through them.
+pub struct CompressedStore {
+    block_ms: i64,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    blocks: BTreeMap<i64, Block>,
+    open: Vec<Event>,
+    open_id: i64,
+    evictions: Evictions,
+    tracker: Tracker,
+    feed: Feed
+}
+
+// A closed block. Sensor ids and tags are kept once per block in the series table and text values
+// in the order they were packed, the bits only hold indexes and changes.
+struct Block {
+    count: usize,
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    series: Vec<Series>,
+    texts: Vec<String>,
+    bits: Vec<u8>
+}
+
+#[derive(Clone, PartialEq)]
+struct Series {
+    sensor_id: String,
+    tags: BTreeMap<String, String>
+}
+
+// What the previous event looked like, shared by the packer and unpacker
+struct Previous {
+    timestamp: DateTime<Utc>,
+    delta: i64,
+    series: usize,
+    quality: Quality,
+    kind: u64,
+    bits: u64,
+    window: Option<(u32, u32)>,
+    text: usize
+}
+
+struct Unpacker<'a> {
+    block: &'a Block,
+    reader: BitReader<'a>,
+    previous: Previous,
+    index: usize
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl CompressedStore {
+    pub fn new() -> CompressedStore {
+        CompressedStore::with_retention(Retention::default())
+    }
+
+    pub fn with_retention(retention: Retention) -> CompressedStore {
+        CompressedStore {
+            block_ms: DEFAULT_BLOCK_MS,
+            retention,
+            clock: Arc::new(SystemClock),
+            blocks: BTreeMap::new(),
+            open: Vec::new(),
+            open_id: i64::MIN,
+            evictions: Evictions::default(),
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> CompressedStore {
+        self.clock = clock;
+        self
+    }
+
+    // Span of each block, only takes effect on an empty store
+    pub fn with_block_ms(mut self, block_ms: i64) -> CompressedStore {
+        if self.is_empty() {
+            self.block_ms = block_ms.clamp(1, MAX_BLOCK_MS);
+        }
+
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.values().map(|block| block.count).sum::<usize>() + self.open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.open.is_empty()
+    }
+
+    // Closed blocks, not counting the open one
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        let closed: usize = self.blocks.values().map(|block| block.size_bytes()).sum();
+        let open: usize = self.open.iter().map(|event| event.size_bytes()).sum();
+        closed + open
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    // Packs the open block now instead of waiting for an event from a later block
+    pub fn close_block(&mut self) {
+        if self.open.is_empty() { return }
+
+        let events = mem::take(&mut self.open);
+        self.blocks.insert(self.open_id, Block::pack(&events));
+    }
+
+    fn block_id(&self, timestamp: DateTime<Utc>) -> i64 {
+        timestamp.timestamp_millis().div_euclid(self.block_ms)
+    }
+
+    fn insert(&mut self, event: Event) {
+        let id = self.block_id(event.timestamp);
+
+        if id > self.open_id {
+            self.close_block();
+            self.open_id = id;
+        } else if id == self.open_id && self.open.is_empty() {
+            // The open block was closed early, take it back rather than repacking on every event
+            if let Some(block) = self.blocks.remove(&id) {
+                self.open = block.unpack().collect();
+            }
+        }
+
+        if id == self.open_id {
+            let index = self.open.partition_point(|older| older.timestamp <= event.timestamp);
+            self.open.insert(index, event);
+        } else {
+            self.insert_late(id, event);
+        }
+    }
+
+    // Late events are rare, so the closed block they belong to is simply unpacked and repacked
+    fn insert_late(&mut self, id: i64, event: Event) {
+        let mut events: Vec<Event> = match self.blocks.get(&id) {
+            Some(block) => block.unpack().collect(),
+            None => Vec::new()
+        };
+
+        let index = events.partition_point(|older| older.timestamp <= event.timestamp);
+        events.insert(index, event);
+        self.blocks.insert(id, Block::pack(&events));
+    }
+
+    // Retention is applied a block at a time and never to the open block
+    fn evict(&mut self) {
+        while let Some((&id, oldest)) = self.blocks.first_key_value() {
+            let removed = oldest.count;
+
+            let by_count = match self.retention.max_events {
+                Some(max_events) => self.len() - removed >= max_events,
+                None => false
+            };
+            let by_age = match self.retention.max_age_ms {
+                Some(max_age_ms) => (self.clock.now() - oldest.last).num_milliseconds() > max_age_ms,
+                None => false
+            };
+            let by_bytes = match self.retention.max_bytes {
+                Some(max_bytes) => self.num_bytes() > max_bytes,
+                None => false
+            };
+
+            if !(by_count || by_age || by_bytes) { break }
+
+            self.blocks.remove(&id);
+
+            if by_count {
+                self.evictions.count += removed as u64;
+            } else if by_age {
+                self.evictions.age += removed as u64;
+            } else {
+                self.evictions.bytes += removed as u64;
+            }
+        }
+    }
+
+    fn newest_first(&self, end_id: i64) -> impl Iterator<Item = Event> + '_ {
+        let open = self.open.iter().rev().cloned();
+        let closed = self.blocks.range(..=end_id)
+            .rev()
+            .flat_map(|(_, block)| {
+                let mut events: Vec<Event> = block.unpack().collect();
+                events.reverse();
+                events
+            });
+
+        open.chain(closed)
+    }
+}
+
+impl Default for CompressedStore {
+    fn default() -> CompressedStore {
+        CompressedStore::new()
+    }
+}
+
+impl Store for CompressedStore {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let evictions = self.evictions;
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
+
+        for event in events {
+            self.insert(event);
+        }
+
+        self.evict();
+        self.feed.inserted(&inserted);
+        self.feed.evicted(evictions, self.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+
+        self.iter_before(now + Duration::nanoseconds(1))
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.newest_first(i64::MAX)
+            .take(n as usize)
+            .collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let closed = self.blocks.range(self.block_id(start)..)
+            .flat_map(|(_, block)| block.unpack());
+
+        Box::new(closed.chain(self.open.iter().cloned()).skip_while(move |event| event.timestamp < start))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.newest_first(self.block_id(end)).skip_while(move |event| event.timestamp >= end))
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        let (oldest, newest) = stats::bounds(self);
+
+        Stats {
+            count: self.len(),
+            bytes: self.num_bytes(),
+            oldest,
+            newest,
+            evictions: self.evictions,
+            ..self.tracker.stats(self.clock.now())
+        }
+    }
+}
+
+
+// Blocks
+// -------------------------------------------------------------------------------------------------
+// Every event after the first writes its timestamp as a delta of delta in nanoseconds, then one
+// bit each for an unchanged series, quality and value kind, and finally its value. Ints and floats
+// are xor'd with the previous numeric value and only the meaningful bits are kept, bools take a
+// single bit and text values are taken from the block in order.
+impl Block {
+    // Events must be sorted oldest first and all fall inside one block
+    fn pack(events: &[Event]) -> Block {
+        let first = &events[0];
+        let mut block = Block {
+            count: events.len(),
+            first: first.timestamp,
+            last: events[events.len() - 1].timestamp,
+            series: vec![Series::of(first)],
+            texts: Vec::new(),
+            bits: Vec::new()
+        };
+
+        // The series table is filled first so every index is written with its final width
+        for event in events.iter() {
+            if !block.series.iter().any(|series| series.matches(event)) {
+                block.series.push(Series::of(event));
+            }
+        }
+
+        let mut writer = BitWriter::new();
+        let mut previous = Previous::new(first.timestamp);
+
+        for (index, event) in events.iter().enumerate() {
+            if index > 0 {
+                let delta = (event.timestamp - previous.timestamp).num_nanoseconds().unwrap_or(0);
+                writer.write_dod(delta - previous.delta);
+                previous.timestamp = event.timestamp;
+                previous.delta = delta;
+            }
+
+            let series = block.series.iter().position(|series| series.matches(event)).unwrap_or(0);
+            if series == previous.series {
+                writer.write_bit(false);
+            } else {
+                writer.write_bit(true);
+                writer.write(series as u64, index_width(block.series.len()));
+                previous.series = series;
+            }
+
+            if event.quality == previous.quality {
+                writer.write_bit(false);
+            } else {
+                writer.write_bit(true);
+                writer.write(quality_index(event.quality), 2);
+                previous.quality = event.quality;
+            }
+
+            let kind = value_kind(&event.value);
+            if kind == previous.kind {
+                writer.write_bit(false);
+            } else {
+                writer.write_bit(true);
+                writer.write(kind, 2);
+                previous.kind = kind;
+            }
+
+            match &event.value {
+                Value::Int(value) => previous.write_xor(&mut writer, *value as u64),
+                Value::Float(value) => previous.write_xor(&mut writer, value.to_bits()),
+                Value::Bool(value) => writer.write_bit(*value),
+                Value::Text(value) => block.texts.push(value.clone())
+            }
+        }
+
+        block.bits = writer.bytes;
+        block
+    }
+
+    fn unpack(&self) -> Unpacker<'_> {
+        Unpacker {
+            block: self,
+            reader: BitReader::new(&self.bits),
+            previous: Previous::new(self.first),
+            index: 0
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        let series: usize = self.series.iter()
+            .map(|series| series.sensor_id.len() + series.tags.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>())
+            .sum();
+        let texts: usize = self.texts.iter().map(|text| text.len()).sum();
+
+        mem::size_of::<Block>() + self.bits.len() + series + texts
+    }
+}
+
+impl Series {
+    fn of(event: &Event) -> Series {
+        Series {
+            sensor_id: event.sensor_id.clone(),
+            tags: event.tags.clone()
+        }
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        self.sensor_id == event.sensor_id && self.tags == event.tags
+    }
+}
+
+impl Previous {
+    fn new(first: DateTime<Utc>) -> Previous {
+        Previous {
+            timestamp: first,
+            delta: 0,
+            series: 0,
+            quality: Quality::Good,
+            kind: KIND_FLOAT,
+            bits: 0,
+            window: None,
+            text: 0
+        }
+    }
+
+    // A zero bit for an unchanged value, otherwise the meaningful bits of the xor either inside
+    // the previous leading/trailing zero window or with a new window written out first
+    fn write_xor(&mut self, writer: &mut BitWriter, bits: u64) {
+        let xor = bits ^ self.bits;
+        self.bits = bits;
+
+        if xor == 0 {
+            writer.write_bit(false);
+            return
+        }
+
+        writer.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+
+        match self.window {
+            Some((prev_leading, prev_trailing)) if leading >= prev_leading && trailing >= prev_trailing => {
+                writer.write_bit(false);
+                writer.write(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+            },
+            _ => {
+                let meaningful = 64 - leading - trailing;
+                writer.write_bit(true);
+                writer.write(leading as u64, 5);
+                writer.write((meaningful - 1) as u64, 6);
+                writer.write(xor >> trailing, meaningful);
+                self.window = Some((leading, trailing));
+            }
+        }
+    }
+
+    fn read_xor(&mut self, reader: &mut BitReader) -> Option<u64> {
+        if reader.read_bit()? {
+            if reader.read_bit()? {
+                let leading = reader.read(5)? as u32;
+                let meaningful = reader.read(6)? as u32 + 1;
+                self.window = Some((leading, 64 - leading - meaningful));
+            }
+
+            let (leading, trailing) = self.window?;
+            self.bits ^= reader.read(64 - leading - trailing)? << trailing;
+        }
+
+        Some(self.bits)
+    }
+}
+
+impl<'a> Unpacker<'a> {
+    fn unpack_next(&mut self) -> Option<Event> {
+        let previous = &mut self.previous;
+        let reader = &mut self.reader;
+
+        if self.index > 0 {
+            previous.delta += reader.read_dod()?;
+            previous.timestamp += Duration::nanoseconds(previous.delta);
+        }
+
+        if reader.read_bit()? {
+            previous.series = reader.read(index_width(self.block.series.len()))? as usize;
+        }
+
+        if reader.read_bit()? {
+            previous.quality = quality_from_index(reader.read(2)?);
+        }
+
+        if reader.read_bit()? {
+            previous.kind = reader.read(2)?;
+        }
+
+        let value = match previous.kind {
+            KIND_INT => Value::Int(previous.read_xor(reader)? as i64),
+            KIND_FLOAT => Value::Float(f64::from_bits(previous.read_xor(reader)?)),
+            KIND_BOOL => Value::Bool(reader.read_bit()?),
+            _ => {
+                previous.text += 1;
+                Value::Text(self.block.texts.get(previous.text - 1)?.clone())
+            }
+        };
+
+        let series = self.block.series.get(previous.series)?;
+        self.index += 1;
+
+        Some(Event {
+            timestamp: previous.timestamp,
+            sensor_id: series.sensor_id.clone(),
+            value,
+            tags: series.tags.clone(),
+            quality: previous.quality,
+            msg_id: None
+        })
+    }
+}
+
+impl<'a> Iterator for Unpacker<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        if self.index >= self.block.count { return None }
+
+        let event = self.unpack_next();
+        if event.is_none() {
+            println!("Error unpacking compressed block at {}", self.block.first);
+            self.index = self.block.count;
+        }
+
+        event
+    }
+}
+
+fn index_width(len: usize) -> u32 {
+    usize::BITS - (len.max(1) - 1).leading_zeros()
+}
+
+fn value_kind(value: &Value) -> u64 {
+    match value {
+        Value::Int(_) => KIND_INT,
+        Value::Float(_) => KIND_FLOAT,
+        Value::Bool(_) => KIND_BOOL,
+        Value::Text(_) => KIND_TEXT
+    }
+}
+
+fn quality_index(quality: Quality) -> u64 {
+    match quality {
+        Quality::Good => 0,
+        Quality::Uncertain => 1,
+        Quality::Bad => 2
+    }
+}
+
+fn quality_from_index(index: u64) -> Quality {
+    match index {
+        0 => Quality::Good,
+        1 => Quality::Uncertain,
+        _ => Quality::Bad
+    }
+}
+
+
+// Bits
+// -------------------------------------------------------------------------------------------------
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            len: 0
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.len % 8);
+        }
+
+        self.len += 1;
+    }
+
+    // The low n bits of value, most significant first
+    fn write(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn write_dod(&mut self, dod: i64) {
+        let zigzag = ((dod << 1) ^ (dod >> 63)) as u64;
+
+        if zigzag == 0 {
+            self.write_bit(false);
+            return
+        }
+
+        for (class, width) in DOD_WIDTHS.iter().enumerate() {
+            let last = class == DOD_WIDTHS.len() - 1;
+            if !last && zigzag >> width != 0 { continue }
+
+            self.write(u64::MAX, class as u32 + 1);
+            if !last {
+                self.write_bit(false);
+            }
+            self.write(zigzag, *width);
+            return
+        }
+    }
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            bytes,
+            pos: 0
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read(&mut self, n: u32) -> Option<u64> {
+        let mut value = 0;
+
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+
+        Some(value)
+    }
+
+    fn read_dod(&mut self) -> Option<i64> {
+        let mut class = 0;
+
+        while class < DOD_WIDTHS.len() && self.read_bit()? {
+            class += 1;
+        }
+
+        if class == 0 { return Some(0) }
+
+        let zigzag = self.read(DOD_WIDTHS[class - 1])?;
+        Some((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64))
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use super::super::Cursor;
+    use super::super::InMemory;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    const HOUR_S: i64 = 60 * 60;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn at_seconds(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter()
+            .map(|s| Event::new(time(*s), "temp_sensor_1", Value::Int(*s)))
+            .collect()
+    }
+
+    fn values(events: &[Event]) -> Vec<Value> {
+        events.iter().map(|event| event.value.clone()).collect()
+    }
+
+    fn one_hz(start_s: i64, n: i64) -> Vec<Event> {
+        (0..n)
+            .map(|i| {
+                let value = 20.0 + ((i as f64 / 600.0).sin() * 50.0).round() / 10.0;
+                Event::new(time(start_s + i), "temp_sensor_1", Value::Float(value))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_every_kind_of_event() {
+        let base = time(1_000_000);
+        let events = vec![
+            Event::new(base, "temp_sensor_1", Value::Float(21.5)),
+            Event::new(base, "temp_sensor_1", Value::Float(-0.0)).with_quality(Quality::Uncertain),
+            Event::new(base + Duration::nanoseconds(1), "temp_sensor_1", Value::Int(-7)),
+            Event::new(base + Duration::milliseconds(250), "temp_sensor_1", Value::Int(i64::MAX)),
+            Event::new(base + Duration::seconds(1), "temp_sensor_1", Value::Bool(true)).with_tag("index", "1"),
+            Event::new(base + Duration::seconds(2), "door_1", Value::Text(String::from("open"))),
+            Event::new(base + Duration::seconds(3), "door_1", Value::Text(String::from("closed")))
+                .with_quality(Quality::Bad),
+            Event::new(base + Duration::days(30), "temp_sensor_1", Value::Float(f64::MIN_POSITIVE)),
+            Event::new(base + Duration::days(30) + Duration::seconds(1), "temp_sensor_1", Value::Bool(false))
+        ];
+
+        let mut store = CompressedStore::new();
+        store.add_events(events.clone());
+        store.close_block();
+
+        assert_eq!(store.num_blocks(), 2);
+        assert_eq!(store.get_range(MIN_DATETIME, MAX_DATETIME), events);
+    }
+
+    #[test]
+    fn packs_regular_series_small() {
+        let events = one_hz(0, 24 * HOUR_S);
+        let mut in_memory = InMemory::new();
+        let mut store = CompressedStore::new();
+        in_memory.add_events(events.clone());
+        store.add_events(events.clone());
+        store.close_block();
+
+        assert_eq!(store.num_blocks(), 12);
+        assert!(store.num_bytes() * 20 < in_memory.num_bytes());
+        assert_eq!(store.get_range(MIN_DATETIME, MAX_DATETIME), events);
+    }
+
+    #[test]
+    fn late_events_land_in_closed_blocks() {
+        let mut store = CompressedStore::new().with_block_ms(10_000);
+        store.add_events(at_seconds(&[10, 12, 20, 30]));
+        assert_eq!(store.num_blocks(), 2);
+
+        store.add_events(at_seconds(&[11, 5, 31, 19]));
+        assert_eq!(store.num_blocks(), 3);
+        assert_eq!(values(&store.get_range(MIN_DATETIME, MAX_DATETIME)),
+                   values(&at_seconds(&[5, 10, 11, 12, 19, 20, 30, 31])));
+
+        store.close_block();
+        store.add_events(at_seconds(&[35]));
+        assert_eq!(store.num_blocks(), 3);
+        assert_eq!(values(&store.get_window_of_n(3)), values(&at_seconds(&[35, 31, 30])));
+    }
+
+    #[test]
+    fn queries_span_blocks() {
+        let mut store = CompressedStore::new().with_block_ms(10_000);
+        store.add_events(at_seconds(&[10, 20, 20, 20, 30, 40, 50]));
+
+        assert_eq!(values(&store.get_range(time(20), time(40))), values(&at_seconds(&[20, 20, 20, 30])));
+        assert_eq!(values(&store.get_before(time(40), 2)), values(&at_seconds(&[20, 30])));
+        assert_eq!(values(&store.get_after(time(20), 2)), values(&at_seconds(&[30, 40])));
+
+        let mut cursor = Cursor::new();
+        assert_eq!(store.read(&mut cursor, 3).len(), 3);
+        assert_eq!(values(&store.read(&mut cursor, 2)), values(&at_seconds(&[20, 30])));
+    }
+
+    #[test]
+    fn window_and_retention_follow_the_clock() {
+        let clock = Arc::new(ManualClock::new(time(50)));
+        let retention = Retention { max_age_ms: Some(25_000), ..Retention::default() };
+        let mut store = CompressedStore::with_retention(retention)
+            .with_block_ms(10_000)
+            .with_clock(clock.clone());
+        store.add_events(at_seconds(&[10, 15, 20, 30, 40, 50, 60]));
+
+        assert_eq!(store.evictions().age, 3);
+        assert_eq!(values(&store.get_window(20_000)), values(&at_seconds(&[50, 40, 30])));
+
+        clock.set(time(100));
+        store.add_events(at_seconds(&[70]));
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.evictions().total(), 7);
+    }
+}
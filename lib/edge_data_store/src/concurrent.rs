@@ -0,0 +1,555 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use super::Change;
+use super::Evictions;
+use super::Feed;
+use super::Filter;
+use super::SharedStore;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+
+const CHUNK_SIZE: usize = 1024;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Readers clone the current snapshot and query it without holding any lock, so they never wait
+// for a write in progress, only for the pointer swap that publishes it. The single writer works
+// on its own copy and chunks still shared with a published snapshot are copied before they change,
+// so a write costs at most a chunk copy plus one pointer per chunk.
+pub struct ConcurrentStore {
+    current: RwLock<Arc<Snapshot>>,
+    writer: Mutex<Snapshot>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    tracker: Mutex<Tracker>,
+    feed: Feed
+}
+
+// Immutable view of a ConcurrentStore. Events are held oldest first in chunks that never overlap.
+#[derive(Clone)]
+pub struct Snapshot {
+    chunks: Vec<Arc<Vec<Event>>>,
+    len: usize,
+    num_bytes: usize,
+    evictions: Evictions
+}
+
+// Owns its snapshot so it can outlive the borrow of the store it came from
+struct SnapshotIter {
+    snapshot: Arc<Snapshot>,
+    chunk: usize,
+    offset: usize,
+    ascending: bool
+}
+
+// Shares any Store behind a mutex, readers and the writer take turns
+pub struct Locked<S: Store + Send> {
+    store: Mutex<S>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl ConcurrentStore {
+    pub fn new() -> ConcurrentStore {
+        ConcurrentStore::with_retention(Retention::default())
+    }
+
+    pub fn with_retention(retention: Retention) -> ConcurrentStore {
+        ConcurrentStore {
+            current: RwLock::new(Arc::new(Snapshot::empty())),
+            writer: Mutex::new(Snapshot::empty()),
+            retention,
+            clock: Arc::new(SystemClock),
+            tracker: Mutex::new(Tracker::new()),
+            feed: Feed::new()
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ConcurrentStore {
+        self.clock = clock;
+        self
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        let current = self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        current.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.snapshot().num_bytes()
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.snapshot().evictions()
+    }
+}
+
+impl Default for ConcurrentStore {
+    fn default() -> ConcurrentStore {
+        ConcurrentStore::new()
+    }
+}
+
+impl SharedStore for ConcurrentStore {
+    fn add_events(&self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        // Writers queue here, readers never touch this lock
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.tracker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).added(&events, self.clock.now());
+        let evictions = writer.evictions;
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
+
+        for event in events {
+            writer.insert(event);
+        }
+        writer.evict(&self.retention, self.clock.now());
+
+        let snapshot = Arc::new(writer.clone());
+        let mut current = self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *current = snapshot;
+        drop(current);
+
+        self.feed.inserted(&inserted);
+        self.feed.evicted(evictions, writer.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+
+        self.snapshot()
+            .iter_before(now + Duration::nanoseconds(1))
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .cloned()
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.snapshot()
+            .iter()
+            .rev()
+            .take(n as usize)
+            .cloned()
+            .collect()
+    }
+
+    fn iter_from(&self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_> {
+        let snapshot = self.snapshot();
+        let (chunk, offset) = snapshot.lower_bound(start);
+
+        Box::new(SnapshotIter { snapshot, chunk, offset, ascending: true })
+    }
+
+    fn iter_before(&self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_> {
+        let snapshot = self.snapshot();
+        let (chunk, offset) = snapshot.lower_bound(end);
+
+        Box::new(SnapshotIter { snapshot, chunk, offset, ascending: false })
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        let tracked = self.tracker.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).stats(self.clock.now());
+        let snapshot = self.snapshot();
+        let oldest = snapshot.iter().next().map(|event| event.timestamp);
+        let newest = snapshot.iter().next_back().map(|event| event.timestamp);
+
+        Stats {
+            count: snapshot.len(),
+            bytes: snapshot.num_bytes(),
+            oldest,
+            newest,
+            evictions: snapshot.evictions(),
+            ..tracked
+        }
+    }
+}
+
+impl Snapshot {
+    fn empty() -> Snapshot {
+        Snapshot {
+            chunks: Vec::new(),
+            len: 0,
+            num_bytes: 0,
+            evictions: Evictions::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    // Every event, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Event> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    // Events at or after start, oldest first
+    pub fn iter_from(&self, start: DateTime<Utc>) -> impl Iterator<Item = &Event> {
+        let (chunk, offset) = self.lower_bound(start);
+        let first = self.chunks.get(chunk).map(|first| &first[offset..]).unwrap_or(&[]);
+        let rest = self.chunks.get(chunk + 1..).unwrap_or(&[]);
+
+        first.iter().chain(rest.iter().flat_map(|chunk| chunk.iter()))
+    }
+
+    // Events strictly before end, newest first
+    pub fn iter_before(&self, end: DateTime<Utc>) -> impl Iterator<Item = &Event> {
+        let (chunk, offset) = self.lower_bound(end);
+        let last = self.chunks.get(chunk).map(|last| &last[..offset]).unwrap_or(&[]);
+
+        last.iter().rev().chain(self.chunks[..chunk].iter().rev().flat_map(|chunk| chunk.iter().rev()))
+    }
+
+    // Position of the first event at or after timestamp
+    fn lower_bound(&self, timestamp: DateTime<Utc>) -> (usize, usize) {
+        let chunk = self.chunks.partition_point(|chunk| chunk[chunk.len() - 1].timestamp < timestamp);
+
+        match self.chunks.get(chunk) {
+            Some(events) => (chunk, events.partition_point(|event| event.timestamp < timestamp)),
+            None => (chunk, 0)
+        }
+    }
+
+    fn insert(&mut self, event: Event) {
+        self.len += 1;
+        self.num_bytes += event.size_bytes();
+
+        let in_order = match self.chunks.last() {
+            Some(chunk) => chunk[chunk.len() - 1].timestamp <= event.timestamp,
+            None => true
+        };
+
+        if in_order {
+            match self.chunks.last_mut() {
+                Some(chunk) if chunk.len() < CHUNK_SIZE => Arc::make_mut(chunk).push(event),
+                _ => self.chunks.push(Arc::new(vec![event]))
+            }
+            return
+        }
+
+        // A late event goes into the first chunk holding anything newer, which is split if it
+        // grows too large
+        let index = self.chunks.partition_point(|chunk| chunk[chunk.len() - 1].timestamp <= event.timestamp);
+        let chunk = Arc::make_mut(&mut self.chunks[index]);
+        let offset = chunk.partition_point(|older| older.timestamp <= event.timestamp);
+        chunk.insert(offset, event);
+
+        if chunk.len() > 2 * CHUNK_SIZE {
+            let newer = chunk.split_off(CHUNK_SIZE);
+            self.chunks.insert(index + 1, Arc::new(newer));
+        }
+    }
+
+    fn evict(&mut self, retention: &Retention, now: DateTime<Utc>) {
+        if let Some(max_events) = retention.max_events {
+            let excess = self.len.saturating_sub(max_events);
+            self.drop_oldest(excess);
+            self.evictions.count += excess as u64;
+        }
+
+        if let Some(max_age_ms) = retention.max_age_ms {
+            let expired = self.iter()
+                .take_while(|event| (now - event.timestamp).num_milliseconds() > max_age_ms)
+                .count();
+            self.drop_oldest(expired);
+            self.evictions.age += expired as u64;
+        }
+
+        if let Some(max_bytes) = retention.max_bytes {
+            let mut freed = 0;
+            let over = self.iter()
+                .take_while(|event| {
+                    if self.num_bytes - freed <= max_bytes { return false }
+                    freed += event.size_bytes();
+                    true
+                })
+                .count();
+            self.drop_oldest(over);
+            self.evictions.bytes += over as u64;
+        }
+    }
+
+    fn drop_oldest(&mut self, mut n: usize) {
+        // Chunks dropped whole are counted through the Arc and let go together, only the chunk
+        // that keeps some of its events is copied when shared
+        let mut whole = 0;
+        for chunk in self.chunks.iter() {
+            if chunk.len() > n { break }
+            n -= chunk.len();
+            self.len -= chunk.len();
+            self.num_bytes -= chunk.iter().map(|event| event.size_bytes()).sum::<usize>();
+            whole += 1;
+        }
+        self.chunks.drain(..whole);
+
+        if let Some(first) = self.chunks.first_mut().filter(|_| n > 0) {
+            let first = Arc::make_mut(first);
+            self.len -= n;
+            self.num_bytes -= first[..n].iter().map(|event| event.size_bytes()).sum::<usize>();
+            first.drain(..n);
+        }
+    }
+}
+
+impl Iterator for SnapshotIter {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let chunks = &self.snapshot.chunks;
+
+        if self.ascending {
+            while let Some(chunk) = chunks.get(self.chunk) {
+                if let Some(event) = chunk.get(self.offset) {
+                    self.offset += 1;
+                    return Some(event.clone())
+                }
+                self.chunk += 1;
+                self.offset = 0;
+            }
+        } else {
+            loop {
+                if self.offset > 0 {
+                    self.offset -= 1;
+                    return chunks.get(self.chunk).and_then(|chunk| chunk.get(self.offset)).cloned()
+                }
+                if self.chunk == 0 { break }
+                self.chunk -= 1;
+                self.offset = chunks[self.chunk].len();
+            }
+        }
+
+        None
+    }
+}
+
+impl<S: Store + Send> Locked<S> {
+    pub fn new(store: S) -> Locked<S> {
+        Locked {
+            store: Mutex::new(store)
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn with_store<T>(&self, f: impl FnOnce(&mut S) -> T) -> T {
+        let mut store = self.store.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut store)
+    }
+}
+
+// Iterators collect under the lock, a borrow of the store can not outlive the guard
+impl<S: Store + Send> SharedStore for Locked<S> {
+    fn add_events(&self, events: Vec<Event>) {
+        self.with_store(|store| store.add_events(events))
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        self.with_store(|store| store.get_window(win_len_ms))
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.with_store(|store| store.get_window_of_n(n))
+    }
+
+    fn iter_from(&self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_> {
+        let events: Vec<Event> = self.with_store(|store| store.iter_from(start).collect());
+        Box::new(events.into_iter())
+    }
+
+    fn iter_before(&self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_> {
+        let events: Vec<Event> = self.with_store(|store| store.iter_before(end).collect());
+        Box::new(events.into_iter())
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.with_store(|store| store.subscribe(filter, capacity))
+    }
+
+    fn stats(&self) -> Stats {
+        self.with_store(|store| store.stats())
+    }
+
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Event> {
+        self.with_store(|store| store.get_range(start, end))
+    }
+
+    fn get_before(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        self.with_store(|store| store.get_before(timestamp, n))
+    }
+
+    fn get_after(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        self.with_store(|store| store.get_after(timestamp, n))
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::super::Cursor;
+    use super::super::InMemory;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn at_seconds(seconds: impl Iterator<Item = i64>) -> Vec<Event> {
+        seconds.map(|s| Event::new(time(s), "temp_sensor_1", Value::Int(s))).collect()
+    }
+
+    fn seconds(events: &[Event]) -> Vec<i64> {
+        events.iter().map(|event| event.timestamp.timestamp()).collect()
+    }
+
+    fn is_sorted(events: &[Event]) -> bool {
+        events.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp)
+    }
+
+    #[test]
+    fn late_events_keep_chunks_sorted() {
+        let store = ConcurrentStore::new();
+        store.add_events(at_seconds((0..3 * CHUNK_SIZE as i64).map(|s| s * 2)));
+        let before = store.snapshot();
+
+        store.add_events(at_seconds((0..3 * CHUNK_SIZE as i64).map(|s| s * 2 + 1)));
+        let all = store.get_range(MIN_DATETIME, MAX_DATETIME);
+
+        assert_eq!(before.len(), 3 * CHUNK_SIZE);
+        assert_eq!(all.len(), 6 * CHUNK_SIZE);
+        assert_eq!(seconds(&all), (0..6 * CHUNK_SIZE as i64).collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.get_before(time(3_000), 2)), vec![2_998, 2_999]);
+        assert_eq!(seconds(&store.get_after(time(3_000), 2)), vec![3_001, 3_002]);
+        assert_eq!(seconds(&store.get_window_of_n(2)), vec![6 * CHUNK_SIZE as i64 - 1, 6 * CHUNK_SIZE as i64 - 2]);
+        assert!(is_sorted(&before.iter().cloned().collect::<Vec<Event>>()));
+    }
+
+    #[test]
+    fn retention_drops_oldest() {
+        let clock = Arc::new(ManualClock::new(time(5_000)));
+        let retention = Retention { max_events: Some(1_500), max_age_ms: Some(3_000_000), ..Retention::default() };
+        let store = ConcurrentStore::with_retention(retention).with_clock(clock.clone());
+        store.add_events(at_seconds(0..5_000));
+
+        assert_eq!(store.len(), 1_500);
+        assert_eq!(store.evictions().count, 3_500);
+        assert_eq!(seconds(&store.get_window(10_000)), (4_990..5_000).rev().collect::<Vec<i64>>());
+
+        // Chunks still shared with a snapshot are left as they were
+        let before = store.snapshot();
+        clock.set(time(7_000));
+        store.add_events(at_seconds(5_000..5_001));
+        assert_eq!(before.len(), 1_500);
+        assert_eq!(seconds(&before.iter().take(1).cloned().collect::<Vec<Event>>()), vec![3_500]);
+        assert_eq!(store.len(), 1_001);
+        assert_eq!(store.evictions(), Evictions { count: 3_501, age: 499, bytes: 0 });
+        assert_eq!(store.num_bytes(), store.get_range(MIN_DATETIME, MAX_DATETIME).iter().map(Event::size_bytes).sum::<usize>());
+    }
+
+    #[test]
+    fn shared_through_an_arc_as_a_store() {
+        let shared = Arc::new(Locked::new(InMemory::new()));
+        let mut store: Box<dyn Store + Send> = Box::new(shared.clone());
+        store.add_events(at_seconds(0..10));
+
+        let mut cursor = Cursor::new();
+        assert_eq!(seconds(&store.read(&mut cursor, 4)), vec![0, 1, 2, 3]);
+        assert_eq!(seconds(&shared.get_range(time(4), time(6))), vec![4, 5]);
+        assert_eq!(shared.get_window_of_n(20).len(), 10);
+    }
+
+    #[test]
+    fn readers_run_alongside_a_writer() {
+        let store = Arc::new(ConcurrentStore::with_retention(Retention { max_events: Some(20_000), ..Retention::default() }));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<thread::JoinHandle<usize>> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let done = done.clone();
+
+                thread::spawn(move || {
+                    let mut reads = 0;
+                    let mut newest = MIN_DATETIME;
+
+                    while !done.load(Ordering::SeqCst) {
+                        let snapshot = store.snapshot();
+                        let events: Vec<Event> = snapshot.iter().cloned().collect();
+                        assert_eq!(events.len(), snapshot.len());
+                        assert!(is_sorted(&events));
+
+                        let latest = store.get_window_of_n(50);
+                        assert!(latest.windows(2).all(|pair| pair[0].timestamp >= pair[1].timestamp));
+                        if let Some(event) = latest.first() {
+                            assert!(event.timestamp >= newest);
+                            newest = event.timestamp;
+                        }
+
+                        reads += 1;
+                    }
+
+                    reads
+                })
+            })
+            .collect();
+
+        for batch in 0..500 {
+            let start = batch * 100;
+            let mut events = at_seconds(start..start + 100);
+            events.swap(10, 90);
+            store.as_ref().add_events(events);
+        }
+        done.store(true, Ordering::SeqCst);
+
+        let reads: usize = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+        assert!(reads > 0);
+        assert_eq!(store.len(), 20_000);
+        assert!(is_sorted(&store.get_range(MIN_DATETIME, MAX_DATETIME)));
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
+pub use edge_core::ConflictPolicy;
+pub use edge_core::Dedup;
+pub use edge_core::DedupKey;
+use super::Change;
+use super::Filter;
+use super::SeriesKey;
+use super::Stats;
+use super::Store;
+use super::MAX_DATETIME;
+use super::MIN_DATETIME;
+
+const DEFAULT_WINDOW_MS: i64 = 60 * 60 * 1000;
+
+type MsgKey = (SeriesKey, String);
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Counts since the store was created. Dropped counts exact duplicates and those lost to keep
+// first, replaced those that won under keep last.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Duplicates {
+    pub dropped: u64,
+    pub replaced: u64,
+    pub rejected: u64
+}
+
+// Wraps any store and drops the events that repeat one it already holds. Message ids are looked
+// up in an index of their own for window_ms after they were last seen, so a retry is caught even
+// when the device stamped it again. The events of one message are expected in one add_events call,
+// as the service adds them, and only repeat each other when their timestamps match. A duplicate
+// that wins under keep last hides the event it replaces from reads, the wrapped store keeps both.
+// The index and the hidden events are only held in memory, a restart forgets them.
+pub struct Deduped<S: Store> {
+    store: S,
+    dedup: Dedup,
+    window_ms: i64,
+    msg_ids: BTreeMap<MsgKey, Seen>,
+    replaced: BTreeMap<DateTime<Utc>, Vec<Event>>,
+    evictions: Option<Receiver<Change>>,
+    duplicates: Duplicates,
+    clock: Arc<dyn Clock>
+}
+
+// The events stored under one message id, in the order the message carried them
+struct Seen {
+    at: DateTime<Utc>,
+    events: Vec<Event>
+}
+
+// The message ids one add_events call was the first to use and how many events of each it has
+// carried so far
+#[derive(Default)]
+struct Call {
+    fresh: BTreeSet<MsgKey>,
+    positions: BTreeMap<MsgKey, usize>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Duplicates {
+    pub fn total(&self) -> u64 {
+        self.dropped + self.replaced + self.rejected
+    }
+}
+
+impl<S: Store> Deduped<S> {
+    pub fn new(store: S, dedup: Dedup) -> Deduped<S> {
+        Deduped {
+            store,
+            dedup,
+            window_ms: DEFAULT_WINDOW_MS,
+            msg_ids: BTreeMap::new(),
+            replaced: BTreeMap::new(),
+            evictions: None,
+            duplicates: Duplicates::default(),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    // How long message ids are remembered, retries arriving later are stored again
+    pub fn with_window_ms(mut self, window_ms: i64) -> Deduped<S> {
+        self.window_ms = window_ms.max(0);
+        self
+    }
+
+    // Only ages out the message ids, the wrapped store keeps its own clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Deduped<S> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn duplicates(&self) -> Duplicates {
+        self.duplicates
+    }
+
+    // The event already held that event repeats, pending holds the events of this call so far
+    fn find(&self, event: &Event, pending: &[Event], call: &Call) -> Option<Event> {
+        match self.dedup.key {
+            DedupKey::SensorTime => {
+                let repeats = |stored: &Event| stored.timestamp == event.timestamp && same_series(stored, event);
+
+                match pending.iter().find(|stored| repeats(stored)) {
+                    Some(stored) => Some(stored.clone()),
+                    None => self.iter_from(event.timestamp)
+                        .take_while(|stored| stored.timestamp == event.timestamp)
+                        .find(|stored| repeats(stored))
+                }
+            },
+            DedupKey::MsgId => {
+                let key = msg_key(event)?;
+                let stored = &self.msg_ids.get(&key)?.events;
+
+                // A retry repeats the message event for event, even when the device stamped it
+                // again. Within its first delivery a message only repeats itself at the same time.
+                if call.fresh.contains(&key) {
+                    stored.iter().find(|stored| stored.timestamp == event.timestamp).cloned()
+                } else {
+                    stored.get(call.positions.get(&key).copied().unwrap_or(0)).cloned()
+                }
+            }
+        }
+    }
+
+    fn admit(&mut self, event: Event, pending: &mut Vec<Event>, call: &mut Call) {
+        let found = self.find(&event, pending, call);
+        if let Some(key) = msg_key(&event) {
+            *call.positions.entry(key).or_insert(0) += 1;
+        }
+
+        let stored = match found {
+            Some(stored) => stored,
+            None => {
+                self.remember(&event, &event, call);
+                pending.push(event);
+                return
+            }
+        };
+
+        let replace = resolve(self.dedup.policy, &stored, &event, &mut self.duplicates);
+        if msg_key(&event).is_some_and(|key| call.fresh.contains(&key)) {
+            self.remember(&event, if replace { &event } else { &stored }, call);
+        }
+        if !replace { return }
+
+        self.replace(&stored, &event);
+
+        if let Some(index) = pending.iter().position(|pending_event| *pending_event == stored) {
+            pending[index] = event;
+            return
+        }
+
+        self.replaced.entry(stored.timestamp).or_default().push(stored);
+        if self.evictions.is_none() {
+            // No event has an empty sensor id, so only the evictions come through
+            self.evictions = Some(self.store.subscribe(Filter::new().sensor(""), 1));
+        }
+
+        // Going back to a reading that was replaced earlier shows the stored copy again
+        let hidden = self.replaced.entry(event.timestamp).or_default();
+        match hidden.iter().position(|hidden_event| same_reading(hidden_event, &event)) {
+            Some(index) => { hidden.remove(index); },
+            None => pending.push(event)
+        }
+    }
+
+    // Holds kept as the next event of the message event belongs to
+    fn remember(&mut self, event: &Event, kept: &Event, call: &mut Call) {
+        if self.dedup.key != DedupKey::MsgId { return }
+
+        if let Some(key) = msg_key(event) {
+            let now = self.clock.now();
+            if !self.msg_ids.contains_key(&key) {
+                call.fresh.insert(key.clone());
+            }
+
+            let seen = self.msg_ids.entry(key).or_insert_with(|| Seen { at: now, events: Vec::new() });
+            seen.at = now;
+            seen.events.push(kept.clone());
+        }
+    }
+
+    // Later retries are matched against event where they were matched against stored
+    fn replace(&mut self, stored: &Event, event: &Event) {
+        let now = self.clock.now();
+        if let Some(seen) = msg_key(event).and_then(|key| self.msg_ids.get_mut(&key)) {
+            seen.at = now;
+            for kept in seen.events.iter_mut().filter(|kept| *kept == stored) {
+                *kept = event.clone();
+            }
+        }
+    }
+
+    fn is_hidden(&self, event: &Event) -> bool {
+        match self.replaced.get(&event.timestamp) {
+            Some(hidden) => hidden.iter().any(|hidden_event| same_reading(hidden_event, event)),
+            None => false
+        }
+    }
+
+    fn prune(&mut self) {
+        let horizon = self.clock.now() - Duration::milliseconds(self.window_ms);
+        self.msg_ids.retain(|_, seen| seen.at >= horizon);
+        self.replaced.retain(|_, hidden| !hidden.is_empty());
+        if self.replaced.is_empty() {
+            self.evictions = None;
+            return
+        }
+
+        // Hidden events can go once the wrapped store has evicted them, which its feed announces.
+        // A full buffer lags rather than blocks, so a lag may stand for an eviction too.
+        let evicted = match &self.evictions {
+            Some(evictions) => evictions.try_iter()
+                .filter(|change| !matches!(change, Change::Inserted(_)))
+                .count() > 0,
+            None => false
+        };
+        if !evicted { return }
+
+        match self.store.iter_from(MIN_DATETIME).next() {
+            Some(oldest) => self.replaced = self.replaced.split_off(&oldest.timestamp),
+            None => self.replaced.clear()
+        }
+        if self.replaced.is_empty() {
+            self.evictions = None;
+        }
+    }
+}
+
+impl<S: Store> Store for Deduped<S> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.prune();
+        let mut pending = Vec::with_capacity(events.len());
+        let mut call = Call::default();
+
+        for event in events {
+            self.admit(event, &mut pending, &mut call);
+        }
+
+        if !pending.is_empty() {
+            self.store.add_events(pending);
+        }
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        self.store.get_window(win_len_ms)
+            .into_iter()
+            .filter(|event| !self.is_hidden(event))
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        if self.replaced.is_empty() {
+            return self.store.get_window_of_n(n)
+        }
+
+        self.iter_before(MAX_DATETIME).take(n as usize).collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.store.iter_from(start).filter(move |event| !self.is_hidden(event)))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.store.iter_before(end).filter(move |event| !self.is_hidden(event)))
+    }
+
+    // Subscribers see the events that replace others, not the replacements
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.store.subscribe(filter, capacity)
+    }
+
+    // Hidden events are still counted
+    fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+}
+
+// Counts a duplicate, true when it should replace the stored event. A retry stamped again with
+// the same value is an exact duplicate.
+fn resolve(policy: ConflictPolicy, stored: &Event, event: &Event, duplicates: &mut Duplicates) -> bool {
+    if stored.value == event.value && stored.quality == event.quality {
+        duplicates.dropped += 1;
+        return false
+    }
+
+    match policy {
+        ConflictPolicy::KeepFirst => {
+            duplicates.dropped += 1;
+            false
+        },
+        ConflictPolicy::KeepLast => {
+            duplicates.replaced += 1;
+            true
+        },
+        ConflictPolicy::Reject => {
+            duplicates.rejected += 1;
+            false
+        }
+    }
+}
+
+fn same_series(a: &Event, b: &Event) -> bool {
+    a.sensor_id == b.sensor_id && a.tags == b.tags
+}
+
+// Ignores the message id, some stores read events back without it
+fn same_reading(a: &Event, b: &Event) -> bool {
+    a.timestamp == b.timestamp && same_series(a, b) && a.value == b.value && a.quality == b.quality
+}
+
+fn msg_key(event: &Event) -> Option<MsgKey> {
+    event.msg_id.as_ref().map(|msg_id| (SeriesKey::of(event), msg_id.clone()))
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use edge_core::Retention;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::super::SqliteStore;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn at_seconds(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter()
+            .map(|s| Event::new(time(*s), "temp_sensor_1", Value::Int(*s)))
+            .collect()
+    }
+
+    fn values(events: &[Event]) -> Vec<Value> {
+        events.iter().map(|event| event.value.clone()).collect()
+    }
+
+    #[test]
+    fn drops_duplicates_by_sensor_and_time() {
+        let mut store = Deduped::new(InMemory::new(), Dedup::new(DedupKey::SensorTime, ConflictPolicy::KeepLast));
+        store.add_events(at_seconds(&[10, 20, 20]));
+        store.add_events(vec![
+            Event::new(time(20), "temp_sensor_1", Value::Int(21)),
+            Event::new(time(20), "temp_sensor_1", Value::Int(20)).with_tag("index", "1"),
+            Event::new(time(10), "door_1", Value::Int(10))
+        ]);
+
+        assert_eq!(store.get_window_of_n(10).len(), 4);
+        assert_eq!(values(&store.get_range(time(20), time(21))), vec![Value::Int(21), Value::Int(20)]);
+        assert_eq!(store.duplicates(), Duplicates { dropped: 1, replaced: 1, rejected: 0 });
+
+        // Back to the first reading
+        store.add_events(at_seconds(&[20]));
+        assert_eq!(values(&store.get_range(time(20), time(21))), vec![Value::Int(20), Value::Int(20)]);
+        assert_eq!(store.store().len(), 5);
+    }
+
+    #[test]
+    fn forgets_hidden_events_once_evicted() {
+        let retention = Retention { max_events: Some(2), ..Retention::default() };
+        let mut store = Deduped::new(InMemory::with_retention(retention),
+                                     Dedup::new(DedupKey::SensorTime, ConflictPolicy::KeepLast));
+        store.add_events(at_seconds(&[10]));
+        assert!(store.evictions.is_none());
+
+        store.add_events(vec![Event::new(time(10), "temp_sensor_1", Value::Int(11))]);
+        store.add_events(at_seconds(&[20]));
+        assert_eq!(store.replaced.len(), 1);
+
+        store.add_events(at_seconds(&[30]));
+        store.add_events(at_seconds(&[40]));
+        assert!(store.replaced.is_empty() && store.evictions.is_none());
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(40), Value::Int(30)]);
+    }
+
+    #[test]
+    fn drops_redelivered_messages() {
+        let reading = |msg_id: &str, value: i64| Event::new(time(10), "temp_sensor_1", Value::Int(value)).with_msg_id(msg_id);
+        let mut store = Deduped::new(InMemory::new(), Dedup::new(DedupKey::MsgId, ConflictPolicy::Reject));
+        store.add_events(vec![reading("a", 1), reading("b", 2), reading("a", 1)]);
+        store.add_events(vec![reading("b", 3), at_seconds(&[10]).remove(0)]);
+
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(10), Value::Int(2), Value::Int(1)]);
+        assert_eq!(store.duplicates(), Duplicates { dropped: 1, replaced: 0, rejected: 1 });
+    }
+
+    #[test]
+    fn catches_restamped_retries_on_any_store() {
+        let clock = Arc::new(ManualClock::new(time(100)));
+        let sqlite = SqliteStore::open(":memory:", "temp_sensor_1").unwrap();
+        let mut store = Deduped::new(sqlite, Dedup::new(DedupKey::MsgId, ConflictPolicy::KeepLast))
+            .with_window_ms(60_000)
+            .with_clock(clock.clone());
+
+        // One message of window data, its events share the id
+        let window = |values: &[i64]| values.iter()
+            .enumerate()
+            .map(|(i, value)| Event::new(time(10 + i as i64), "temp_sensor_1", Value::Int(*value)).with_msg_id("w"))
+            .collect::<Vec<Event>>();
+        store.add_events(window(&[1, 2]));
+        store.add_events(vec![Event::new(time(50), "temp_sensor_1", Value::Int(5)).with_msg_id("m")]);
+
+        store.add_events(window(&[1, 3]));
+        store.add_events(vec![Event::new(time(55), "temp_sensor_1", Value::Int(5)).with_msg_id("m")]);
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(5), Value::Int(3), Value::Int(1)]);
+        assert_eq!(store.duplicates(), Duplicates { dropped: 2, replaced: 1, rejected: 0 });
+
+        // A retry stamped again is matched event for event
+        let restamped = |values: &[i64]| values.iter()
+            .enumerate()
+            .map(|(i, value)| Event::new(time(30 + i as i64), "temp_sensor_1", Value::Int(*value)).with_msg_id("w"))
+            .collect::<Vec<Event>>();
+        store.add_events(restamped(&[4, 3]));
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(5), Value::Int(4), Value::Int(3)]);
+        assert_eq!(store.duplicates(), Duplicates { dropped: 3, replaced: 2, rejected: 0 });
+
+        // Forgotten once the window has passed
+        clock.set(time(200));
+        store.add_events(vec![Event::new(time(60), "temp_sensor_1", Value::Int(5)).with_msg_id("m")]);
+        assert_eq!(store.get_window_of_n(10).len(), 4);
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::sync::Arc;
+use chrono::prelude::*;
+use parquet::data_type::ByteArray;
+use parquet::data_type::ByteArrayType;
+use parquet::data_type::DoubleType;
+use parquet::data_type::Int64Type;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use edge_core::Event;
+use edge_core::Quality;
+use edge_core::Value;
+use super::Filter;
+use super::Store;
+use super::MAX_DATETIME;
+use super::MIN_DATETIME;
+
+const ROW_GROUP_SIZE: usize = 65_536;
+
+// Timestamps are kept to the microsecond, which every parquet reader maps to a UTC datetime
+const PARQUET_SCHEMA: &str = "
+    message event {
+        REQUIRED INT64 timestamp (TIMESTAMP(MICROS,true));
+        REQUIRED BYTE_ARRAY sensor_id (STRING);
+        OPTIONAL DOUBLE value;
+        OPTIONAL BYTE_ARRAY value_text (STRING);
+        REQUIRED BYTE_ARRAY value_type (STRING);
+        REQUIRED BYTE_ARRAY quality (STRING);
+        REQUIRED BYTE_ARRAY tags (STRING);
+    }
+";
+
+const CSV_HEADER: &str = "timestamp,sensor_id,value,value_type,quality,tags";
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Parquet
+}
+
+// The events to export, those in [start, end) that match filter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub filter: Filter
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(Format::Csv),
+            "parquet" | "pq" => Some(Format::Parquet),
+            _ => None
+        }
+    }
+
+    // Picks the format from the extension of path
+    pub fn from_path(path: &str) -> Option<Format> {
+        path.rsplit('.').next().and_then(Format::from_name)
+    }
+}
+
+impl Selection {
+    pub fn all() -> Selection {
+        Selection::between(MIN_DATETIME, MAX_DATETIME)
+    }
+
+    pub fn between(start: DateTime<Utc>, end: DateTime<Utc>) -> Selection {
+        Selection {
+            start,
+            end,
+            filter: Filter::new()
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Selection {
+        self.filter = filter;
+        self
+    }
+
+    pub fn events<'a>(&'a self, store: &'a dyn Store) -> impl Iterator<Item = Event> + 'a {
+        store.iter_range(self.start, self.end).filter(move |event| self.filter.matches_event(event))
+    }
+}
+
+// Writes the selected events of store to path, oldest first. Like a snapshot the file is written
+// next to path and renamed over it once complete. Returns the number of events written.
+pub fn export(store: &dyn Store, selection: &Selection, format: Format, path: &str) -> io::Result<usize> {
+    println!("Exporting {:?}: {}", format, path);
+
+    let partial = format!("{}.partial", path);
+    let count = {
+        let file = File::create(&partial)?;
+        let events = selection.events(store);
+
+        match format {
+            Format::Csv => {
+                let mut writer = BufWriter::new(file);
+                let count = write_csv(events, &mut writer)?;
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+                count
+            },
+            Format::Parquet => {
+                let count = write_parquet(events, &file)?;
+                file.sync_all()?;
+                count
+            }
+        }
+    };
+    fs::rename(&partial, path)?;
+
+    Ok(count)
+}
+
+// One row per event, values are written as is with their type in a column of its own and the
+// tags as a json object
+pub fn write_csv(events: impl Iterator<Item = Event>, mut writer: impl Write) -> io::Result<usize> {
+    writeln!(writer, "{}", CSV_HEADER)?;
+
+    let mut count = 0;
+    for event in events {
+        let value = match &event.value {
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::Text(value) => csv_field(value)
+        };
+
+        writeln!(writer, "{},{},{},{},{},{}",
+                 event.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                 csv_field(&event.sensor_id),
+                 value,
+                 value_type(&event.value),
+                 quality_name(event.quality),
+                 csv_field(&serde_json::to_string(&event.tags)?))?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+// Numeric and bool values go in the value column as doubles, text values in value_text
+pub fn write_parquet<W: Write + Send>(events: impl Iterator<Item = Event>, writer: W) -> io::Result<usize> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(writer, schema, properties)?;
+
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(ROW_GROUP_SIZE);
+    for event in events {
+        batch.push(event);
+
+        if batch.len() == ROW_GROUP_SIZE {
+            write_row_group(&mut writer, &batch)?;
+            count += batch.len();
+            batch.clear();
+        }
+    }
+
+    if !batch.is_empty() {
+        write_row_group(&mut writer, &batch)?;
+        count += batch.len();
+    }
+
+    writer.close()?;
+    Ok(count)
+}
+
+fn write_row_group<W: Write + Send>(writer: &mut SerializedFileWriter<W>, events: &[Event]) -> io::Result<()> {
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                let timestamps: Vec<i64> = events.iter().map(|event| event.timestamp.timestamp_micros()).collect();
+                column.typed::<Int64Type>().write_batch(&timestamps, None, None)?;
+            },
+            1 => {
+                let sensor_ids = strings(events, |event| event.sensor_id.clone());
+                column.typed::<ByteArrayType>().write_batch(&sensor_ids, None, None)?;
+            },
+            2 => {
+                let (values, levels) = optional(events, |event| match event.value {
+                    Value::Text(_) => None,
+                    _ => event.value.as_f64()
+                });
+                column.typed::<DoubleType>().write_batch(&values, Some(&levels), None)?;
+            },
+            3 => {
+                let (values, levels) = optional(events, |event| match &event.value {
+                    Value::Text(text) => Some(ByteArray::from(text.as_str())),
+                    _ => None
+                });
+                column.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+            },
+            4 => {
+                let types = strings(events, |event| value_type(&event.value).to_string());
+                column.typed::<ByteArrayType>().write_batch(&types, None, None)?;
+            },
+            5 => {
+                let qualities = strings(events, |event| quality_name(event.quality).to_string());
+                column.typed::<ByteArrayType>().write_batch(&qualities, None, None)?;
+            },
+            _ => {
+                let tags = strings(events, |event| serde_json::to_string(&event.tags).unwrap_or_default());
+                column.typed::<ByteArrayType>().write_batch(&tags, None, None)?;
+            }
+        }
+
+        column.close()?;
+        index += 1;
+    }
+
+    row_group.close()?;
+    Ok(())
+}
+
+fn strings(events: &[Event], field: impl Fn(&Event) -> String) -> Vec<ByteArray> {
+    events.iter().map(|event| ByteArray::from(field(event).into_bytes())).collect()
+}
+
+// The present values and a definition level per event, 1 when present and 0 when null
+fn optional<T>(events: &[Event], field: impl Fn(&Event) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::new();
+    let mut levels = Vec::with_capacity(events.len());
+
+    for event in events {
+        match field(event) {
+            Some(value) => {
+                values.push(value);
+                levels.push(1);
+            },
+            None => levels.push(0)
+        }
+    }
+
+    (values, levels)
+}
+
+fn value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Int(_) => "int",
+        Value::Float(_) => "float",
+        Value::Bool(_) => "bool",
+        Value::Text(_) => "text"
+    }
+}
+
+fn quality_name(quality: Quality) -> &'static str {
+    match quality {
+        Quality::Good => "good",
+        Quality::Uncertain => "uncertain",
+        Quality::Bad => "bad"
+    }
+}
+
+// Quotes a field when it holds a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use parquet::file::reader::FileReader;
+    use parquet::file::reader::SerializedFileReader;
+    use parquet::record::RowAccessor;
+    use super::super::InMemory;
+    use super::*;
+
+    fn test_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("edge_export_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn store() -> InMemory {
+        let mut store = InMemory::new();
+        store.add_events(vec![
+            Event::new(time(1_000), "temp_sensor_1", Value::Float(21.5)).with_tag("index", "1"),
+            Event::new(time(1_001), "door_1", Value::Text(String::from("open, \"wide\""))).with_quality(Quality::Bad),
+            Event::new(time(1_002), "temp_sensor_1", Value::Int(22)),
+            Event::new(time(1_003), "door_1", Value::Bool(true))
+        ]);
+        store
+    }
+
+    #[test]
+    fn writes_csv() {
+        let store = store();
+        let selection = Selection::between(time(1_000), time(1_003));
+        let mut csv = Vec::new();
+
+        assert_eq!(write_csv(selection.events(&store), &mut csv).unwrap(), 3);
+        assert_eq!(String::from_utf8(csv).unwrap().lines().collect::<Vec<&str>>(), vec![
+            CSV_HEADER,
+            "1970-01-01T00:16:40Z,temp_sensor_1,21.5,float,good,\"{\"\"index\"\":\"\"1\"\"}\"",
+            "1970-01-01T00:16:41Z,door_1,\"open, \"\"wide\"\"\",text,bad,{}",
+            "1970-01-01T00:16:42Z,temp_sensor_1,22,int,good,{}"
+        ]);
+    }
+
+    #[test]
+    fn writes_parquet() {
+        let path = test_path("doors.parquet");
+        let selection = Selection::all().with_filter(Filter::new().sensor("door_1"));
+
+        assert_eq!(Format::from_path(&path), Some(Format::Parquet));
+        assert_eq!(export(&store(), &selection, Format::Parquet, &path).unwrap(), 2);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+
+        let rows: Vec<parquet::record::Row> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(rows[0].get_timestamp_micros(0).unwrap(), 1_001_000_000);
+        assert_eq!(rows[0].get_string(1).unwrap(), "door_1");
+        assert_eq!(rows[0].get_string(3).unwrap(), "open, \"wide\"");
+        assert_eq!(rows[0].get_string(5).unwrap(), "bad");
+        assert_eq!(rows[1].get_double(2).unwrap(), 1.0);
+        assert_eq!(rows[1].get_string(4).unwrap(), "bool");
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+
+use edge_core::Event;
+use super::Evictions;
+use super::Filter;
+use super::Store;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Inserted(Event),
+    // Events dropped by a retention limit, sent to every subscriber whatever its filter
+    Evicted { limit: Limit, count: u64 },
+    // Changes this subscriber missed because its buffer was full, sent once there is room again
+    Lagged(u64)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Count,
+    Age,
+    Bytes
+}
+
+// Fans the changes of one store out to its subscribers. Each subscriber has its own bounded
+// buffer, a slow subscriber misses changes instead of holding up the store.
+#[derive(Default)]
+pub struct Feed {
+    subscribers: Mutex<Vec<Subscriber>>
+}
+
+struct Subscriber {
+    filter: Filter,
+    sender: SyncSender<Change>,
+    missed: u64
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Feed {
+    pub fn new() -> Feed {
+        Feed::default()
+    }
+
+    pub fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        let (sender, receiver) = sync_channel(capacity.max(1));
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        subscribers.push(Subscriber {
+            filter,
+            sender,
+            missed: 0
+        });
+
+        receiver
+    }
+
+    pub fn num_subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).len()
+    }
+
+    // Lets a store skip copying its events when nobody is listening
+    pub fn is_active(&self) -> bool {
+        self.num_subscribers() > 0
+    }
+
+    pub fn inserted(&self, events: &[Event]) {
+        if events.is_empty() { return }
+
+        self.publish(|subscriber| {
+            events.iter()
+                .filter(|event| subscriber.filter.matches_event(event))
+                .map(|event| Change::Inserted(event.clone()))
+                .collect()
+        });
+    }
+
+    // Publishes the difference between two eviction counts of the same store
+    pub fn evicted(&self, before: Evictions, after: Evictions) {
+        let changes: Vec<Change> = [(Limit::Count, before.count, after.count),
+                                    (Limit::Age, before.age, after.age),
+                                    (Limit::Bytes, before.bytes, after.bytes)]
+            .iter()
+            .filter(|(_, before, after)| after > before)
+            .map(|(limit, before, after)| Change::Evicted { limit: *limit, count: after - before })
+            .collect();
+
+        if changes.is_empty() { return }
+        self.publish(|_| changes.clone());
+    }
+
+    // Subscribers whose receiver was dropped are removed as soon as a send notices
+    fn publish(&self, changes_for: impl Fn(&Subscriber) -> Vec<Change>) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        subscribers.retain_mut(|subscriber| {
+            for change in changes_for(subscriber) {
+                if !subscriber.send(change) { return false }
+            }
+
+            true
+        });
+    }
+}
+
+impl Subscriber {
+    // False once the receiver is gone
+    fn send(&mut self, change: Change) -> bool {
+        if self.missed > 0 {
+            match self.sender.try_send(Change::Lagged(self.missed)) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += 1;
+                    return true
+                },
+                Err(TrySendError::Disconnected(_)) => return false
+            }
+        }
+
+        match self.sender.try_send(change) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += 1;
+                true
+            },
+            Err(TrySendError::Disconnected(_)) => false
+        }
+    }
+}
+
+// Adds events to store and returns the ones it kept, as its feed announces them. There is room for
+// every event and one eviction of each limit, so nothing is missed.
+pub fn add_accepted<S: Store + ?Sized>(store: &mut S, events: Vec<Event>) -> Vec<Event> {
+    let changes = store.subscribe(Filter::new(), events.len() + 3);
+    store.add_events(events);
+
+    changes.try_iter()
+        .filter_map(|change| match change {
+            Change::Inserted(event) => Some(event),
+            _ => None
+        })
+        .collect()
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use chrono::prelude::*;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::super::Store;
+    use super::*;
+
+    fn event(seconds: i64, sensor_id: &str) -> Event {
+        Event::new(Utc.timestamp_opt(seconds, 0).unwrap(), sensor_id, Value::Int(seconds))
+    }
+
+    #[test]
+    fn delivers_matching_inserts() {
+        let feed = Feed::new();
+        let all = feed.subscribe(Filter::new(), 10);
+        let doors = feed.subscribe(Filter::new().sensor("door_1"), 10);
+
+        feed.inserted(&[event(1, "temp_sensor_1"), event(2, "door_1")]);
+        feed.evicted(Evictions::default(), Evictions { count: 2, age: 0, bytes: 1 });
+
+        assert_eq!(all.try_iter().count(), 4);
+        assert_eq!(doors.try_iter().collect::<Vec<Change>>(), vec![
+            Change::Inserted(event(2, "door_1")),
+            Change::Evicted { limit: Limit::Count, count: 2 },
+            Change::Evicted { limit: Limit::Bytes, count: 1 }
+        ]);
+    }
+
+    #[test]
+    fn reports_lag_and_drops_closed_subscribers() {
+        let feed = Feed::new();
+        let slow = feed.subscribe(Filter::new(), 2);
+        drop(feed.subscribe(Filter::new(), 2));
+
+        feed.inserted(&[event(1, "a"), event(2, "a"), event(3, "a"), event(4, "a")]);
+        assert_eq!(feed.num_subscribers(), 1);
+        assert_eq!(slow.try_iter().count(), 2);
+
+        feed.inserted(&[event(5, "a")]);
+        assert_eq!(slow.try_iter().collect::<Vec<Change>>(), vec![Change::Lagged(2), Change::Inserted(event(5, "a"))]);
+    }
+
+    #[test]
+    fn stores_publish_inserts_and_evictions() {
+        let retention = edge_core::Retention { max_events: Some(2), ..edge_core::Retention::default() };
+        let mut store = InMemory::with_retention(retention);
+        let changes = store.subscribe(Filter::new(), 10);
+
+        store.add_events(vec![event(1, "a"), event(2, "a"), event(3, "a")]);
+        assert_eq!(changes.try_iter().collect::<Vec<Change>>(), vec![
+            Change::Inserted(event(1, "a")),
+            Change::Inserted(event(2, "a")),
+            Change::Inserted(event(3, "a")),
+            Change::Evicted { limit: Limit::Count, count: 1 }
+        ]);
+    }
+}
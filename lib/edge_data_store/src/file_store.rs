@@ -0,0 +1,699 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Retention;
+use edge_core::SyncPolicy;
+use edge_core::SystemClock;
+use super::Change;
+use super::Evictions;
+use super::Feed;
+use super::Filter;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+
+const SEGMENT_EXT: &str = "log";
+const HEADER_LEN: usize = 8;
+const DEFAULT_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Events are appended to numbered segment files as length and crc prefixed json records. Only the
+// newest segment is written to, full segments are closed and later deleted whole by retention.
+pub struct FileStore {
+    dir: PathBuf,
+    sync: SyncPolicy,
+    segment_bytes: u64,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    segments: Vec<Segment>,
+    writer: Option<File>,
+    index: BTreeMap<(DateTime<Utc>, u64), Location>,
+    next_seq: u64,
+    unsynced: usize,
+    last_sync: DateTime<Utc>,
+    evictions: Evictions,
+    tracker: Tracker,
+    feed: Feed,
+    read_only: bool
+}
+
+struct Segment {
+    id: u64,
+    path: PathBuf,
+    reader: File,
+    size: u64,
+    count: usize,
+    newest: Option<DateTime<Utc>>
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl FileStore {
+    pub fn open(dir: &str, sync: SyncPolicy) -> Option<FileStore> {
+        println!("Opening file store: {}", dir);
+
+        match FileStore::recover(Path::new(dir), sync, false) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                println!("Error opening file store: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // For reading a store another process may be writing to. Nothing on disk is created, truncated
+    // or deleted, a torn record at the end of the newest segment is left out and adding events fails.
+    pub fn open_read_only(dir: &str) -> Option<FileStore> {
+        println!("Opening file store read only: {}", dir);
+
+        match FileStore::recover(Path::new(dir), SyncPolicy::Never, true) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                println!("Error opening file store: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> FileStore {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> FileStore {
+        self.last_sync = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_segment_bytes(mut self, segment_bytes: u64) -> FileStore {
+        self.segment_bytes = segment_bytes;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn num_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.size).sum()
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.sync_data()?;
+        }
+
+        self.unsynced = 0;
+        self.last_sync = self.clock.now();
+
+        Ok(())
+    }
+
+    fn recover(dir: &Path, sync: SyncPolicy, read_only: bool) -> io::Result<FileStore> {
+        if !read_only {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut ids = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXT) { continue }
+
+            match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok()) {
+                Some(id) => ids.push(id),
+                None => println!("Ignoring unknown file in file store: {:?}", path)
+            }
+        }
+
+        ids.sort_unstable();
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let mut store = FileStore {
+            dir: dir.to_path_buf(),
+            sync,
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            retention: Retention::default(),
+            last_sync: clock.now(),
+            clock,
+            segments: Vec::new(),
+            writer: None,
+            index: BTreeMap::new(),
+            next_seq: 0,
+            unsynced: 0,
+            evictions: Evictions::default(),
+            tracker: Tracker::new(),
+            feed: Feed::new(),
+            read_only
+        };
+
+        let last = ids.last().copied();
+        for id in ids {
+            store.load_segment(id, Some(id) == last)?;
+        }
+
+        if let (Some(segment), false) = (store.segments.last(), read_only) {
+            store.writer = Some(OpenOptions::new().append(true).open(&segment.path)?);
+        }
+
+        Ok(store)
+    }
+
+    // Reads every record of a segment back into the index. In the segment being written the first
+    // record that is short or fails its checksum marks a torn write, the segment is truncated there.
+    // Closed segments are never modified, a corrupt record in one is skipped when its length can
+    // still be trusted and otherwise ends the segment.
+    fn load_segment(&mut self, id: u64, tail: bool) -> io::Result<()> {
+        let path = self.segment_path(id);
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+
+        let mut offset = 0;
+        let mut count = 0;
+        let mut newest = None;
+
+        while offset < data.len() {
+            let len = match decode_record(&data[offset..]) {
+                Some((event, len)) => {
+                    newest = newest.max(Some(event.timestamp));
+                    self.index.insert((event.timestamp, self.next_seq), Location {
+                        segment: id,
+                        offset: offset as u64,
+                        len: len as u32
+                    });
+                    self.next_seq += 1;
+                    count += 1;
+                    len
+                },
+                None if tail => break,
+                None => match record_len(&data[offset..]) {
+                    Some(len) => {
+                        println!("Skipping corrupt record in segment {:?} at offset {}", path, offset);
+                        len
+                    },
+                    None => {
+                        println!("Ignoring the rest of segment {:?} from offset {}", path, offset);
+                        break
+                    }
+                }
+            };
+
+            offset += len;
+        }
+
+        if tail && offset < data.len() && !self.read_only {
+            println!("Truncating torn record in segment {:?} at offset {}", path, offset);
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        self.segments.push(Segment {
+            id,
+            reader: File::open(&path)?,
+            path,
+            size: if tail { offset as u64 } else { data.len() as u64 },
+            count,
+            newest
+        });
+
+        Ok(())
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXT))
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        if self.writer.is_some() {
+            self.sync()?;
+        }
+
+        let id = match self.segments.last() {
+            Some(segment) => segment.id + 1,
+            None => 0
+        };
+        let path = self.segment_path(id);
+        let writer = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        self.segments.push(Segment {
+            id,
+            reader: File::open(&path)?,
+            path,
+            size: 0,
+            count: 0,
+            newest: None
+        });
+        self.writer = Some(writer);
+
+        Ok(())
+    }
+
+    fn append(&mut self, events: Vec<Event>) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "file store is open read only"));
+        }
+
+        let mut batch = Vec::new();
+        let mut pending = Vec::new();
+
+        for event in events {
+            let full = match self.segments.last() {
+                Some(segment) => {
+                    let used = segment.size + batch.len() as u64;
+                    used > 0 && used >= self.segment_bytes
+                },
+                None => true
+            };
+
+            if full {
+                self.flush(&mut batch, &mut pending)?;
+                self.roll()?;
+            }
+
+            let record = encode_record(&event)?;
+            let segment = self.segments.last().expect("segment rolled above");
+
+            pending.push((event.timestamp, Location {
+                segment: segment.id,
+                offset: segment.size + batch.len() as u64,
+                len: record.len() as u32
+            }));
+            batch.extend_from_slice(&record);
+        }
+
+        self.flush(&mut batch, &mut pending)?;
+
+        let due = match self.sync {
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::IntervalMs(ms) => (self.clock.now() - self.last_sync).num_milliseconds() >= ms,
+            SyncPolicy::Never => false
+        };
+
+        if due {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    // The pending locations only go in the index once their records are written. A failed write
+    // is cut back off the segment so the next one lands where the index expects it.
+    fn flush(&mut self, batch: &mut Vec<u8>, pending: &mut Vec<(DateTime<Utc>, Location)>) -> io::Result<()> {
+        if batch.is_empty() { return Ok(()) }
+
+        let segment = match (self.writer.as_mut(), self.segments.last_mut()) {
+            (Some(writer), Some(segment)) => {
+                if let Err(e) = writer.write_all(batch) {
+                    if let Err(e) = writer.set_len(segment.size) {
+                        println!("Error cutting failed write off segment {:?}: {:?}", segment.path, e);
+                    }
+                    batch.clear();
+                    pending.clear();
+                    return Err(e);
+                }
+
+                segment.size += batch.len() as u64;
+                segment
+            },
+            _ => {
+                batch.clear();
+                pending.clear();
+                return Ok(())
+            }
+        };
+
+        for (timestamp, location) in pending.drain(..) {
+            segment.count += 1;
+            segment.newest = segment.newest.max(Some(timestamp));
+            self.index.insert((timestamp, self.next_seq), location);
+            self.next_seq += 1;
+            self.unsynced += 1;
+        }
+
+        batch.clear();
+
+        Ok(())
+    }
+
+    // Retention is applied a segment at a time and never to the segment being written
+    fn evict(&mut self) -> io::Result<()> {
+        if self.read_only { return Ok(()) }
+
+        while self.segments.len() > 1 {
+            let total_count = self.index.len();
+            let total_bytes = self.num_bytes();
+            let oldest = &self.segments[0];
+
+            let by_count = match self.retention.max_events {
+                Some(max_events) => total_count - oldest.count >= max_events,
+                None => false
+            };
+            let by_age = match (self.retention.max_age_ms, oldest.newest) {
+                (Some(max_age_ms), Some(newest)) => (self.clock.now() - newest).num_milliseconds() > max_age_ms,
+                _ => false
+            };
+            let by_bytes = match self.retention.max_bytes {
+                Some(max_bytes) => total_bytes > max_bytes as u64,
+                None => false
+            };
+
+            if !(by_count || by_age || by_bytes) { break }
+
+            let segment = self.segments.remove(0);
+            let removed = segment.count as u64;
+            self.index.retain(|_, location| location.segment != segment.id);
+            fs::remove_file(&segment.path)?;
+
+            if by_count {
+                self.evictions.count += removed;
+            } else if by_age {
+                self.evictions.age += removed;
+            } else {
+                self.evictions.bytes += removed;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, location: &Location) -> Option<Event> {
+        let segment = self.segments.iter().find(|segment| segment.id == location.segment)?;
+
+        let mut data = vec![0; location.len as usize];
+        let mut reader = &segment.reader;
+        let result = reader.seek(SeekFrom::Start(location.offset))
+            .and_then(|_| reader.read_exact(&mut data));
+
+        if let Err(e) = result {
+            println!("Error reading file store segment {:?}: {:?}", segment.path, e);
+            return None
+        }
+
+        decode_record(&data).map(|(event, _)| event)
+    }
+}
+
+impl Store for FileStore {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let evictions = self.evictions;
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
+
+        match self.append(events) {
+            Ok(()) => self.feed.inserted(&inserted),
+            Err(e) => println!("Error writing to file store: {:?}", e)
+        }
+
+        if let Err(e) = self.evict() {
+            println!("Error applying file store retention: {:?}", e);
+        }
+        self.feed.evicted(evictions, self.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+
+        self.iter_before(now + chrono::Duration::nanoseconds(1))
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.index.values()
+            .rev()
+            .filter_map(|location| self.read(location))
+            .take(n as usize)
+            .collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.index.range((start, 0)..).filter_map(move |(_, location)| self.read(location)))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.index.range(..(end, 0)).rev().filter_map(move |(_, location)| self.read(location)))
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    // The insert rate and out of order count start over when the store is reopened
+    fn stats(&self) -> Stats {
+        Stats {
+            count: self.len(),
+            bytes: self.num_bytes() as usize,
+            oldest: self.index.keys().next().map(|(timestamp, _)| *timestamp),
+            newest: self.index.keys().next_back().map(|(timestamp, _)| *timestamp),
+            evictions: self.evictions,
+            ..self.tracker.stats(self.clock.now())
+        }
+    }
+}
+
+
+// Records
+// -------------------------------------------------------------------------------------------------
+// [payload length: u32 le][crc32 of payload: u32 le][json payload]
+fn encode_record(event: &Event) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(event)?;
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+
+    Ok(record)
+}
+
+fn decode_record(data: &[u8]) -> Option<(Event, usize)> {
+    if data.len() < HEADER_LEN { return None }
+
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let crc = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let payload = data.get(HEADER_LEN..HEADER_LEN + len)?;
+
+    if crc32(payload) != crc { return None }
+
+    serde_json::from_slice::<Event>(payload).ok().map(|event| (event, HEADER_LEN + len))
+}
+
+// The length of the record at the start of data when it fits, whether or not its checksum holds
+fn record_len(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_LEN { return None }
+
+    let len = HEADER_LEN + u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if len <= data.len() { Some(len) } else { None }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::process;
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("edge_file_store_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn at_seconds(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter()
+            .map(|s| Event::new(time(*s), "temp_sensor_1", Value::Int(*s)))
+            .collect()
+    }
+
+    fn values(events: &[Event]) -> Vec<Value> {
+        events.iter().map(|event| event.value.clone()).collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn reopen_rebuilds_index() {
+        let dir = test_dir("reopen");
+        {
+            let mut store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+            store.add_events(at_seconds(&[10, 30]));
+            store.add_events(at_seconds(&[20]));
+        }
+
+        let mut store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(30), Value::Int(20), Value::Int(10)]);
+        assert_eq!(values(&store.get_range(time(15), time(40))), vec![Value::Int(20), Value::Int(30)]);
+
+        store.add_events(at_seconds(&[40]));
+        assert_eq!(values(&store.get_before(time(100), 2)), vec![Value::Int(30), Value::Int(40)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn truncates_torn_record() {
+        let dir = test_dir("torn");
+        let good_len = {
+            let mut store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+            store.add_events(at_seconds(&[10, 20]));
+            store.num_bytes()
+        };
+
+        let path = Path::new(&dir).join(format!("{:020}.{}", 0, SEGMENT_EXT));
+        let record = encode_record(&at_seconds(&[30])[0]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+
+        let mut store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+        store.add_events(at_seconds(&[40]));
+        let store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(40), Value::Int(20), Value::Int(10)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skips_corrupt_record_in_closed_segment() {
+        let dir = test_dir("corrupt");
+        let record_len = encode_record(&at_seconds(&[10])[0]).unwrap().len() as u64;
+        {
+            let mut store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap()
+                .with_segment_bytes(3 * record_len);
+            store.add_events(at_seconds(&[10, 20, 30, 40]));
+            assert_eq!(store.num_segments(), 2);
+        }
+
+        // Flip a bit in the payload of the first record of the closed segment
+        let path = Path::new(&dir).join(format!("{:020}.{}", 0, SEGMENT_EXT));
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN + 2] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+
+        let store = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(40), Value::Int(30), Value::Int(20)]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * record_len);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_leaves_files_alone() {
+        let dir = test_dir("read_only");
+        assert!(FileStore::open_read_only(&dir).is_none());
+        assert!(!Path::new(&dir).exists());
+
+        let mut writer = FileStore::open(&dir, SyncPolicy::EveryWrite).unwrap();
+        writer.add_events(at_seconds(&[10, 20]));
+
+        let path = Path::new(&dir).join(format!("{:020}.{}", 0, SEGMENT_EXT));
+        let record = encode_record(&at_seconds(&[30])[0]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+
+        let mut reader = FileStore::open_read_only(&dir).unwrap();
+        assert_eq!(values(&reader.get_window_of_n(10)), vec![Value::Int(20), Value::Int(10)]);
+        reader.add_events(at_seconds(&[40]));
+        assert_eq!(reader.len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rolls_and_deletes_segments() {
+        let dir = test_dir("retention");
+        let record_len = encode_record(&at_seconds(&[10])[0]).unwrap().len() as u64;
+        let retention = Retention { max_events: Some(4), ..Retention::default() };
+        let mut store = FileStore::open(&dir, SyncPolicy::Never).unwrap()
+            .with_segment_bytes(2 * record_len)
+            .with_retention(retention);
+
+        for s in 0..10 {
+            store.add_events(at_seconds(&[s]));
+        }
+
+        assert_eq!(store.num_segments(), 2);
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.evictions().count, 6);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert_eq!(values(&store.get_window_of_n(1)), vec![Value::Int(9)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn window_follows_the_clock() {
+        let dir = test_dir("window");
+        let clock = Arc::new(ManualClock::new(time(100)));
+        let mut store = FileStore::open(&dir, SyncPolicy::IntervalMs(1_000)).unwrap()
+            .with_clock(clock.clone());
+        store.add_events(at_seconds(&[50, 95, 100, 105]));
+
+        assert_eq!(values(&store.get_window(10_000)), vec![Value::Int(100), Value::Int(95)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,136 +1,412 @@
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
 use chrono::prelude::*;
 
+use edge_core::Clock;
 use edge_core::Event;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use super::Change;
+use super::Feed;
+use super::Filter;
+use super::LateEvents;
+use super::Lateness;
+use super::Stats;
 use super::Store;
+use super::Tracker;
+use super::Watermarks;
 
+// Number of events dropped by each retention limit since the store was created
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Evictions {
+    pub count: u64,
+    pub age: u64,
+    pub bytes: u64
+}
+
+// Events are kept newest first, so the oldest events are always at the back of the buffer
 pub struct InMemory {
-    buffer: VecDeque<Event>
+    buffer: VecDeque<Event>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    num_bytes: usize,
+    evictions: Evictions,
+    watermarks: Watermarks,
+    tracker: Tracker,
+    feed: Feed
 }
 
 
+impl Evictions {
+    pub fn total(&self) -> u64 {
+        self.count + self.age + self.bytes
+    }
+}
+
 impl InMemory {
     pub fn new() -> InMemory {
+        InMemory::with_retention(Retention::default())
+    }
+
+    pub fn with_retention(retention: Retention) -> InMemory {
         InMemory {
-           buffer: VecDeque::new()
+            buffer: VecDeque::new(),
+            retention,
+            clock: Arc::new(SystemClock),
+            num_bytes: 0,
+            evictions: Evictions::default(),
+            watermarks: Watermarks::new(Lateness::default()),
+            tracker: Tracker::new(),
+            feed: Feed::new()
         }
     }
 
-    fn insert(&mut self, mut events: Vec<Event>) -> () {
-        loop {
-            if events.is_empty() {
-                break
-            } else {
-                match events.pop() {
-                    Some(event) => {
-                        self.buffer.push_front(event)
-                    },
-                    None => { break }
-                }
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> InMemory {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_lateness(mut self, lateness: Lateness) -> InMemory {
+        self.watermarks = Watermarks::new(lateness);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    pub fn late_events(&self) -> LateEvents {
+        self.watermarks.late_events()
+    }
+
+    // Events held back by the side output lateness policy since the last call
+    pub fn take_late(&mut self) -> Vec<Event> {
+        self.watermarks.take_side_output()
+    }
+
+    // Removes the events before end and hands them back oldest first, they are not counted as
+    // evictions. Subscribers are not told, the events are moving somewhere else.
+    pub fn take_before(&mut self, end: DateTime<Utc>) -> Vec<Event> {
+        let mut taken = Vec::new();
+
+        while self.buffer.back().is_some_and(|oldest| oldest.timestamp < end) {
+            if let Some(event) = self.buffer.pop_back() {
+                self.num_bytes -= event.size_bytes();
+                taken.push(event);
             }
         }
+
+        taken
     }
 
-    fn insert_interleaved(&mut self, mut  events: Vec<Event>) -> () {
-        let oldest_event = match events.get(0).cloned() {
-            Some(event) => {event},
-            None => { return }
-        };
-
-        events.reverse();
-        let mut index = 0;
-
-        // Find location to start inserting
-        loop {
-            match self.buffer.get(index) {
-                Some(event) => {
-                    if event.timestamp > oldest_event.timestamp {
-                        index += 1;
-                    } else {
-                        break
-                    }
-                    
-                },
-                None => { break }
+    // Goes in front of any events with the same timestamp, so those keep their arrival order too.
+    // An event newer than the front is pushed, a late one only shifts the shorter side.
+    fn insert(&mut self, event: Event) {
+        self.num_bytes += event.size_bytes();
+
+        match self.buffer.front() {
+            Some(newest) if newest.timestamp > event.timestamp => {
+                let index = self.buffer.partition_point(|newer| newer.timestamp > event.timestamp);
+                self.buffer.insert(index, event);
+            },
+            _ => self.buffer.push_front(event)
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(event) = self.buffer.pop_back() {
+            self.num_bytes -= event.size_bytes();
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some(max_events) = self.retention.max_events {
+            while self.buffer.len() > max_events {
+                self.evict_oldest();
+                self.evictions.count += 1;
             }
         }
 
-        // Insert events
-        loop {
-             match events.pop() {
-                Some(event) => {
-                    match self.buffer.get(index) {
-                        Some(old_event) => {
-                            if event.timestamp < old_event.timestamp {
-                                index -= 1;
-                            } else {
-                                self.buffer.insert(index, event);
-                                index -= 1;
-                            }
-                            
-                        },
-                        None => {
-                            self.buffer.push_front(event);
-                        }
-                    }
-                },
-                None => { break }
+        if let Some(max_age_ms) = self.retention.max_age_ms {
+            let now = self.clock.now();
+
+            while let Some(oldest) = self.buffer.back() {
+                if (now - oldest.timestamp).num_milliseconds() <= max_age_ms { break }
+                self.evict_oldest();
+                self.evictions.age += 1;
             }
         }
+
+        if let Some(max_bytes) = self.retention.max_bytes {
+            while self.num_bytes > max_bytes {
+                self.evict_oldest();
+                self.evictions.bytes += 1;
+            }
+        }
+    }
+}
+
+impl Default for InMemory {
+    fn default() -> InMemory {
+        InMemory::new()
     }
 }
 
 impl Store for InMemory {
-    fn add_events(&mut self, events: Vec<Event>) -> () {
-        if events.len() == 0 { return }
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let events = self.watermarks.admit(events);
+        let evictions = self.evictions;
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
 
-        if self.buffer.is_empty() {
-            self.insert(events);
-        } else {
-            self.insert_interleaved(events);
+        for event in events {
+            self.insert(event);
         }
+
+        self.evict();
+        self.feed.inserted(&inserted);
+        self.feed.evicted(evictions, self.evictions);
     }
 
     fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
-        let mut window = Vec::new();
-        let now: DateTime<Utc> = Utc::now();
-
-        loop {
-            match self.buffer.front() {
-                Some(event) => {
-                    let delta = now - event.timestamp;
-                    if delta.num_milliseconds() > win_len_ms {
-                        break
-                    } else {
-                         window.push(event.clone());
-                    }
-                },
-                None => break
-            }
-        }
+        let now = self.clock.now();
 
-        return window;
+        self.buffer.iter()
+            .skip_while(|event| event.timestamp > now)
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .cloned()
+            .collect()
     }
 
     fn get_window_of_n(&self, n: u64) -> Vec<Event> {
-        let mut window = Vec::new();
-        let mut index = 0;
-
-        loop {
-            if index <= n {
-                match self.buffer.front() {
-                    Some(event) => {
-                        window.push(event.clone());
-                        index += 1;
-                    },
-                    None => break
-                }
-            } else {
-                break
-            }
-        }
+        self.buffer.iter()
+            .take(n as usize)
+            .cloned()
+            .collect()
+    }
 
-        return window;
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let index = self.buffer.partition_point(|event| event.timestamp >= start);
+        Box::new(self.buffer.range(..index).rev().cloned())
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let index = self.buffer.partition_point(|event| event.timestamp >= end);
+        Box::new(self.buffer.range(index..).cloned())
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            count: self.len(),
+            bytes: self.num_bytes,
+            oldest: self.buffer.back().map(|event| event.timestamp),
+            newest: self.buffer.front().map(|event| event.timestamp),
+            evictions: self.evictions,
+            // Counted per series like late_events, not across the sensors sharing the store
+            out_of_order: self.watermarks.late_events().out_of_order,
+            ..self.tracker.stats(self.clock.now())
+        }
     }
 }
 
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use proptest::prelude::*;
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::super::Cursor;
+    use super::super::LatePolicy;
+    use super::*;
+
+    const NOW_S: i64 = 1_000_000;
+
+    fn manual_clock() -> Arc<ManualClock> {
+        Arc::new(ManualClock::new(time(NOW_S)))
+    }
+
+    fn events_ago(ages_ms: &[i64]) -> Vec<Event> {
+        let now = time(NOW_S);
+
+        ages_ms.iter()
+            .map(|age| Event::new(now - Duration::milliseconds(*age), "temp_sensor_1", Value::Int(*age)))
+            .collect()
+    }
+
+    fn values(events: &[Event]) -> Vec<Value> {
+        events.iter().map(|event| event.value.clone()).collect()
+    }
+
+    fn at_seconds(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter()
+            .map(|s| Event::new(Utc.timestamp_opt(*s, 0).unwrap(), "temp_sensor_1", Value::Int(*s)))
+            .collect()
+    }
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn keeps_newest_first() {
+        let mut store = InMemory::new();
+        store.add_events(events_ago(&[500, 400]));
+        store.add_events(events_ago(&[450, 100]));
+        store.add_events(events_ago(&[300]));
+
+        let window = store.get_window_of_n(10);
+        assert_eq!(values(&window), vec![Value::Int(100), Value::Int(300), Value::Int(400),
+                                         Value::Int(450), Value::Int(500)]);
+        assert_eq!(values(&store.get_window_of_n(2)), vec![Value::Int(100), Value::Int(300)]);
+    }
+
+    #[test]
+    fn window_follows_the_clock() {
+        let clock = manual_clock();
+        let mut store = InMemory::new().with_clock(clock.clone());
+        store.add_events(events_ago(&[60_000, 10_000, 9_999, 100]));
+
+        assert_eq!(values(&store.get_window(10_000)), vec![Value::Int(100), Value::Int(9_999),
+                                                           Value::Int(10_000)]);
+
+        clock.advance(Duration::milliseconds(1));
+        assert_eq!(values(&store.get_window(10_000)), vec![Value::Int(100), Value::Int(9_999)]);
+
+        clock.set(time(NOW_S - 3_600));
+        assert_eq!(values(&store.get_window(10_000)), vec![]);
+    }
+
+    #[test]
+    fn evicts_by_count() {
+        let retention = Retention { max_events: Some(3), ..Retention::default() };
+        let mut store = InMemory::with_retention(retention);
+        store.add_events(events_ago(&[500, 400, 300, 200, 100]));
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(100), Value::Int(200), Value::Int(300)]);
+        assert_eq!(store.evictions(), Evictions { count: 2, age: 0, bytes: 0 });
+    }
+
+    #[test]
+    fn evicts_by_age() {
+        let clock = manual_clock();
+        let retention = Retention { max_age_ms: Some(10_000), ..Retention::default() };
+        let mut store = InMemory::with_retention(retention).with_clock(clock.clone());
+        store.add_events(events_ago(&[60_000, 30_000, 100]));
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.evictions().age, 2);
+
+        clock.advance(Duration::seconds(60));
+        store.add_events(vec![Event::new(clock.now(), "temp_sensor_1", Value::Int(0))]);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.evictions().age, 3);
+    }
+
+    #[test]
+    fn evicts_by_bytes() {
+        let events = events_ago(&[300, 200, 100]);
+        let event_size = events[0].size_bytes();
+        let retention = Retention { max_bytes: Some(2 * event_size), ..Retention::default() };
+        let mut store = InMemory::with_retention(retention);
+        store.add_events(events);
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.num_bytes(), 2 * event_size);
+        assert_eq!(store.evictions().bytes, 1);
+        assert_eq!(store.evictions().total(), 1);
+    }
+
+    #[test]
+    fn range_queries() {
+        let mut store = InMemory::new();
+        store.add_events(at_seconds(&[10, 20, 30, 40, 50]));
+
+        assert_eq!(values(&store.get_range(time(20), time(40))), vec![Value::Int(20), Value::Int(30)]);
+        assert_eq!(values(&store.get_before(time(40), 2)), vec![Value::Int(20), Value::Int(30)]);
+        assert_eq!(values(&store.get_after(time(20), 2)), vec![Value::Int(30), Value::Int(40)]);
+        assert_eq!(values(&store.get_after(time(25), 1)), vec![Value::Int(30)]);
+        assert!(store.get_range(time(60), time(70)).is_empty());
+        assert!(store.get_before(time(10), 5).is_empty());
+    }
+
+    #[test]
+    fn cursor_resumes_between_equal_timestamps() {
+        let mut store = InMemory::new();
+        store.add_events(at_seconds(&[10, 20, 20, 20, 30]));
+
+        let mut cursor = Cursor::new();
+        assert_eq!(store.read(&mut cursor, 2).len(), 2);
+        assert_eq!(store.read(&mut cursor, 2).len(), 2);
+        assert_eq!(cursor.timestamp(), Some(time(20)));
+
+        store.add_events(at_seconds(&[40]));
+        assert_eq!(values(&store.read(&mut cursor, 5)), vec![Value::Int(30), Value::Int(40)]);
+        assert!(store.read(&mut cursor, 5).is_empty());
+
+        let mut cursor = Cursor::at(time(25));
+        assert_eq!(values(&store.read(&mut cursor, 1)), vec![Value::Int(30)]);
+    }
+
+    #[test]
+    fn applies_lateness_per_series() {
+        let lateness = Lateness { max_lateness_ms: 5_000, policy: LatePolicy::SideOutput };
+        let mut store = InMemory::new().with_lateness(lateness);
+        store.add_events(at_seconds(&[100, 96, 90]));
+        store.add_events(vec![Event::new(time(50), "door_1", Value::Bool(true))]);
+
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(100), Value::Int(96), Value::Bool(true)]);
+        assert_eq!(values(&store.take_late()), vec![Value::Int(90)]);
+        assert_eq!(store.late_events(), LateEvents { out_of_order: 2, accepted: 0, dropped: 0, side_output: 1 });
+        assert_eq!(store.stats().out_of_order, 2);
+    }
+
+    fn policy() -> impl Strategy<Value = LatePolicy> {
+        prop_oneof![Just(LatePolicy::Drop), Just(LatePolicy::SideOutput), Just(LatePolicy::Accept)]
+    }
+
+    proptest! {
+        #[test]
+        fn buffer_stays_sorted(batches in prop::collection::vec(prop::collection::vec((0i64..1_000, 0usize..3), 0..40), 1..20),
+                               max_lateness_ms in 0i64..500_000,
+                               policy in policy()) {
+            let mut store = InMemory::new().with_lateness(Lateness { max_lateness_ms, policy });
+            let mut added = 0;
+
+            for batch in batches {
+                added += batch.len() as u64;
+                store.add_events(batch.iter()
+                    .map(|(s, sensor)| Event::new(time(*s), &format!("sensor_{}", sensor), Value::Int(*s)))
+                    .collect());
+
+                let newest_first: Vec<DateTime<Utc>> = store.get_window_of_n(u64::MAX).iter().map(|event| event.timestamp).collect();
+                prop_assert!(newest_first.windows(2).all(|pair| pair[0] >= pair[1]));
+
+                let late = store.late_events();
+                prop_assert_eq!(store.len() as u64, added - late.dropped - late.side_output);
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Event;
+use super::SeriesKey;
+
+// Late events held for the caller before the oldest are dropped
+const SIDE_OUTPUT_CAPACITY: usize = 10_000;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// What happens to an event older than its series' watermark
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LatePolicy {
+    Drop,
+    // Kept aside for the caller to take instead of being stored
+    SideOutput,
+    Accept
+}
+
+// An event is too late once it is more than max_lateness_ms older than the newest event seen on
+// its series. The default accepts every event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lateness {
+    pub max_lateness_ms: i64,
+    pub policy: LatePolicy
+}
+
+// Counts since the store was created. Out of order events are those older than an event already
+// seen on their series, the others count the too late events by what was done with them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LateEvents {
+    pub out_of_order: u64,
+    pub accepted: u64,
+    pub dropped: u64,
+    pub side_output: u64
+}
+
+// Tracks the newest event of each series and decides which incoming events are stored
+pub struct Watermarks {
+    lateness: Lateness,
+    newest: HashMap<SeriesKey, DateTime<Utc>>,
+    late_events: LateEvents,
+    side_output: VecDeque<Event>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Default for Lateness {
+    fn default() -> Lateness {
+        Lateness {
+            max_lateness_ms: i64::MAX,
+            policy: LatePolicy::Accept
+        }
+    }
+}
+
+impl LateEvents {
+    pub fn too_late(&self) -> u64 {
+        self.accepted + self.dropped + self.side_output
+    }
+}
+
+impl Watermarks {
+    pub fn new(lateness: Lateness) -> Watermarks {
+        Watermarks {
+            lateness,
+            newest: HashMap::new(),
+            late_events: LateEvents::default(),
+            side_output: VecDeque::new()
+        }
+    }
+
+    pub fn lateness(&self) -> Lateness {
+        self.lateness
+    }
+
+    // Events of the series older than this are too late, None until the series has an event
+    pub fn watermark(&self, key: &SeriesKey) -> Option<DateTime<Utc>> {
+        self.newest.get(key).map(|newest| {
+            newest.checked_sub_signed(Duration::milliseconds(self.lateness.max_lateness_ms))
+                .unwrap_or(super::MIN_DATETIME)
+        })
+    }
+
+    pub fn late_events(&self) -> LateEvents {
+        self.late_events
+    }
+
+    // Late events set aside by the side output policy, oldest arrival first
+    pub fn take_side_output(&mut self) -> Vec<Event> {
+        self.side_output.drain(..).collect()
+    }
+
+    // The events of a batch to store, sorted oldest first. Watermarks move forward in arrival
+    // order, so a batch is judged the same as its events arriving one at a time.
+    pub fn admit(&mut self, events: Vec<Event>) -> Vec<Event> {
+        let mut admitted = Vec::with_capacity(events.len());
+
+        for event in events {
+            let key = SeriesKey::of(&event);
+            let watermark = self.watermark(&key);
+
+            match self.newest.get_mut(&key) {
+                Some(newest) if event.timestamp < *newest => self.late_events.out_of_order += 1,
+                Some(newest) => *newest = event.timestamp,
+                None => { self.newest.insert(key, event.timestamp); }
+            }
+
+            if watermark.is_none_or(|watermark| event.timestamp >= watermark) {
+                admitted.push(event);
+                continue
+            }
+
+            match self.lateness.policy {
+                LatePolicy::Drop => self.late_events.dropped += 1,
+                LatePolicy::SideOutput => self.set_aside(event),
+                LatePolicy::Accept => {
+                    self.late_events.accepted += 1;
+                    admitted.push(event);
+                }
+            }
+        }
+
+        // Stable, so events with equal timestamps keep their arrival order
+        admitted.sort_by_key(|event| event.timestamp);
+        admitted
+    }
+
+    fn set_aside(&mut self, event: Event) {
+        if self.side_output.len() == SIDE_OUTPUT_CAPACITY {
+            self.side_output.pop_front();
+            self.late_events.side_output -= 1;
+            self.late_events.dropped += 1;
+        }
+
+        self.side_output.push_back(event);
+        self.late_events.side_output += 1;
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::Value;
+    use super::*;
+
+    fn event(seconds: i64, sensor_id: &str) -> Event {
+        Event::new(Utc.timestamp_opt(seconds, 0).unwrap(), sensor_id, Value::Int(seconds))
+    }
+
+    fn seconds(events: &[Event]) -> Vec<i64> {
+        events.iter().map(|event| event.timestamp.timestamp()).collect()
+    }
+
+    #[test]
+    fn watermarks_are_per_series() {
+        let mut watermarks = Watermarks::new(Lateness { max_lateness_ms: 10_000, policy: LatePolicy::Drop });
+        let admitted = watermarks.admit(vec![event(100, "a"), event(95, "a"), event(20, "b"), event(89, "a"), event(15, "b")]);
+
+        assert_eq!(seconds(&admitted), vec![15, 20, 95, 100]);
+        assert_eq!(watermarks.watermark(&SeriesKey::of(&event(0, "a"))), Some(Utc.timestamp_opt(90, 0).unwrap()));
+        assert_eq!(watermarks.late_events(), LateEvents { out_of_order: 3, accepted: 0, dropped: 1, side_output: 0 });
+    }
+
+    #[test]
+    fn sets_aside_or_accepts_late_events() {
+        let mut watermarks = Watermarks::new(Lateness { max_lateness_ms: 0, policy: LatePolicy::SideOutput });
+        assert_eq!(seconds(&watermarks.admit(vec![event(10, "a"), event(10, "a"), event(5, "a")])), vec![10, 10]);
+        assert_eq!(seconds(&watermarks.take_side_output()), vec![5]);
+        assert!(watermarks.take_side_output().is_empty());
+
+        let mut watermarks = Watermarks::new(Lateness { max_lateness_ms: 0, policy: LatePolicy::Accept });
+        assert_eq!(seconds(&watermarks.admit(vec![event(10, "a"), event(5, "a")])), vec![5, 10]);
+        assert_eq!(watermarks.late_events().too_late(), 1);
+    }
+}
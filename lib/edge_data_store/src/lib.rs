@@ -1,18 +1,321 @@
 pub mod in_memory;
+pub mod redis_store;
+pub mod file_store;
+pub mod sqlite_store;
+pub mod mmap_store;
+pub mod compressed;
+pub mod rollup;
+pub mod sketch;
+pub mod series_store;
+pub mod tiered;
+pub mod concurrent;
+pub mod async_store;
+pub mod feed;
+pub mod snapshot;
+pub mod export;
+pub mod resample;
+pub mod lateness;
+pub mod dedup;
+pub mod stats;
 
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
 extern crate edge_core;
+extern crate redis;
+extern crate rusqlite;
+extern crate serde_json;
+extern crate chrono;
+extern crate parquet;
+extern crate memmap2;
+extern crate tokio;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
 
 use edge_core::Event;
 
 pub use self::in_memory::InMemory;
+pub use self::in_memory::Evictions;
+pub use self::redis_store::RedisStore;
+pub use self::file_store::FileStore;
+pub use self::sqlite_store::SqliteStore;
+pub use self::mmap_store::MmapStore;
+pub use self::mmap_store::Record;
+pub use self::compressed::CompressedStore;
+pub use self::rollup::Rollup;
+pub use self::rollup::Tier;
+pub use self::rollup::Aggregate;
+pub use self::sketch::Sketch;
+pub use self::sketch::Sketched;
+pub use self::sketch::Bin;
+pub use self::series_store::SeriesStore;
+pub use self::series_store::SeriesKey;
+pub use self::series_store::Filter;
+pub use self::series_store::Aligned;
+pub use self::tiered::Tiered;
+pub use self::concurrent::ConcurrentStore;
+pub use self::concurrent::Locked;
+pub use self::concurrent::Snapshot;
+pub use self::async_store::Blocking;
+pub use self::feed::Feed;
+pub use self::feed::Change;
+pub use self::feed::Limit;
+pub use self::snapshot::Manifest;
+pub use self::snapshot::SeriesInfo;
+pub use self::export::Format;
+pub use self::export::Selection;
+pub use self::resample::Resample;
+pub use self::resample::Fill;
+pub use self::resample::Sample;
+pub use self::lateness::Lateness;
+pub use self::lateness::LatePolicy;
+pub use self::lateness::LateEvents;
+pub use self::lateness::Watermarks;
+pub use self::dedup::Dedup;
+pub use self::dedup::DedupKey;
+pub use self::dedup::ConflictPolicy;
+pub use self::dedup::Duplicates;
+pub use self::dedup::Deduped;
+pub use self::stats::Stats;
+pub use self::stats::Tracker;
 
 
 // Data types
 // -------------------------------------------------------------------------------------------------
+// The window calls return the newest events first, every other query returns events oldest first
 pub trait Store {
-    fn add_events(&mut self, events: Vec<Event>) -> ();
+    fn add_events(&mut self, events: Vec<Event>);
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event>;
+    fn get_window_of_n(&self, n: u64) -> Vec<Event>;
+
+    // Lazily walks the events at or after start, oldest first
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a>;
+
+    // Lazily walks the events strictly before end, newest first
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a>;
+
+    // Receives the events added from now on that match filter, plus eviction notices. At most
+    // capacity changes are buffered, a subscriber that falls further behind is told how many it
+    // missed.
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change>;
+
+    // Storage health for operators. The default walks every event and cannot know the insert rate
+    // or evictions, stores that keep their own counts override it.
+    fn stats(&self) -> Stats {
+        stats::scan(self)
+    }
+
+    fn iter_range<'a>(&'a self, start: DateTime<Utc>, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.iter_from(start).take_while(move |event| event.timestamp < end))
+    }
+
+    // Events in [start, end)
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Event> {
+        self.iter_range(start, end).collect()
+    }
+
+    // The n events immediately before timestamp
+    fn get_before(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        let mut events: Vec<Event> = self.iter_before(timestamp).take(n).collect();
+        events.reverse();
+        events
+    }
+
+    // The n events immediately after timestamp
+    fn get_after(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        self.iter_from(timestamp)
+            .skip_while(|event| event.timestamp == timestamp)
+            .take(n)
+            .collect()
+    }
+
+    // Reads up to n events from the cursor position and moves the cursor past them
+    fn read(&self, cursor: &mut Cursor, n: usize) -> Vec<Event> {
+        let events: Vec<Event> = match cursor.timestamp {
+            Some(timestamp) => self.iter_from(timestamp).skip(cursor.seen).take(n).collect(),
+            None => self.iter_from(MIN_DATETIME).take(n).collect()
+        };
+
+        for event in events.iter() {
+            cursor.advance(event.timestamp);
+        }
+
+        events
+    }
+}
+
+// Like Store but every method takes &self, so one store can be shared between threads in an Arc
+// and read while it is being written to
+pub trait SharedStore: Send + Sync {
+    fn add_events(&self, events: Vec<Event>);
     fn get_window(&self, win_len_ms: i64) -> Vec<Event>;
     fn get_window_of_n(&self, n: u64) -> Vec<Event>;
+
+    // Walks the events at or after start, oldest first
+    fn iter_from(&self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_>;
+
+    // Walks the events strictly before end, newest first
+    fn iter_before(&self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + '_>;
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change>;
+    fn stats(&self) -> Stats;
+
+    // Events in [start, end)
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Event> {
+        self.iter_from(start).take_while(|event| event.timestamp < end).collect()
+    }
+
+    // The n events immediately before timestamp
+    fn get_before(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        let mut events: Vec<Event> = self.iter_before(timestamp).take(n).collect();
+        events.reverse();
+        events
+    }
+
+    // The n events immediately after timestamp
+    fn get_after(&self, timestamp: DateTime<Utc>, n: usize) -> Vec<Event> {
+        self.iter_from(timestamp)
+            .skip_while(|event| event.timestamp == timestamp)
+            .take(n)
+            .collect()
+    }
+}
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Store for backends whose calls wait on the network or the disk, awaited on a tokio runtime
+// instead of blocking the caller's thread. Methods take &self like SharedStore so one store can
+// serve many tasks. There are no iterators, they cannot be held across an await.
+pub trait AsyncStore: Send + Sync {
+    fn add_events(&self, events: Vec<Event>) -> StoreFuture<'_, ()>;
+    fn get_window(&self, win_len_ms: i64) -> StoreFuture<'_, Vec<Event>>;
+    fn get_window_of_n(&self, n: u64) -> StoreFuture<'_, Vec<Event>>;
+
+    // Events in [start, end)
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> StoreFuture<'_, Vec<Event>>;
+
+    // The n events immediately before timestamp
+    fn get_before(&self, timestamp: DateTime<Utc>, n: usize) -> StoreFuture<'_, Vec<Event>>;
+
+    // The n events immediately after timestamp
+    fn get_after(&self, timestamp: DateTime<Utc>, n: usize) -> StoreFuture<'_, Vec<Event>>;
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change>;
+
+    // Waits for a call already running on the store to finish
+    fn stats(&self) -> Stats;
+}
+
+// Position in a store that survives between reads. Events sharing the cursor timestamp are
+// counted so a read that stops in the middle of them resumes at the right one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cursor {
+    timestamp: Option<DateTime<Utc>>,
+    seen: usize
+}
+
+pub const MIN_DATETIME: DateTime<Utc> = DateTime::<Utc>::MIN_UTC;
+pub const MAX_DATETIME: DateTime<Utc> = DateTime::<Utc>::MAX_UTC;
+
+// The most points a query on a fixed grid lays out, a wider range or a finer step is refused
+pub const MAX_GRID_POINTS: usize = 100_000;
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Cursor {
+    // Starts at the oldest event in the store
+    pub fn new() -> Cursor {
+        Cursor::default()
+    }
+
+    // Starts at the first event at or after timestamp
+    pub fn at(timestamp: DateTime<Utc>) -> Cursor {
+        Cursor {
+            timestamp: Some(timestamp),
+            seen: 0
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+
+    fn advance(&mut self, timestamp: DateTime<Utc>) {
+        if self.timestamp == Some(timestamp) {
+            self.seen += 1;
+        } else {
+            self.timestamp = Some(timestamp);
+            self.seen = 1;
+        }
+    }
+}
+
+// A shared store handed out in an Arc is also a plain Store, e.g. for a Service stream, while the
+// other clones of the Arc keep reading it
+impl<T: SharedStore + ?Sized> Store for Arc<T> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        SharedStore::add_events(self.as_ref(), events)
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        SharedStore::get_window(self.as_ref(), win_len_ms)
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        SharedStore::get_window_of_n(self.as_ref(), n)
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        SharedStore::iter_from(self.as_ref(), start)
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        SharedStore::iter_before(self.as_ref(), end)
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        SharedStore::subscribe(self.as_ref(), filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        SharedStore::stats(self.as_ref())
+    }
+}
+
+// Lets boxed stores, e.g. those a Service creates from config, go wherever a Store is expected
+impl<S: Store + ?Sized> Store for Box<S> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        self.as_mut().add_events(events)
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        self.as_ref().get_window(win_len_ms)
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.as_ref().get_window_of_n(n)
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.as_ref().iter_from(start)
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.as_ref().iter_before(end)
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.as_ref().subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        self.as_ref().stats()
+    }
 }
 
 
@@ -0,0 +1,654 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+use std::ops::Deref;
+use std::slice;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use memmap2::Mmap;
+use memmap2::MmapMut;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Quality;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use edge_core::Value;
+use super::Change;
+use super::Evictions;
+use super::Feed;
+use super::Filter;
+use super::SeriesKey;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+
+const MAGIC: &[u8; 8] = b"EDGEMMAP";
+const VERSION: u32 = 1;
+
+// The header holds the ring position and the series table as json, records start on the next page
+const HEADER_BYTES: usize = 4096;
+const SERIES_OFFSET: usize = 44;
+pub const RECORD_BYTES: usize = mem::size_of::<Record>();
+
+const KIND_INT: u8 = 0;
+const KIND_FLOAT: u8 = 1;
+const KIND_BOOL: u8 = 2;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// One event as laid out in the file. The file uses the byte order of the machine that wrote it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record {
+    pub timestamp_ns: i64,
+    bits: u64,
+    // Index into the store's series table
+    pub series: u16,
+    kind: u8,
+    quality: u8,
+    padding: u32
+}
+
+// Keeps the newest capacity numeric events in a ring of fixed size records in a memory mapped
+// file, the page cache decides what is actually in memory. Each batch is added in time order and
+// events older than the newest stored event are dropped, the ring is never reordered.
+pub struct MmapStore {
+    map: Map,
+    capacity: usize,
+    head: usize,
+    count: usize,
+    series: Vec<SeriesKey>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    evictions: Evictions,
+    dropped: u64,
+    tracker: Tracker,
+    feed: Feed
+}
+
+// The store adding events maps the file writable, readers beside it map it read only
+enum Map {
+    Writable(MmapMut),
+    ReadOnly(Mmap)
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Record {
+    fn of(event: &Event, series: u16) -> Option<Record> {
+        let (kind, bits) = match event.value {
+            Value::Int(value) => (KIND_INT, value as u64),
+            Value::Float(value) => (KIND_FLOAT, value.to_bits()),
+            Value::Bool(value) => (KIND_BOOL, value as u64),
+            Value::Text(_) => return None
+        };
+
+        Some(Record {
+            timestamp_ns: event.timestamp.timestamp_nanos_opt()?,
+            bits,
+            series,
+            kind,
+            quality: event.quality.code(),
+            padding: 0
+        })
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        Utc.timestamp_nanos(self.timestamp_ns)
+    }
+
+    pub fn value(&self) -> Value {
+        match self.kind {
+            KIND_INT => Value::Int(self.bits as i64),
+            KIND_BOOL => Value::Bool(self.bits != 0),
+            _ => Value::Float(f64::from_bits(self.bits))
+        }
+    }
+
+    pub fn quality(&self) -> Quality {
+        Quality::from_code(self.quality)
+    }
+}
+
+impl Map {
+    fn bytes_mut(&mut self) -> Option<&mut [u8]> {
+        match self {
+            Map::Writable(map) => Some(map),
+            Map::ReadOnly(_) => None
+        }
+    }
+}
+
+impl Deref for Map {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Map::Writable(map) => map,
+            Map::ReadOnly(map) => map
+        }
+    }
+}
+
+impl MmapStore {
+    // Opens the ring at path, creating it with room for capacity events. An existing file keeps
+    // the capacity it was created with.
+    pub fn open(path: &str, capacity: usize) -> Option<MmapStore> {
+        println!("Opening mmap store: {}", path);
+
+        match MmapStore::map(path, capacity, false) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                println!("Error opening mmap store: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // For reading a ring another process may be writing to. The file is never written and adding
+    // events fails. The ring position is read once, records the writer wraps over after that read
+    // back as the newer events that replaced them.
+    pub fn open_read_only(path: &str) -> Option<MmapStore> {
+        println!("Opening mmap store read only: {}", path);
+
+        match MmapStore::map(path, 0, true) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                println!("Error opening mmap store: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> MmapStore {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> MmapStore {
+        self.clock = clock;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.count * RECORD_BYTES
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    // Events dropped for being older than the newest stored event, or for having a text value
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn series(&self, index: u16) -> Option<&SeriesKey> {
+        self.series.get(index as usize)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.map {
+            Map::Writable(map) => map.flush(),
+            Map::ReadOnly(_) => Ok(())
+        }
+    }
+
+    // The records of the last win_len_ms straight from the file, oldest first. The ring may wrap
+    // inside the window, the second slice continues the first.
+    pub fn window_slices(&self, win_len_ms: i64) -> (&[Record], &[Record]) {
+        let now = self.clock.now();
+        let end = self.partition(|record| record.timestamp() <= now);
+        let start = self.partition(|record| (now - record.timestamp()).num_milliseconds() > win_len_ms).min(end);
+
+        self.slices(start, end)
+    }
+
+    // All the records, oldest first
+    pub fn as_slices(&self) -> (&[Record], &[Record]) {
+        self.slices(0, self.count)
+    }
+
+    pub fn to_event(&self, record: &Record) -> Event {
+        let mut event = Event::new(record.timestamp(), "", record.value()).with_quality(record.quality());
+
+        if let Some(key) = self.series(record.series) {
+            event.sensor_id = key.sensor_id.clone();
+            event.tags = key.tags.clone();
+        }
+
+        event
+    }
+
+    fn map(path: &str, capacity: usize, read_only: bool) -> io::Result<MmapStore> {
+        let existing = fs::metadata(path).map(|metadata| metadata.len() > 0).unwrap_or(false);
+        if read_only && !existing {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No mmap store to read"))
+        }
+        if !existing && capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Capacity must be at least one event"))
+        }
+
+        let map = if read_only {
+            let file = OpenOptions::new().read(true).open(path)?;
+            Map::ReadOnly(unsafe { Mmap::map(&file)? })
+        } else {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+            if !existing {
+                let len = file_len(capacity)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Capacity is too large"))?;
+                file.set_len(len as u64)?;
+            }
+
+            // The file is only ever changed through this store
+            let mut map = unsafe { MmapMut::map_mut(&file)? };
+            if !existing {
+                map[..8].copy_from_slice(MAGIC);
+                map[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+                map[12..16].copy_from_slice(&(RECORD_BYTES as u32).to_ne_bytes());
+                map[16..24].copy_from_slice(&(capacity as u64).to_ne_bytes());
+            }
+            Map::Writable(map)
+        };
+
+        let mut store = MmapStore {
+            map,
+            capacity,
+            head: 0,
+            count: 0,
+            series: Vec::new(),
+            retention: Retention::default(),
+            clock: Arc::new(SystemClock),
+            evictions: Evictions::default(),
+            dropped: 0,
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        };
+
+        if existing {
+            store.read_header()?;
+        } else {
+            store.write_header();
+            store.write_series();
+        }
+
+        Ok(store)
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if self.map.len() < HEADER_BYTES || &self.map[..8] != MAGIC {
+            return Err(invalid(String::from("Not an mmap store")))
+        }
+
+        let version = self.read_u32(8);
+        let record_bytes = self.read_u32(12) as usize;
+        if version != VERSION || record_bytes != RECORD_BYTES {
+            return Err(invalid(format!("Unsupported mmap store version: {} with records of {} bytes", version, record_bytes)))
+        }
+
+        // Read from a file that may be damaged, so any value that does not fit is rejected
+        let damaged = || invalid(String::from("Damaged mmap store header"));
+        self.capacity = usize::try_from(self.read_u64(16)).map_err(|_| damaged())?;
+        self.head = usize::try_from(self.read_u64(24)).map_err(|_| damaged())?;
+        self.count = usize::try_from(self.read_u64(32)).map_err(|_| damaged())?;
+
+        let len = file_len(self.capacity).ok_or_else(damaged)?;
+        if self.map.len() < len || self.head >= self.capacity.max(1) || self.count > self.capacity {
+            return Err(damaged())
+        }
+
+        let len = self.read_u32(40) as usize;
+        let json = self.map.get(SERIES_OFFSET..SERIES_OFFSET + len).ok_or_else(|| invalid(String::from("Damaged series table")))?;
+        let series: Vec<(String, BTreeMap<String, String>)> = serde_json::from_slice(json)?;
+        self.series = series.into_iter().map(|(sensor_id, tags)| SeriesKey { sensor_id, tags }).collect();
+
+        Ok(())
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_ne_bytes(self.map[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        u64::from_ne_bytes(self.map[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write_header(&mut self) {
+        let (head, count) = (self.head, self.count);
+        if let Some(map) = self.map.bytes_mut() {
+            map[24..32].copy_from_slice(&(head as u64).to_ne_bytes());
+            map[32..40].copy_from_slice(&(count as u64).to_ne_bytes());
+        }
+    }
+
+    fn write_series(&mut self) -> bool {
+        let series: Vec<(&String, &BTreeMap<String, String>)> = self.series.iter()
+            .map(|key| (&key.sensor_id, &key.tags))
+            .collect();
+        let json = serde_json::to_vec(&series).unwrap_or_default();
+
+        let map = match self.map.bytes_mut() {
+            Some(map) if SERIES_OFFSET + json.len() <= HEADER_BYTES => map,
+            _ => return false
+        };
+
+        map[SERIES_OFFSET..SERIES_OFFSET + json.len()].copy_from_slice(&json);
+        map[40..44].copy_from_slice(&(json.len() as u32).to_ne_bytes());
+        true
+    }
+
+    fn series_index(&mut self, event: &Event) -> Option<u16> {
+        let key = SeriesKey::of(event);
+
+        if let Some(index) = self.series.iter().position(|series| *series == key) {
+            return Some(index as u16)
+        }
+
+        if self.series.len() > u16::MAX as usize { return None }
+
+        self.series.push(key);
+        if !self.write_series() {
+            println!("Mmap store series table is full, dropping: {:?}", event.sensor_id);
+            self.series.pop();
+            return None
+        }
+
+        Some(self.series.len() as u16 - 1)
+    }
+
+    fn records(&self) -> &[Record] {
+        // The records start page aligned and every bit pattern is a valid Record
+        unsafe { slice::from_raw_parts(self.map[HEADER_BYTES..].as_ptr() as *const Record, self.capacity) }
+    }
+
+    fn records_mut(&mut self) -> Option<&mut [Record]> {
+        let capacity = self.capacity;
+        let map = self.map.bytes_mut()?;
+        Some(unsafe { slice::from_raw_parts_mut(map[HEADER_BYTES..].as_mut_ptr() as *mut Record, capacity) })
+    }
+
+    // Position in the ring of the ith oldest record
+    fn physical(&self, index: usize) -> usize {
+        (self.head + self.capacity - self.count + index) % self.capacity
+    }
+
+    fn get(&self, index: usize) -> &Record {
+        &self.records()[self.physical(index)]
+    }
+
+    fn newest(&self) -> Option<&Record> {
+        if self.count == 0 { None } else { Some(self.get(self.count - 1)) }
+    }
+
+    // The number of oldest records for which pred holds, pred must hold for a prefix
+    fn partition(&self, pred: impl Fn(&Record) -> bool) -> usize {
+        let (mut lo, mut hi) = (0, self.count);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if pred(self.get(mid)) { lo = mid + 1 } else { hi = mid }
+        }
+
+        lo
+    }
+
+    fn slices(&self, start: usize, end: usize) -> (&[Record], &[Record]) {
+        if start >= end { return (&[], &[]) }
+
+        let records = self.records();
+        let first = self.physical(start);
+        let len = end - start;
+
+        if first + len <= self.capacity {
+            (&records[first..first + len], &[])
+        } else {
+            (&records[first..], &records[..first + len - self.capacity])
+        }
+    }
+
+    fn push(&mut self, record: Record) {
+        let head = self.head;
+        if let Some(records) = self.records_mut() {
+            records[head] = record;
+        }
+        self.head = (self.head + 1) % self.capacity;
+
+        if self.count == self.capacity {
+            self.evictions.count += 1;
+        } else {
+            self.count += 1;
+        }
+    }
+
+    fn evict(&mut self) {
+        if let Some(max_events) = self.retention.max_events {
+            while self.count > max_events {
+                self.count -= 1;
+                self.evictions.count += 1;
+            }
+        }
+
+        if let Some(max_age_ms) = self.retention.max_age_ms {
+            let now = self.clock.now();
+
+            while self.count > 0 && (now - self.get(0).timestamp()).num_milliseconds() > max_age_ms {
+                self.count -= 1;
+                self.evictions.age += 1;
+            }
+        }
+
+        if let Some(max_bytes) = self.retention.max_bytes {
+            while self.num_bytes() > max_bytes {
+                self.count -= 1;
+                self.evictions.bytes += 1;
+            }
+        }
+    }
+
+    fn iter_range(&self, start: usize, end: usize) -> impl DoubleEndedIterator<Item = Event> + '_ {
+        let (first, second) = self.slices(start, end);
+        first.iter().chain(second.iter()).map(move |record| self.to_event(record))
+    }
+}
+
+impl Store for MmapStore {
+    fn add_events(&mut self, mut events: Vec<Event>) {
+        if events.is_empty() { return }
+        if let Map::ReadOnly(_) = self.map {
+            println!("Error writing to mmap store: store is open read only");
+            return
+        }
+
+        self.tracker.added(&events, self.clock.now());
+        events.sort_by_key(|event| event.timestamp);
+        let evictions = self.evictions;
+        let publish = self.feed.is_active();
+        let mut inserted = Vec::new();
+
+        for event in events {
+            if self.newest().is_some_and(|newest| event.timestamp < newest.timestamp()) {
+                self.dropped += 1;
+                continue
+            }
+
+            let record = match self.series_index(&event).and_then(|series| Record::of(&event, series)) {
+                Some(record) => record,
+                None => {
+                    self.dropped += 1;
+                    continue
+                }
+            };
+
+            self.push(record);
+            if publish { inserted.push(event) }
+        }
+
+        self.evict();
+        self.write_header();
+        self.feed.inserted(&inserted);
+        self.feed.evicted(evictions, self.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let (first, second) = self.window_slices(win_len_ms);
+        first.iter().chain(second.iter()).rev().map(|record| self.to_event(record)).collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        let start = self.count.saturating_sub(n as usize);
+        self.iter_range(start, self.count).rev().collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let index = self.partition(|record| record.timestamp() < start);
+        Box::new(self.iter_range(index, self.count))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let index = self.partition(|record| record.timestamp() < end);
+        Box::new(self.iter_range(0, index).rev())
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            count: self.count,
+            bytes: self.num_bytes(),
+            oldest: if self.count == 0 { None } else { Some(self.get(0).timestamp()) },
+            newest: self.newest().map(|record| record.timestamp()),
+            evictions: self.evictions,
+            ..self.tracker.stats(self.clock.now())
+        }
+    }
+}
+
+// Bytes in a file holding capacity records, None when that does not fit in a usize
+fn file_len(capacity: usize) -> Option<usize> {
+    capacity.checked_mul(RECORD_BYTES)?.checked_add(HEADER_BYTES)
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use edge_core::ManualClock;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    fn test_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("edge_mmap_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn readings(seconds: std::ops::Range<i64>) -> Vec<Event> {
+        seconds.map(|s| Event::new(time(s), "vibration_1", Value::Float(s as f64 / 2.0)).with_tag("axis", "x")).collect()
+    }
+
+    fn seconds(events: &[Event]) -> Vec<i64> {
+        events.iter().map(|event| event.timestamp.timestamp()).collect()
+    }
+
+    #[test]
+    fn wraps_around_the_ring() {
+        let clock = Arc::new(ManualClock::new(time(12)));
+        let mut store = MmapStore::open(&test_path("ring.mmap"), 5).unwrap().with_clock(clock);
+        store.add_events(readings(0..8));
+        store.add_events(vec![Event::new(time(8), "door_1", Value::Bool(true)).with_quality(Quality::Bad)]);
+
+        assert_eq!(RECORD_BYTES, 24);
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.evictions().count, 4);
+        assert_eq!(seconds(&store.get_window_of_n(3)), vec![8, 7, 6]);
+        assert_eq!(seconds(&store.get_range(time(5), MAX_DATETIME)), vec![5, 6, 7, 8]);
+        assert_eq!(seconds(&store.get_before(time(6), 10)), vec![4, 5]);
+
+        let (first, second) = store.window_slices(10_000);
+        assert_eq!((first.len(), second.len()), (1, 4));
+        assert_eq!(store.to_event(&second[3]), Event::new(time(8), "door_1", Value::Bool(true)).with_quality(Quality::Bad));
+        assert_eq!(store.get_window(10_000)[2], readings(6..7).remove(0));
+    }
+
+    #[test]
+    fn drops_late_and_text_events() {
+        let mut store = MmapStore::open(&test_path("late.mmap"), 10).unwrap();
+        store.add_events(readings(5..8));
+        store.add_events(readings(3..6));
+        store.add_events(vec![Event::new(time(9), "door_1", Value::Text(String::from("open")))]);
+
+        assert_eq!(seconds(&store.get_range(MIN_DATETIME, MAX_DATETIME)), vec![5, 6, 7]);
+        assert_eq!(store.dropped(), 4);
+    }
+
+    #[test]
+    fn reopens_where_it_left_off() {
+        let path = test_path("reopen.mmap");
+        {
+            let mut store = MmapStore::open(&path, 4).unwrap();
+            store.add_events(readings(0..6));
+            store.flush().unwrap();
+        }
+
+        let mut store = MmapStore::open(&path, 100).unwrap();
+        assert_eq!(store.capacity(), 4);
+        assert_eq!(store.get_range(MIN_DATETIME, MAX_DATETIME), readings(2..6));
+
+        store.add_events(readings(6..7));
+        assert_eq!(seconds(&store.get_window_of_n(10)), vec![6, 5, 4, 3]);
+        drop(store);
+
+        // A reader maps the ring without changing it
+        let mut reader = MmapStore::open_read_only(&path).unwrap();
+        reader.add_events(readings(7..8));
+        assert_eq!(seconds(&reader.get_window_of_n(10)), vec![6, 5, 4, 3]);
+        assert!(reader.flush().is_ok());
+        drop(reader);
+        assert!(MmapStore::open_read_only(&test_path("missing.mmap")).is_none());
+
+        // A capacity that would overflow the expected file length
+        let mut data = fs::read(&path).unwrap();
+        data[16..24].copy_from_slice(&(u64::MAX / 2).to_ne_bytes());
+        fs::write(&path, &data).unwrap();
+        assert!(MmapStore::open(&path, 4).is_none());
+
+        fs::write(&path, b"not a ring").unwrap();
+        assert!(MmapStore::open(&path, 4).is_none());
+        assert!(MmapStore::open(&test_path("missing.mmap"), 0).is_none());
+        assert!(!Path::new(&test_path("missing.mmap")).exists());
+    }
+}
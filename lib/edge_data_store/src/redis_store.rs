@@ -0,0 +1,672 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use redis::RedisResult;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use super::Change;
+use super::Evictions;
+use super::Feed;
+use super::Filter;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+use super::stats;
+
+const KEY_PREFIX: &str = "rusty_edge:";
+const SEQ_SUFFIX: &str = ":seq";
+const PAGE_SIZE: usize = 256;
+
+
+// Sorted set access
+// -------------------------------------------------------------------------------------------------
+// The handful of sorted set commands the store needs. Implemented for a real redis connection and
+// for MemorySortedSet, an in-process stand-in used when no redis-server is available.
+pub trait SortedSet {
+    fn zadd(&mut self, key: &str, members: Vec<(i64, String)>) -> RedisResult<()>;
+    fn zrevrange_by_score(&mut self, key: &str, max: i64, min: i64) -> RedisResult<Vec<String>>;
+    fn zrevrange(&mut self, key: &str, start: usize, stop: usize) -> RedisResult<Vec<String>>;
+
+    // Up to count members in an inclusive score range with their scores, members with the same
+    // score in byte order
+    fn zrange_by_score_limit(&mut self, key: &str, min: i64, max: i64,
+                             count: usize) -> RedisResult<Vec<(String, i64)>>;
+    fn zrevrange_by_score_limit(&mut self, key: &str, max: i64, min: i64,
+                                count: usize) -> RedisResult<Vec<(String, i64)>>;
+
+    // Both remove the members in an inclusive range and return how many went, negative ranks
+    // count back from the highest score
+    fn zremrange_by_score(&mut self, key: &str, min: i64, max: i64) -> RedisResult<usize>;
+    fn zremrange_by_rank(&mut self, key: &str, start: isize, stop: isize) -> RedisResult<usize>;
+
+    // Adds by to the counter at key and returns the new value
+    fn incr_by(&mut self, key: &str, by: i64) -> RedisResult<i64>;
+}
+
+impl SortedSet for redis::Connection {
+    fn zadd(&mut self, key: &str, members: Vec<(i64, String)>) -> RedisResult<()> {
+        if members.is_empty() { return Ok(()) }
+
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(key);
+
+        for (score, member) in members {
+            cmd.arg(score).arg(member);
+        }
+
+        cmd.query(self)
+    }
+
+    fn zrevrange_by_score(&mut self, key: &str, max: i64, min: i64) -> RedisResult<Vec<String>> {
+        redis::cmd("ZREVRANGEBYSCORE").arg(key).arg(max).arg(min).query(self)
+    }
+
+    fn zrevrange(&mut self, key: &str, start: usize, stop: usize) -> RedisResult<Vec<String>> {
+        redis::cmd("ZREVRANGE").arg(key).arg(start).arg(stop).query(self)
+    }
+
+    fn zrange_by_score_limit(&mut self, key: &str, min: i64, max: i64,
+                             count: usize) -> RedisResult<Vec<(String, i64)>> {
+        redis::cmd("ZRANGEBYSCORE").arg(key).arg(min).arg(max).arg("WITHSCORES")
+            .arg("LIMIT").arg(0).arg(count)
+            .query(self)
+    }
+
+    fn zrevrange_by_score_limit(&mut self, key: &str, max: i64, min: i64,
+                                count: usize) -> RedisResult<Vec<(String, i64)>> {
+        redis::cmd("ZREVRANGEBYSCORE").arg(key).arg(max).arg(min).arg("WITHSCORES")
+            .arg("LIMIT").arg(0).arg(count)
+            .query(self)
+    }
+
+    fn zremrange_by_score(&mut self, key: &str, min: i64, max: i64) -> RedisResult<usize> {
+        redis::cmd("ZREMRANGEBYSCORE").arg(key).arg(min).arg(max).query(self)
+    }
+
+    fn zremrange_by_rank(&mut self, key: &str, start: isize, stop: isize) -> RedisResult<usize> {
+        redis::cmd("ZREMRANGEBYRANK").arg(key).arg(start).arg(stop).query(self)
+    }
+
+    fn incr_by(&mut self, key: &str, by: i64) -> RedisResult<i64> {
+        redis::cmd("INCRBY").arg(key).arg(by).query(self)
+    }
+}
+
+#[derive(Default)]
+pub struct MemorySortedSet {
+    sets: HashMap<String, BTreeSet<(i64, String)>>,
+    counters: HashMap<String, i64>
+}
+
+impl MemorySortedSet {
+    pub fn new() -> MemorySortedSet {
+        MemorySortedSet {
+            sets: HashMap::new(),
+            counters: HashMap::new()
+        }
+    }
+}
+
+impl SortedSet for MemorySortedSet {
+    fn zadd(&mut self, key: &str, members: Vec<(i64, String)>) -> RedisResult<()> {
+        let set = self.sets.entry(key.to_string()).or_default();
+
+        for member in members {
+            set.insert(member);
+        }
+
+        Ok(())
+    }
+
+    fn zrevrange_by_score(&mut self, key: &str, max: i64, min: i64) -> RedisResult<Vec<String>> {
+        match self.sets.get(key) {
+            Some(set) => {
+                Ok(set.iter()
+                    .rev()
+                    .filter(|(score, _)| *score <= max && *score >= min)
+                    .map(|(_, member)| member.clone())
+                    .collect())
+            },
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn zrevrange(&mut self, key: &str, start: usize, stop: usize) -> RedisResult<Vec<String>> {
+        match self.sets.get(key) {
+            Some(set) if stop >= start => {
+                Ok(set.iter()
+                    .rev()
+                    .skip(start)
+                    .take(stop - start + 1)
+                    .map(|(_, member)| member.clone())
+                    .collect())
+            },
+            _ => Ok(Vec::new())
+        }
+    }
+
+    fn zrange_by_score_limit(&mut self, key: &str, min: i64, max: i64,
+                             count: usize) -> RedisResult<Vec<(String, i64)>> {
+        match self.sets.get(key) {
+            Some(set) => {
+                Ok(set.iter()
+                    .filter(|(score, _)| *score <= max && *score >= min)
+                    .take(count)
+                    .map(|(score, member)| (member.clone(), *score))
+                    .collect())
+            },
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn zrevrange_by_score_limit(&mut self, key: &str, max: i64, min: i64,
+                                count: usize) -> RedisResult<Vec<(String, i64)>> {
+        match self.sets.get(key) {
+            Some(set) => {
+                Ok(set.iter()
+                    .rev()
+                    .filter(|(score, _)| *score <= max && *score >= min)
+                    .take(count)
+                    .map(|(score, member)| (member.clone(), *score))
+                    .collect())
+            },
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn zremrange_by_score(&mut self, key: &str, min: i64, max: i64) -> RedisResult<usize> {
+        match self.sets.get_mut(key) {
+            Some(set) => {
+                let before = set.len();
+                set.retain(|(score, _)| *score < min || *score > max);
+                Ok(before - set.len())
+            },
+            None => Ok(0)
+        }
+    }
+
+    fn zremrange_by_rank(&mut self, key: &str, start: isize, stop: isize) -> RedisResult<usize> {
+        match self.sets.get_mut(key) {
+            Some(set) => {
+                let len = set.len() as isize;
+                let rank = |index: isize| if index < 0 { len + index } else { index };
+                let (start, stop) = (rank(start).max(0), rank(stop).min(len - 1));
+                if start > stop { return Ok(0) }
+
+                let removed: Vec<(i64, String)> = set.iter()
+                    .skip(start as usize)
+                    .take((stop - start + 1) as usize)
+                    .cloned()
+                    .collect();
+                for member in removed.iter() {
+                    set.remove(member);
+                }
+
+                Ok(removed.len())
+            },
+            None => Ok(0)
+        }
+    }
+
+    fn incr_by(&mut self, key: &str, by: i64) -> RedisResult<i64> {
+        let counter = self.counters.entry(key.to_string()).or_insert(0);
+        *counter += by;
+        Ok(*counter)
+    }
+}
+
+
+// Store
+// -------------------------------------------------------------------------------------------------
+// Events are kept in one sorted set per sensor, scored by the event timestamp in milliseconds and
+// stored as json members, so any process pointed at the same redis-server sees the same window.
+// Each member starts with a number from a per sensor counter, so equal readings stay apart and
+// members with the same score sort in the order they were added. Retention is applied on every add,
+// except max_bytes as redis keeps no sizes the store can read.
+pub struct RedisStore<C: SortedSet = redis::Connection> {
+    key: String,
+    conn: RefCell<C>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    evictions: Evictions,
+    tracker: Tracker,
+    feed: Feed
+}
+
+impl RedisStore {
+    pub fn new(url: &str, sensor_id: &str) -> Option<RedisStore> {
+        println!("Connecting to redis: {}", url);
+
+        let client = match redis::Client::open(url) {
+            Ok(client) => client,
+            Err(e) => {
+                println!("Error creating the redis client: {:?}", e);
+                return None
+            }
+        };
+
+        match client.get_connection() {
+            Ok(conn) => Some(RedisStore::with_connection(conn, sensor_id)),
+            Err(e) => {
+                println!("Error connecting to redis: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+impl<C: SortedSet> RedisStore<C> {
+    pub fn with_connection(conn: C, sensor_id: &str) -> RedisStore<C> {
+        RedisStore {
+            key: [KEY_PREFIX, sensor_id].concat(),
+            conn: RefCell::new(conn),
+            retention: Retention::default(),
+            clock: Arc::new(SystemClock),
+            evictions: Evictions::default(),
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        }
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> RedisStore<C> {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RedisStore<C> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    fn evict(&mut self) -> RedisResult<()> {
+        let conn = self.conn.get_mut();
+
+        if let Some(max_age_ms) = self.retention.max_age_ms {
+            let cutoff = self.clock.now().timestamp_millis() - max_age_ms;
+            self.evictions.age += conn.zremrange_by_score(&self.key, i64::MIN, cutoff - 1)? as u64;
+        }
+
+        if let Some(max_events) = self.retention.max_events {
+            let stop = -(max_events as isize) - 1;
+            self.evictions.count += conn.zremrange_by_rank(&self.key, 0, stop)? as u64;
+        }
+
+        Ok(())
+    }
+
+    fn decode(&self, result: RedisResult<Vec<String>>) -> Vec<Event> {
+        match result {
+            Ok(members) => decode_members(&members),
+            Err(e) => {
+                println!("Error reading from redis: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn pages(&self, ascending: bool, bound: DateTime<Utc>) -> Pages<'_, C> {
+        Pages {
+            store: self,
+            ascending,
+            bound_ms: bound.timestamp_millis(),
+            last: None,
+            at_last: 0,
+            page: Vec::new().into_iter(),
+            done: false
+        }
+    }
+}
+
+// [sequence number]:[json event], members written before the sequence number are plain json
+fn encode_member(seq: i64, event: &Event) -> serde_json::Result<String> {
+    Ok(format!("{:020}:{}", seq, serde_json::to_string(event)?))
+}
+
+fn decode_members(members: &[String]) -> Vec<Event> {
+    let mut events = Vec::new();
+
+    for member in members.iter() {
+        let json = match member.split_once(':') {
+            Some((seq, json)) if seq.bytes().all(|byte| byte.is_ascii_digit()) => json,
+            _ => member.as_str()
+        };
+
+        match serde_json::from_str::<Event>(json) {
+            Ok(event) => events.push(event),
+            Err(e) => println!("Error parsing event from redis: {:?}", e)
+        }
+    }
+
+    events
+}
+
+// Walks the sorted set a page at a time from a millisecond score bound, so iterating a long range
+// never pulls the whole range out of redis at once. Each page resumes strictly after the last
+// (score, member) returned, so members added or trimmed by another process during a scan are not
+// skipped or returned twice.
+struct Pages<'a, C: SortedSet> {
+    store: &'a RedisStore<C>,
+    ascending: bool,
+    bound_ms: i64,
+    last: Option<(i64, String)>,
+    // Members with the last score up to the last member, read again by the next page and skipped
+    at_last: usize,
+    page: std::vec::IntoIter<Event>,
+    done: bool
+}
+
+impl<'a, C: SortedSet> Iterator for Pages<'a, C> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.page.next() { return Some(event) }
+            if self.done { return None }
+
+            let key = &self.store.key;
+            let (from, count) = match &self.last {
+                Some((score, _)) => (*score, PAGE_SIZE + self.at_last),
+                None => (self.bound_ms, PAGE_SIZE)
+            };
+            let mut conn = self.store.conn.borrow_mut();
+            let result = if self.ascending {
+                conn.zrange_by_score_limit(key, from, i64::MAX, count)
+            } else {
+                conn.zrevrange_by_score_limit(key, from, i64::MIN, count)
+            };
+
+            match result {
+                Ok(members) => {
+                    self.done = members.len() < count;
+                    let page = self.next_page(&members);
+                    self.page = decode_members(&page).into_iter();
+                },
+                Err(e) => {
+                    println!("Error reading from redis: {:?}", e);
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, C: SortedSet> Pages<'a, C> {
+    // The members past the last one returned, in scan order
+    fn next_page(&mut self, members: &[(String, i64)]) -> Vec<String> {
+        let mut page = Vec::new();
+        let mut run = 0;
+
+        for (i, (member, score)) in members.iter().enumerate() {
+            run = if i > 0 && members[i - 1].1 == *score { run + 1 } else { 1 };
+
+            let past = match &self.last {
+                Some((last_score, last_member)) => {
+                    let order = (*score, member.as_str()).cmp(&(*last_score, last_member.as_str()));
+                    if self.ascending { order.is_gt() } else { order.is_lt() }
+                },
+                None => true
+            };
+
+            if past {
+                self.last = Some((*score, member.clone()));
+                self.at_last = run;
+                page.push(member.clone());
+            }
+        }
+
+        // Everything read was at the last score and already returned, so read further into it
+        if page.is_empty() {
+            self.at_last = members.len();
+        }
+
+        page
+    }
+}
+
+impl<C: SortedSet> Store for RedisStore<C> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let evictions = self.evictions;
+
+        let seq_key = [self.key.as_str(), SEQ_SUFFIX].concat();
+        let last = match self.conn.get_mut().incr_by(&seq_key, events.len() as i64) {
+            Ok(last) => last,
+            Err(e) => {
+                println!("Error writing to redis: {:?}", e);
+                return
+            }
+        };
+
+        let mut members = Vec::new();
+        for (seq, event) in (last - events.len() as i64 + 1..).zip(events.iter()) {
+            match encode_member(seq, event) {
+                Ok(member) => members.push((event.timestamp.timestamp_millis(), member)),
+                Err(e) => println!("Error serializing event for redis: {:?}", e)
+            }
+        }
+
+        match self.conn.get_mut().zadd(&self.key, members) {
+            Ok(()) => self.feed.inserted(&events),
+            Err(e) => println!("Error writing to redis: {:?}", e)
+        }
+
+        if let Err(e) = self.evict() {
+            println!("Error applying redis retention: {:?}", e);
+        }
+        self.feed.evicted(evictions, self.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let max = self.clock.now().timestamp_millis();
+        let result = self.conn.borrow_mut().zrevrange_by_score(&self.key, max, max - win_len_ms);
+
+        self.decode(result)
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        if n == 0 { return Vec::new() }
+
+        let result = self.conn.borrow_mut().zrevrange(&self.key, 0, n as usize - 1);
+
+        self.decode(result)
+    }
+
+    // Scores only have millisecond resolution so the bound events are checked again once decoded
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.pages(true, start).filter(move |event| event.timestamp >= start))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.pages(false, end).filter(move |event| event.timestamp < end))
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    // Redis keeps no sizes the store can read, so this walks the whole set
+    fn stats(&self) -> Stats {
+        Stats {
+            insert_rate: self.tracker.insert_rate(self.clock.now()),
+            out_of_order: self.tracker.out_of_order(),
+            evictions: self.evictions,
+            ..stats::scan(self)
+        }
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::super::Cursor;
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp_opt(1_000_000, 0).unwrap()
+    }
+
+    fn events_ago(ages_ms: &[i64]) -> Vec<Event> {
+        let now = now();
+
+        ages_ms.iter()
+            .map(|age| Event::new(now - Duration::milliseconds(*age), "temp_sensor_1", Value::Int(*age)))
+            .collect()
+    }
+
+    #[test]
+    fn window_of_n_is_newest_first() {
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1");
+        store.add_events(events_ago(&[3000, 2000]));
+        store.add_events(events_ago(&[1000]));
+
+        let window = store.get_window_of_n(2);
+        assert_eq!(window.len(), 2);
+        assert!(window[0].timestamp > window[1].timestamp);
+        assert_eq!(store.get_window_of_n(10).len(), 3);
+        assert!(store.get_window_of_n(0).is_empty());
+    }
+
+    #[test]
+    fn window_follows_the_clock() {
+        let clock = Arc::new(ManualClock::new(now()));
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1")
+            .with_clock(clock.clone());
+        store.add_events(events_ago(&[60_000, 10_000, 500, 100]));
+
+        let window = store.get_window(10_000);
+        assert_eq!(window.len(), 3);
+        assert_eq!(window[0].value, Value::Int(100));
+
+        clock.advance(Duration::milliseconds(9_600));
+        assert_eq!(store.get_window(10_000).len(), 1);
+
+        clock.set(now() - Duration::milliseconds(20_000));
+        assert!(store.get_window(10_000).is_empty());
+    }
+
+    #[test]
+    fn range_queries_span_pages() {
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1");
+        let events = (0..600)
+            .map(|s| Event::new(Utc.timestamp_opt(s, 0).unwrap(), "temp_sensor_1", Value::Int(s)))
+            .collect();
+        store.add_events(events);
+
+        let start = Utc.timestamp_opt(100, 0).unwrap();
+        let end = Utc.timestamp_opt(500, 0).unwrap();
+        let range = store.get_range(start, end);
+        assert_eq!(range.len(), 400);
+        assert_eq!(range[0].value, Value::Int(100));
+        assert_eq!(range[399].value, Value::Int(499));
+
+        let before = store.get_before(end, 300);
+        assert_eq!(before.len(), 300);
+        assert_eq!(before[0].value, Value::Int(200));
+        assert_eq!(before[299].value, Value::Int(499));
+
+        let after = store.get_after(start, 2);
+        assert_eq!(after[0].value, Value::Int(101));
+
+        let mut cursor = Cursor::new();
+        let mut total = 0;
+
+        loop {
+            let events = store.read(&mut cursor, 250);
+            if events.is_empty() { break }
+            total += events.len();
+        }
+
+        assert_eq!(total, 600);
+    }
+
+    #[test]
+    fn scans_survive_concurrent_writes() {
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1");
+        let at = Utc.timestamp_opt(1_000, 0).unwrap();
+        let events = (0..600).map(|i| Event::new(at, "temp_sensor_1", Value::Int(i))).collect();
+        store.add_events(events);
+
+        let mut scan = store.iter_from(at);
+        let mut values: Vec<Value> = scan.by_ref().take(300).map(|event| event.value).collect();
+
+        // Another process trims the oldest members and adds a reading before the scan finishes
+        let member = encode_member(1_000, &Event::new(at, "temp_sensor_1", Value::Int(600))).unwrap();
+        let mut conn = store.conn.borrow_mut();
+        conn.zremrange_by_rank("rusty_edge:temp_sensor_1", 0, 99).unwrap();
+        conn.zadd("rusty_edge:temp_sensor_1", vec![(at.timestamp_millis(), member)]).unwrap();
+        drop(conn);
+
+        values.extend(scan.map(|event| event.value));
+        assert_eq!(values, (0..601).map(Value::Int).collect::<Vec<Value>>());
+    }
+
+    #[test]
+    fn keeps_equal_readings_in_order() {
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1");
+        let at = now();
+        store.add_events(vec![Event::new(at, "temp_sensor_1", Value::Int(9)), Event::new(at, "temp_sensor_1", Value::Int(1))]);
+        store.add_events(vec![Event::new(at, "temp_sensor_1", Value::Int(9))]);
+
+        let values: Vec<Value> = store.get_range(at, at + Duration::milliseconds(1)).into_iter().map(|event| event.value).collect();
+        assert_eq!(values, vec![Value::Int(9), Value::Int(1), Value::Int(9)]);
+
+        // Members written before the sequence number still read back
+        let member = serde_json::to_string(&events_ago(&[0])[0]).unwrap();
+        store.conn.get_mut().zadd("rusty_edge:temp_sensor_1", vec![(at.timestamp_millis(), member)]).unwrap();
+        assert_eq!(store.get_window_of_n(10).len(), 4);
+    }
+
+    #[test]
+    fn retention_trims_the_set() {
+        let clock = Arc::new(ManualClock::new(now()));
+        let retention = Retention { max_events: Some(3), max_age_ms: Some(5_000), ..Retention::default() };
+        let mut store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1")
+            .with_retention(retention)
+            .with_clock(clock.clone());
+        store.add_events(events_ago(&[9_000, 5_000, 4_000, 3_000, 2_000, 1_000]));
+
+        let ages: Vec<Value> = store.get_window_of_n(10).into_iter().map(|event| event.value).collect();
+        assert_eq!(ages, vec![Value::Int(1_000), Value::Int(2_000), Value::Int(3_000)]);
+        assert_eq!(store.evictions(), Evictions { count: 2, age: 1, bytes: 0 });
+    }
+
+    #[test]
+    fn sensors_use_separate_keys() {
+        let store = RedisStore::with_connection(MemorySortedSet::new(), "temp_sensor_1");
+        assert_eq!(store.key(), "rusty_edge:temp_sensor_1");
+    }
+
+    #[test]
+    #[ignore]
+    fn local_redis_server() {
+        let sensor_id = ["test_", &Utc::now().timestamp_millis().to_string()].concat();
+        let mut store = RedisStore::new("redis://127.0.0.1/", &sensor_id).unwrap();
+        let now = Utc::now();
+        store.add_events(vec![Event::new(now, &sensor_id, Value::Int(1)),
+                              Event::new(now - Duration::milliseconds(100), &sensor_id, Value::Int(2))]);
+
+        assert_eq!(store.get_window_of_n(5).len(), 2);
+        assert_eq!(store.get_window(60_000).len(), 2);
+    }
+}
@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
+use super::Filter;
+use super::Store;
+use super::MAX_DATETIME;
+use super::MAX_GRID_POINTS;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// How the value at each point of the grid is worked out from the events around it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fill {
+    // The last value at or before the point
+    Previous,
+    // Interpolated between the values either side of the point
+    Linear,
+    // The mean of the values in [point, point + interval)
+    Mean,
+    // The last value in [point, point + interval), missing if there is none
+    Null
+}
+
+// A point of a resampled series, value is None where it is missing
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub timestamp: DateTime<Utc>,
+    pub value: Option<f64>
+}
+
+// Turns the irregular numeric events of one series into a fixed rate series. Points fall on
+// multiples of interval_ms since the epoch, so series resampled at the same rate line up.
+#[derive(Clone)]
+pub struct Resample {
+    interval_ms: i64,
+    fill: Fill,
+    max_gap_ms: Option<i64>,
+    clock: Arc<dyn Clock>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Resample {
+    pub fn new(interval_ms: i64) -> Option<Resample> {
+        if interval_ms <= 0 {
+            println!("Invalid resample interval: {:?} ms", interval_ms);
+            return None
+        }
+
+        Some(Resample {
+            interval_ms,
+            fill: Fill::Previous,
+            max_gap_ms: None,
+            clock: Arc::new(SystemClock)
+        })
+    }
+
+    pub fn with_fill(mut self, fill: Fill) -> Resample {
+        self.fill = fill;
+        self
+    }
+
+    // Previous and linear fill give a missing point rather than bridge a gap between events
+    // longer than this, and only look this far outside the range for neighbours. The bucket
+    // fills are missing whenever their bucket is empty.
+    pub fn with_max_gap_ms(mut self, max_gap_ms: i64) -> Resample {
+        self.max_gap_ms = Some(max_gap_ms);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Resample {
+        self.clock = clock;
+        self
+    }
+
+    pub fn interval_ms(&self) -> i64 {
+        self.interval_ms
+    }
+
+    pub fn fill(&self) -> Fill {
+        self.fill
+    }
+
+    // The points in the last win_len_ms, oldest first
+    pub fn get_window(&self, store: &dyn Store, filter: &Filter, win_len_ms: i64) -> Option<Vec<Sample>> {
+        let now = self.clock.now();
+        self.get_range(store, filter, now - Duration::milliseconds(win_len_ms), now)
+    }
+
+    // The points in [start, end), oldest first, None when there are more than MAX_GRID_POINTS.
+    // Events that are not numeric are skipped.
+    pub fn get_range(&self, store: &dyn Store, filter: &Filter, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<Vec<Sample>> {
+        let grid = self.grid(start, end)?;
+        let (first, last) = match (grid.first(), grid.last()) {
+            (Some(first), Some(last)) => (*first, last.checked_add_signed(self.interval()).unwrap_or(MAX_DATETIME)),
+            _ => return Some(Vec::new())
+        };
+
+        let matching = |event: Event| if filter.matches_event(&event) { point(&event) } else { None };
+        let mut points: Vec<(DateTime<Utc>, f64)> = Vec::new();
+
+        // Only previous and linear fill look outside the range, and no further than max_gap_ms
+        let gap = self.max_gap_ms.map(Duration::milliseconds);
+        if self.fill == Fill::Previous || self.fill == Fill::Linear {
+            let horizon = gap.and_then(|gap| first.checked_sub_signed(gap));
+            points.extend(store.iter_before(first)
+                .take_while(|event| horizon.is_none_or(|horizon| event.timestamp >= horizon))
+                .filter_map(matching)
+                .take(1));
+        }
+
+        points.extend(store.iter_range(first, last).filter_map(matching));
+
+        if self.fill == Fill::Linear {
+            let after = match gap.and_then(|gap| last.checked_add_signed(gap + Duration::nanoseconds(1))) {
+                Some(horizon) => store.iter_range(last, horizon),
+                None => store.iter_from(last)
+            };
+            points.extend(after.filter_map(matching).take(1));
+        }
+
+        Some(grid.into_iter()
+            .map(|timestamp| Sample { timestamp, value: self.value_at(&points, timestamp) })
+            .collect())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::milliseconds(self.interval_ms)
+    }
+
+    fn grid(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<Vec<DateTime<Utc>>> {
+        let first_ms = start.timestamp_millis().div_euclid(self.interval_ms).saturating_mul(self.interval_ms);
+        let mut timestamp = match Utc.timestamp_millis_opt(first_ms).single() {
+            Some(timestamp) => timestamp,
+            None => return Some(Vec::new())
+        };
+
+        let mut grid = Vec::new();
+        if timestamp < start {
+            timestamp = match timestamp.checked_add_signed(self.interval()) {
+                Some(timestamp) => timestamp,
+                None => return Some(grid)
+            };
+        }
+
+        while timestamp < end {
+            if grid.len() == MAX_GRID_POINTS {
+                println!("Resampled range is more than {} points of {} ms", MAX_GRID_POINTS, self.interval_ms);
+                return None
+            }
+
+            grid.push(timestamp);
+            timestamp = match timestamp.checked_add_signed(self.interval()) {
+                Some(timestamp) => timestamp,
+                None => break
+            };
+        }
+
+        Some(grid)
+    }
+
+    // points are oldest first
+    fn value_at(&self, points: &[(DateTime<Utc>, f64)], timestamp: DateTime<Utc>) -> Option<f64> {
+        let after = points.partition_point(|(t, _)| *t <= timestamp);
+        let previous = after.checked_sub(1).map(|i| points[i]);
+
+        match self.fill {
+            Fill::Previous => {
+                let (t, value) = previous?;
+                if self.exceeds_gap(timestamp - t) { None } else { Some(value) }
+            },
+            Fill::Linear => {
+                let (t0, v0) = previous?;
+                if t0 == timestamp { return Some(v0) }
+
+                let (t1, v1) = *points.get(after)?;
+                if self.exceeds_gap(t1 - t0) { return None }
+
+                let fraction = micros(timestamp - t0) / micros(t1 - t0);
+                Some(v0 + (v1 - v0) * fraction)
+            },
+            Fill::Mean => {
+                let bucket = self.bucket(points, timestamp);
+                if bucket.is_empty() { return None }
+                Some(bucket.iter().map(|(_, value)| value).sum::<f64>() / bucket.len() as f64)
+            },
+            Fill::Null => self.bucket(points, timestamp).last().map(|(_, value)| *value)
+        }
+    }
+
+    fn bucket<'a>(&self, points: &'a [(DateTime<Utc>, f64)], timestamp: DateTime<Utc>) -> &'a [(DateTime<Utc>, f64)] {
+        let end = timestamp + self.interval();
+        let lo = points.partition_point(|(t, _)| *t < timestamp);
+        let hi = points.partition_point(|(t, _)| *t < end);
+        &points[lo..hi]
+    }
+
+    fn exceeds_gap(&self, gap: Duration) -> bool {
+        match self.max_gap_ms {
+            Some(max_gap_ms) => gap > Duration::milliseconds(max_gap_ms),
+            None => false
+        }
+    }
+}
+
+fn point(event: &Event) -> Option<(DateTime<Utc>, f64)> {
+    event.value.as_f64().map(|value| (event.timestamp, value))
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(i64::MAX) as f64
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    // Readings of temp_sensor_1 at 1s, 3s and 7s with a door event and a text value in between
+    fn store() -> InMemory {
+        let mut store = InMemory::new();
+        store.add_events(vec![
+            Event::new(time(1), "temp_sensor_1", Value::Float(10.0)),
+            Event::new(time(3), "temp_sensor_1", Value::Int(20)),
+            Event::new(time(4), "door_1", Value::Bool(true)),
+            Event::new(time(4), "temp_sensor_1", Value::Text(String::from("fault"))),
+            Event::new(time(7), "temp_sensor_1", Value::Float(60.0))
+        ]);
+        store
+    }
+
+    fn values(resample: &Resample) -> Vec<Option<f64>> {
+        let filter = Filter::new().sensor("temp_sensor_1");
+        resample.get_range(&store(), &filter, time(0), time(8)).unwrap().iter().map(|sample| sample.value).collect()
+    }
+
+    #[test]
+    fn fills_each_strategy() {
+        let resample = Resample::new(1_000).unwrap();
+        assert_eq!(values(&resample),
+                   vec![None, Some(10.0), Some(10.0), Some(20.0), Some(20.0), Some(20.0), Some(20.0), Some(60.0)]);
+        assert_eq!(values(&resample.clone().with_fill(Fill::Linear)),
+                   vec![None, Some(10.0), Some(15.0), Some(20.0), Some(30.0), Some(40.0), Some(50.0), Some(60.0)]);
+        assert_eq!(values(&resample.clone().with_fill(Fill::Mean)),
+                   vec![None, Some(10.0), None, Some(20.0), None, None, None, Some(60.0)]);
+        assert_eq!(values(&resample.with_fill(Fill::Null)),
+                   vec![None, Some(10.0), None, Some(20.0), None, None, None, Some(60.0)]);
+    }
+
+    #[test]
+    fn marks_long_gaps_missing() {
+        let resample = Resample::new(1_000).unwrap().with_max_gap_ms(2_000);
+        assert_eq!(values(&resample),
+                   vec![None, Some(10.0), Some(10.0), Some(20.0), Some(20.0), Some(20.0), None, Some(60.0)]);
+        assert_eq!(values(&resample.with_fill(Fill::Linear)),
+                   vec![None, Some(10.0), Some(15.0), Some(20.0), None, None, None, Some(60.0)]);
+    }
+
+    #[test]
+    fn finds_neighbours_up_to_the_gap() {
+        let filter = Filter::new().sensor("temp_sensor_1");
+        let linear = Resample::new(1_000).unwrap().with_fill(Fill::Linear);
+        let sample = |resample: Resample| resample.get_range(&store(), &filter, time(2), time(3)).unwrap()[0].value;
+
+        assert_eq!(sample(linear.clone().with_max_gap_ms(2_000)), Some(15.0));
+        assert_eq!(sample(linear.with_max_gap_ms(1_999)), None);
+        assert_eq!(sample(Resample::new(1_000).unwrap().with_max_gap_ms(1_000)), Some(10.0));
+        assert_eq!(sample(Resample::new(1_000).unwrap().with_max_gap_ms(999)), None);
+    }
+
+    #[test]
+    fn aligns_windows_to_the_interval() {
+        let clock = Arc::new(ManualClock::new(Utc.timestamp_millis_opt(7_600).unwrap()));
+        let resample = Resample::new(2_000).unwrap().with_clock(clock);
+        let samples = resample.get_window(&store(), &Filter::new().sensor("temp_sensor_1"), 5_000).unwrap();
+
+        assert_eq!(samples, vec![
+            Sample { timestamp: time(4), value: Some(20.0) },
+            Sample { timestamp: time(6), value: Some(20.0) }
+        ]);
+        assert!(Resample::new(0).is_none());
+        assert!(resample.get_range(&store(), &Filter::new(), time(0), MAX_DATETIME).is_none());
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
+use super::Change;
+use super::Filter;
+use super::feed::add_accepted;
+use super::Stats;
+use super::Store;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// One resolution kept by a rollup, buckets older than max_age_ms are dropped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tier {
+    pub resolution_ms: i64,
+    pub max_age_ms: Option<i64>
+}
+
+// Summary of the numeric events of one series in one bucket
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    pub start: DateTime<Utc>,
+    pub resolution_ms: i64,
+    pub sensor_id: String,
+    pub tags: BTreeMap<String, String>,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64
+}
+
+// Wraps any store and keeps min/max/mean/count aggregates at each tier's resolution, updated as
+// events are added. Only the events the wrapped store keeps are counted, late events it drops for
+// instance are not. The wrapped store holds the raw events under its own retention.
+pub struct Rollup<S: Store> {
+    store: S,
+    tiers: Vec<Buckets>,
+    clock: Arc<dyn Clock>
+}
+
+struct Buckets {
+    tier: Tier,
+    buckets: BTreeMap<i64, Vec<Aggregate>>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Aggregate {
+    fn of(event: &Event, start: DateTime<Utc>, resolution_ms: i64, value: f64) -> Aggregate {
+        Aggregate {
+            start,
+            resolution_ms,
+            sensor_id: event.sensor_id.clone(),
+            tags: event.tags.clone(),
+            count: 1,
+            min: value,
+            max: value,
+            sum: value
+        }
+    }
+
+    pub fn end(&self) -> DateTime<Utc> {
+        self.start + Duration::milliseconds(self.resolution_ms)
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        self.sensor_id == event.sensor_id && self.tags == event.tags
+    }
+}
+
+impl<S: Store> Rollup<S> {
+    pub fn new(store: S) -> Rollup<S> {
+        Rollup {
+            store,
+            tiers: Vec::new(),
+            clock: Arc::new(SystemClock)
+        }
+    }
+
+    // Tiers are kept finest first, adding a resolution that already exists replaces it
+    pub fn with_tier(mut self, tier: Tier) -> Rollup<S> {
+        if tier.resolution_ms <= 0 {
+            println!("Ignoring rollup tier with resolution: {:?} ms", tier.resolution_ms);
+            return self
+        }
+
+        self.tiers.retain(|buckets| buckets.tier.resolution_ms != tier.resolution_ms);
+        self.tiers.push(Buckets { tier, buckets: BTreeMap::new() });
+        self.tiers.sort_by_key(|buckets| buckets.tier.resolution_ms);
+        self
+    }
+
+    // Only ages out the rollups, the wrapped store keeps its own clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Rollup<S> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn tiers(&self) -> Vec<Tier> {
+        self.tiers.iter().map(|buckets| buckets.tier).collect()
+    }
+
+    // Aggregates starting in [start, end) at a resolution, oldest first. Empty if there is no
+    // tier at that resolution.
+    pub fn get_rollups(&self, resolution_ms: i64, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<Aggregate> {
+        let buckets = match self.tiers.iter().find(|buckets| buckets.tier.resolution_ms == resolution_ms) {
+            Some(buckets) => buckets,
+            None => return Vec::new()
+        };
+
+        buckets.buckets.range(bucket_id(start, resolution_ms)..)
+            .flat_map(|(_, aggregates)| aggregates.iter())
+            .skip_while(|aggregate| aggregate.start < start)
+            .take_while(|aggregate| aggregate.start < end)
+            .cloned()
+            .collect()
+    }
+
+    // The finest tier that still holds buckets starting at timestamp
+    pub fn resolution_for(&self, timestamp: DateTime<Utc>) -> Option<i64> {
+        let now = self.clock.now();
+
+        self.tiers.iter()
+            .find(|buckets| match buckets.tier.max_age_ms {
+                Some(max_age_ms) => (now - timestamp).num_milliseconds() <= max_age_ms,
+                None => true
+            })
+            .map(|buckets| buckets.tier.resolution_ms)
+    }
+
+    fn update(&mut self, events: &[Event]) {
+        for buckets in self.tiers.iter_mut() {
+            let resolution_ms = buckets.tier.resolution_ms;
+
+            for event in events {
+                let value = match event.value.as_f64() {
+                    Some(value) => value,
+                    None => continue
+                };
+
+                let id = bucket_id(event.timestamp, resolution_ms);
+                let aggregates = buckets.buckets.entry(id).or_default();
+
+                match aggregates.iter_mut().find(|aggregate| aggregate.matches(event)) {
+                    Some(aggregate) => aggregate.add(value),
+                    None => aggregates.push(Aggregate::of(event, bucket_start(id, resolution_ms), resolution_ms, value))
+                }
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let now = self.clock.now();
+
+        for buckets in self.tiers.iter_mut() {
+            let resolution_ms = buckets.tier.resolution_ms;
+
+            if let Some(max_age_ms) = buckets.tier.max_age_ms {
+                buckets.buckets.retain(|id, _| {
+                    let end = bucket_start(*id, resolution_ms) + Duration::milliseconds(resolution_ms);
+                    (now - end).num_milliseconds() <= max_age_ms
+                });
+            }
+        }
+    }
+}
+
+impl<S: Store> Store for Rollup<S> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        let accepted = add_accepted(&mut self.store, events);
+        self.update(&accepted);
+        self.prune();
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        self.store.get_window(win_len_ms)
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.store.get_window_of_n(n)
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.store.iter_from(start)
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.store.iter_before(end)
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.store.subscribe(filter, capacity)
+    }
+
+    // The raw events only, the aggregates are not counted
+    fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+}
+
+fn bucket_id(timestamp: DateTime<Utc>, resolution_ms: i64) -> i64 {
+    timestamp.timestamp_millis().div_euclid(resolution_ms)
+}
+
+fn bucket_start(id: i64, resolution_ms: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(id.saturating_mul(resolution_ms)).single().unwrap_or(super::MIN_DATETIME)
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use edge_core::Retention;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::super::LatePolicy;
+    use super::super::Lateness;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    const MINUTE_MS: i64 = 60 * 1000;
+    const HOUR_MS: i64 = 60 * MINUTE_MS;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn readings(readings: &[(i64, f64)]) -> Vec<Event> {
+        readings.iter()
+            .map(|(s, value)| Event::new(time(*s), "temp_sensor_1", Value::Float(*value)))
+            .collect()
+    }
+
+    fn tiered(store: InMemory) -> Rollup<InMemory> {
+        Rollup::new(store)
+            .with_tier(Tier { resolution_ms: HOUR_MS, max_age_ms: None })
+            .with_tier(Tier { resolution_ms: MINUTE_MS, max_age_ms: Some(2 * HOUR_MS) })
+            .with_clock(Arc::new(ManualClock::new(time(3_600))))
+    }
+
+    #[test]
+    fn aggregates_each_tier() {
+        let mut rollup = tiered(InMemory::new());
+        rollup.add_events(readings(&[(0, 1.0), (30, 3.0), (59, 2.0), (60, 10.0)]));
+        rollup.add_events(readings(&[(3_600, 7.0)]));
+        rollup.add_events(vec![Event::new(time(61), "temp_sensor_1", Value::Text(String::from("off")))]);
+
+        assert_eq!(rollup.tiers().iter().map(|tier| tier.resolution_ms).collect::<Vec<i64>>(),
+                   vec![MINUTE_MS, HOUR_MS]);
+        assert_eq!(rollup.store().len(), 6);
+
+        let minutes = rollup.get_rollups(MINUTE_MS, MIN_DATETIME, MAX_DATETIME);
+        assert_eq!(minutes.len(), 3);
+        assert_eq!((minutes[0].count, minutes[0].min, minutes[0].max, minutes[0].mean()), (3, 1.0, 3.0, 2.0));
+        assert_eq!(minutes[1].start, time(60));
+        assert_eq!(minutes[1].end(), time(120));
+
+        let hours = rollup.get_rollups(HOUR_MS, time(0), time(3_600));
+        assert_eq!(hours.len(), 1);
+        assert_eq!((hours[0].count, hours[0].min, hours[0].max, hours[0].sum), (4, 1.0, 10.0, 16.0));
+        assert!(rollup.get_rollups(1_000, MIN_DATETIME, MAX_DATETIME).is_empty());
+    }
+
+    #[test]
+    fn counts_only_kept_events() {
+        let lateness = Lateness { max_lateness_ms: 10_000, policy: LatePolicy::Drop };
+        let mut rollup = tiered(InMemory::new().with_lateness(lateness));
+        rollup.add_events(readings(&[(60, 1.0), (20, 9.0), (55, 2.0)]));
+
+        let minutes = rollup.get_rollups(MINUTE_MS, MIN_DATETIME, MAX_DATETIME);
+        assert_eq!(rollup.store().len(), 2);
+        assert_eq!(minutes.iter().map(|minute| minute.count).sum::<u64>(), 2);
+        assert_eq!((minutes[0].start, minutes[0].max), (time(0), 2.0));
+    }
+
+    #[test]
+    fn series_are_kept_apart() {
+        let mut rollup = tiered(InMemory::new());
+        rollup.add_events(vec![
+            Event::new(time(0), "temp_sensor_1", Value::Int(1)).with_tag("index", "0"),
+            Event::new(time(0), "temp_sensor_1", Value::Int(5)).with_tag("index", "1"),
+            Event::new(time(1), "temp_sensor_1", Value::Int(3)).with_tag("index", "0")
+        ]);
+
+        let minutes = rollup.get_rollups(MINUTE_MS, MIN_DATETIME, MAX_DATETIME);
+        assert_eq!(minutes.len(), 2);
+        assert_eq!((minutes[0].tags["index"].as_str(), minutes[0].count, minutes[0].max), ("0", 2, 3.0));
+        assert_eq!((minutes[1].tags["index"].as_str(), minutes[1].count, minutes[1].max), ("1", 1, 5.0));
+    }
+
+    #[test]
+    fn tiers_outlive_raw_events() {
+        let clock = Arc::new(ManualClock::new(time(0)));
+        let raw = InMemory::with_retention(Retention { max_age_ms: Some(MINUTE_MS), ..Retention::default() })
+            .with_clock(clock.clone());
+        let mut rollup = tiered(raw).with_clock(clock.clone());
+        rollup.add_events(readings(&[(0, 1.0), (30, 2.0)]));
+
+        clock.set(time(3 * 3_600));
+        rollup.add_events(readings(&[(3 * 3_600, 4.0)]));
+
+        assert_eq!(rollup.get_window_of_n(10).len(), 1);
+        assert_eq!(rollup.get_rollups(MINUTE_MS, MIN_DATETIME, MAX_DATETIME).len(), 1);
+        assert_eq!(rollup.get_rollups(HOUR_MS, MIN_DATETIME, MAX_DATETIME).len(), 2);
+        assert_eq!(rollup.resolution_for(time(3 * 3_600 - 60)), Some(MINUTE_MS));
+        assert_eq!(rollup.resolution_for(time(0)), Some(HOUR_MS));
+    }
+}
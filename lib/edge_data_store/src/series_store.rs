@@ -0,0 +1,451 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use edge_core::Value;
+use super::Change;
+use super::Feed;
+use super::InMemory;
+use super::Stats;
+use super::Store;
+use super::MAX_GRID_POINTS;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// A series is every event sharing a sensor id and the exact same tags
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SeriesKey {
+    pub sensor_id: String,
+    pub tags: BTreeMap<String, String>
+}
+
+// Selects series by sensor id and tag values, every condition given has to match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub sensor_id: Option<String>,
+    pub tags: BTreeMap<String, String>
+}
+
+// Matching series laid out on a shared grid of step_ms buckets. values[i][j] is the newest value
+// of series[i] in the bucket starting at timestamps[j].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aligned {
+    pub timestamps: Vec<DateTime<Utc>>,
+    pub series: Vec<SeriesKey>,
+    pub values: Vec<Vec<Option<Value>>>
+}
+
+pub type StoreFactory = Box<dyn Fn(&SeriesKey) -> Box<dyn Store + Send> + Send>;
+
+// Holds many series, each in its own store, with an inverted index from tag to series. As a
+// Store it takes events for any series and its queries merge every series by timestamp.
+pub struct SeriesStore {
+    series: BTreeMap<SeriesKey, Box<dyn Store + Send>>,
+    index: BTreeMap<(String, String), BTreeSet<SeriesKey>>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    factory: Option<StoreFactory>,
+    feed: Feed
+}
+
+// Merges per series iterators that are each already in order
+struct Merge<'a> {
+    iters: Vec<Peekable<Box<dyn Iterator<Item = Event> + 'a>>>,
+    ascending: bool
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl SeriesKey {
+    pub fn of(event: &Event) -> SeriesKey {
+        SeriesKey {
+            sensor_id: event.sensor_id.clone(),
+            tags: event.tags.clone()
+        }
+    }
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    pub fn sensor(mut self, sensor_id: &str) -> Filter {
+        self.sensor_id = Some(sensor_id.to_string());
+        self
+    }
+
+    pub fn tag(mut self, key: &str, value: &str) -> Filter {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn matches(&self, key: &SeriesKey) -> bool {
+        let sensor = match &self.sensor_id {
+            Some(sensor_id) => *sensor_id == key.sensor_id,
+            None => true
+        };
+
+        sensor && self.tags.iter().all(|(k, v)| key.tags.get(k) == Some(v))
+    }
+
+    pub fn matches_event(&self, event: &Event) -> bool {
+        let sensor = match &self.sensor_id {
+            Some(sensor_id) => *sensor_id == event.sensor_id,
+            None => true
+        };
+
+        sensor && self.tags.iter().all(|(k, v)| event.tags.get(k) == Some(v))
+    }
+}
+
+impl SeriesStore {
+    pub fn new() -> SeriesStore {
+        SeriesStore {
+            series: BTreeMap::new(),
+            index: BTreeMap::new(),
+            retention: Retention::default(),
+            clock: Arc::new(SystemClock),
+            factory: None,
+            feed: Feed::new()
+        }
+    }
+
+    // Retention of every new series when no factory is set
+    pub fn with_retention(mut self, retention: Retention) -> SeriesStore {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SeriesStore {
+        self.clock = clock;
+        self
+    }
+
+    // Creates the store for each new series, by default an InMemory store
+    pub fn with_factory(mut self, factory: StoreFactory) -> SeriesStore {
+        self.factory = Some(factory);
+        self
+    }
+
+    pub fn num_series(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn keys(&self) -> Vec<SeriesKey> {
+        self.series.keys().cloned().collect()
+    }
+
+    pub fn series(&self, key: &SeriesKey) -> Option<&(dyn Store + Send)> {
+        self.series.get(key).map(|store| store.as_ref())
+    }
+
+    // Series matching the filter, looked up through the tag index
+    pub fn select(&self, filter: &Filter) -> Vec<SeriesKey> {
+        let mut candidates: Option<BTreeSet<&SeriesKey>> = None;
+
+        for (k, v) in filter.tags.iter() {
+            let tagged: BTreeSet<&SeriesKey> = match self.index.get(&(k.clone(), v.clone())) {
+                Some(keys) => keys.iter().collect(),
+                None => return Vec::new()
+            };
+
+            candidates = Some(match candidates {
+                Some(keys) => keys.intersection(&tagged).cloned().collect(),
+                None => tagged
+            });
+        }
+
+        let keys: Vec<&SeriesKey> = match candidates {
+            Some(keys) => keys.into_iter().collect(),
+            None => self.series.keys().collect()
+        };
+
+        keys.into_iter()
+            .filter(|key| filter.matches(key))
+            .cloned()
+            .collect()
+    }
+
+    // The window of every matching series, each newest first
+    pub fn get_window_where(&self, filter: &Filter, win_len_ms: i64) -> Vec<(SeriesKey, Vec<Event>)> {
+        self.select(filter)
+            .into_iter()
+            .map(|key| {
+                let events = self.series[&key].get_window(win_len_ms);
+                (key, events)
+            })
+            .collect()
+    }
+
+    // Events in [start, end) of every matching series, each oldest first
+    pub fn get_range_where(&self, filter: &Filter, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<(SeriesKey, Vec<Event>)> {
+        self.select(filter)
+            .into_iter()
+            .map(|key| {
+                let events = self.series[&key].get_range(start, end);
+                (key, events)
+            })
+            .collect()
+    }
+
+    // Matching series over [start, end) on a grid of step_ms buckets starting at start, None when
+    // that is more than MAX_GRID_POINTS buckets
+    pub fn get_aligned(&self, filter: &Filter, start: DateTime<Utc>, end: DateTime<Utc>, step_ms: i64) -> Option<Aligned> {
+        let step = Duration::milliseconds(step_ms.max(1));
+        let mut timestamps = Vec::new();
+        let mut timestamp = start;
+
+        while timestamp < end {
+            if timestamps.len() == MAX_GRID_POINTS {
+                println!("Aligned range is more than {} buckets of {} ms", MAX_GRID_POINTS, step.num_milliseconds());
+                return None
+            }
+
+            timestamps.push(timestamp);
+            timestamp = match timestamp.checked_add_signed(step) {
+                Some(timestamp) => timestamp,
+                None => break
+            };
+        }
+
+        let mut aligned = Aligned {
+            timestamps,
+            series: Vec::new(),
+            values: Vec::new()
+        };
+
+        for (key, events) in self.get_range_where(filter, start, end) {
+            let mut values = vec![None; aligned.timestamps.len()];
+
+            for event in events {
+                let bucket = ((event.timestamp - start).num_milliseconds() / step.num_milliseconds()) as usize;
+                if let Some(value) = values.get_mut(bucket) {
+                    *value = Some(event.value);
+                }
+            }
+
+            aligned.series.push(key);
+            aligned.values.push(values);
+        }
+
+        Some(aligned)
+    }
+
+    // The last win_len_ms up to now, on a grid starting win_len_ms ago
+    pub fn get_aligned_window(&self, filter: &Filter, win_len_ms: i64, step_ms: i64) -> Option<Aligned> {
+        let end = self.clock.now();
+        self.get_aligned(filter, end - Duration::milliseconds(win_len_ms), end, step_ms)
+    }
+
+    fn create_store(&self, key: &SeriesKey) -> Box<dyn Store + Send> {
+        match &self.factory {
+            Some(factory) => factory(key),
+            None => Box::new(InMemory::with_retention(self.retention.clone()).with_clock(self.clock.clone()))
+        }
+    }
+
+    fn merge<'a>(&'a self, ascending: bool,
+                 iter: impl Fn(&'a (dyn Store + Send)) -> Box<dyn Iterator<Item = Event> + 'a>) -> Merge<'a> {
+        Merge {
+            iters: self.series.values().map(|store| iter(store.as_ref()).peekable()).collect(),
+            ascending
+        }
+    }
+}
+
+impl Default for SeriesStore {
+    fn default() -> SeriesStore {
+        SeriesStore::new()
+    }
+}
+
+impl Store for SeriesStore {
+    fn add_events(&mut self, events: Vec<Event>) {
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
+        let mut batches: BTreeMap<SeriesKey, Vec<Event>> = BTreeMap::new();
+
+        for event in events {
+            batches.entry(SeriesKey::of(&event)).or_default().push(event);
+        }
+
+        for (key, events) in batches {
+            if !self.series.contains_key(&key) {
+                let store = self.create_store(&key);
+
+                for (k, v) in key.tags.iter() {
+                    self.index.entry((k.clone(), v.clone())).or_default().insert(key.clone());
+                }
+
+                self.series.insert(key.clone(), store);
+            }
+
+            if let Some(store) = self.series.get_mut(&key) {
+                store.add_events(events);
+            }
+        }
+
+        self.feed.inserted(&inserted);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+
+        self.iter_before(now + Duration::nanoseconds(1))
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.merge(false, |store| Box::new(store.get_window_of_n(n).into_iter()))
+            .take(n as usize)
+            .collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.merge(true, move |store| store.iter_from(start)))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.merge(false, move |store| store.iter_before(end)))
+    }
+
+    // Only carries inserts, evictions are published by the feed of each series store
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    // Events are out of order when they are older than an earlier event of the same series
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+
+        for store in self.series.values() {
+            stats.merge(&store.stats());
+        }
+
+        stats
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        let mut next: Option<(usize, DateTime<Utc>)> = None;
+
+        for (i, iter) in self.iters.iter_mut().enumerate() {
+            let timestamp = match iter.peek() {
+                Some(event) => event.timestamp,
+                None => continue
+            };
+
+            let better = match next {
+                Some((_, best)) => if self.ascending { timestamp < best } else { timestamp > best },
+                None => true
+            };
+            if better {
+                next = Some((i, timestamp));
+            }
+        }
+
+        next.and_then(|(i, _)| self.iters[i].next())
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use super::super::MAX_DATETIME;
+    use super::super::MIN_DATETIME;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn reading(seconds: i64, sensor_id: &str, building: &str, value: i64) -> Event {
+        Event::new(time(seconds), sensor_id, Value::Int(value))
+            .with_tag("building", building)
+            .with_tag("kind", "temperature")
+    }
+
+    fn building_store(clock: Arc<ManualClock>) -> SeriesStore {
+        let mut store = SeriesStore::new().with_clock(clock);
+        store.add_events(vec![
+            reading(10, "temp_1", "3", 1),
+            reading(20, "temp_2", "3", 2),
+            reading(30, "temp_3", "4", 3),
+            reading(40, "temp_1", "3", 4),
+            reading(55, "temp_2", "3", 5),
+            Event::new(time(50), "door_1", Value::Bool(true)).with_tag("building", "3")
+        ]);
+        store
+    }
+
+    fn sensors(keys: &[SeriesKey]) -> Vec<&str> {
+        keys.iter().map(|key| key.sensor_id.as_str()).collect()
+    }
+
+    #[test]
+    fn selects_through_the_tag_index() {
+        let store = building_store(Arc::new(ManualClock::new(time(60))));
+
+        assert_eq!(store.num_series(), 4);
+        assert_eq!(sensors(&store.select(&Filter::new().tag("building", "3"))), vec!["door_1", "temp_1", "temp_2"]);
+        assert_eq!(sensors(&store.select(&Filter::new().tag("building", "3").tag("kind", "temperature"))),
+                   vec!["temp_1", "temp_2"]);
+        assert_eq!(sensors(&store.select(&Filter::new().sensor("temp_3"))), vec!["temp_3"]);
+        assert!(store.select(&Filter::new().tag("building", "5")).is_empty());
+        assert_eq!(store.select(&Filter::new()).len(), 4);
+    }
+
+    #[test]
+    fn window_of_matching_series() {
+        let store = building_store(Arc::new(ManualClock::new(time(60))));
+        let windows = store.get_window_where(&Filter::new().tag("kind", "temperature").tag("building", "3"), 25_000);
+
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].1.iter().map(|event| event.value.clone()).collect::<Vec<Value>>(), vec![Value::Int(4)]);
+        assert_eq!(windows[1].1.iter().map(|event| event.value.clone()).collect::<Vec<Value>>(), vec![Value::Int(5)]);
+    }
+
+    #[test]
+    fn aligns_series_on_a_grid() {
+        let store = building_store(Arc::new(ManualClock::new(time(60))));
+        let aligned = store.get_aligned(&Filter::new().tag("kind", "temperature"), time(0), time(60), 20_000).unwrap();
+
+        assert_eq!(aligned.timestamps, vec![time(0), time(20), time(40)]);
+        assert_eq!(sensors(&aligned.series), vec!["temp_1", "temp_2", "temp_3"]);
+        assert_eq!(aligned.values[0], vec![Some(Value::Int(1)), None, Some(Value::Int(4))]);
+        assert_eq!(aligned.values[1], vec![None, Some(Value::Int(2)), Some(Value::Int(5))]);
+        assert_eq!(aligned.values[2], vec![None, Some(Value::Int(3)), None]);
+
+        let window = store.get_aligned_window(&Filter::new().sensor("temp_2"), 10_000, 5_000).unwrap();
+        assert_eq!(window.values[0], vec![None, Some(Value::Int(5))]);
+        assert!(store.get_aligned(&Filter::new(), time(0), MAX_DATETIME, 1).is_none());
+    }
+
+    #[test]
+    fn merges_series_as_one_store() {
+        let store = building_store(Arc::new(ManualClock::new(time(60))));
+        let seconds = |events: Vec<Event>| events.iter().map(|event| event.timestamp.timestamp()).collect::<Vec<i64>>();
+
+        assert_eq!(seconds(store.get_range(MIN_DATETIME, MAX_DATETIME)), vec![10, 20, 30, 40, 50, 55]);
+        assert_eq!(seconds(store.get_window_of_n(3)), vec![55, 50, 40]);
+        assert_eq!(seconds(store.get_window(15_000)), vec![55, 50]);
+        assert_eq!(seconds(store.get_before(time(40), 2)), vec![20, 30]);
+    }
+}
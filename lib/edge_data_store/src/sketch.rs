@@ -0,0 +1,463 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
+use super::Change;
+use super::Filter;
+use super::SeriesKey;
+use super::feed::add_accepted;
+use super::Stats;
+use super::Store;
+
+// Values closer to zero than this are counted in the zero bucket
+const MIN_VALUE: f64 = 1e-9;
+const DEFAULT_RESOLUTION_MS: i64 = 60 * 1000;
+const DEFAULT_MAX_AGE_MS: i64 = 60 * 60 * 1000;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// DDSketch, a histogram with logarithmically sized buckets. Every quantile it reports is within
+// relative_accuracy of the true value, and sketches with the same accuracy merge exactly, so the
+// sketches of many buckets or sensors add up to the sketch of all their values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sketch {
+    relative_accuracy: f64,
+    gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64
+}
+
+// One bucket of a sketch, holding count values in [lower, upper)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64
+}
+
+// Wraps any store and keeps a sketch of the numeric values of each series for every bucket of
+// resolution_ms, so quantiles over a rolling window merge a few sketches instead of reading the raw
+// events. Only the values the wrapped store keeps are sketched. Sketches older than max_age_ms are
+// dropped, the wrapped store keeps its own retention.
+pub struct Sketched<S: Store> {
+    store: S,
+    relative_accuracy: f64,
+    resolution_ms: i64,
+    max_age_ms: i64,
+    buckets: BTreeMap<i64, BTreeMap<SeriesKey, Sketch>>,
+    clock: Arc<dyn Clock>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Sketch {
+    // relative_accuracy has to be in (0, 1), e.g. 0.01 for quantiles within 1%
+    pub fn new(relative_accuracy: f64) -> Option<Sketch> {
+        if !(relative_accuracy > 0.0 && relative_accuracy < 1.0) {
+            println!("Invalid sketch accuracy: {:?}", relative_accuracy);
+            return None
+        }
+
+        Some(Sketch {
+            relative_accuracy,
+            gamma: (1.0 + relative_accuracy) / (1.0 - relative_accuracy),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0
+        })
+    }
+
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        if self.is_empty() { None } else { Some(self.min) }
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        if self.is_empty() { None } else { Some(self.max) }
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.is_empty() { None } else { Some(self.sum / self.count as f64) }
+    }
+
+    // NaN and infinite values are ignored
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() { return }
+
+        if value > MIN_VALUE {
+            *self.positive.entry(self.index(value)).or_default() += 1;
+        } else if value < -MIN_VALUE {
+            *self.negative.entry(self.index(-value)).or_default() += 1;
+        } else {
+            self.zero += 1;
+        }
+
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    // Only sketches of the same accuracy can be merged, returns false and leaves self alone
+    // otherwise
+    pub fn merge(&mut self, other: &Sketch) -> bool {
+        if self.relative_accuracy != other.relative_accuracy {
+            println!("Cannot merge sketches of accuracy: {:?} and {:?}", self.relative_accuracy, other.relative_accuracy);
+            return false
+        }
+
+        for (index, count) in other.positive.iter() {
+            *self.positive.entry(*index).or_default() += count;
+        }
+        for (index, count) in other.negative.iter() {
+            *self.negative.entry(*index).or_default() += count;
+        }
+
+        self.zero += other.zero;
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        true
+    }
+
+    // The value at quantile q in [0, 1], e.g. 0.99 for p99
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.is_empty() || !(0.0..=1.0).contains(&q) { return None }
+
+        let rank = (q * (self.count - 1) as f64).floor() as u64;
+        let mut seen = 0;
+
+        // Most negative first, the largest magnitudes have the highest indexes
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank { return Some((-self.value(*index)).clamp(self.min, self.max)) }
+        }
+
+        seen += self.zero;
+        if seen > rank { return Some(0.0_f64.clamp(self.min, self.max)) }
+
+        for (index, count) in self.positive.iter() {
+            seen += count;
+            if seen > rank { return Some(self.value(*index).clamp(self.min, self.max)) }
+        }
+
+        Some(self.max)
+    }
+
+    // Every non empty bucket, lowest values first
+    pub fn bins(&self) -> Vec<Bin> {
+        let mut bins = Vec::new();
+
+        for (index, count) in self.negative.iter().rev() {
+            bins.push(Bin { lower: -self.gamma.powi(*index), upper: -self.gamma.powi(index - 1), count: *count });
+        }
+
+        if self.zero > 0 {
+            bins.push(Bin { lower: -MIN_VALUE, upper: MIN_VALUE, count: self.zero });
+        }
+
+        for (index, count) in self.positive.iter() {
+            bins.push(Bin { lower: self.gamma.powi(index - 1), upper: self.gamma.powi(*index), count: *count });
+        }
+
+        bins
+    }
+
+    fn index(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    // The point of a bucket that is within relative_accuracy of everything in it
+    fn value(&self, index: i32) -> f64 {
+        2.0 * self.gamma.powi(index) / (self.gamma + 1.0)
+    }
+}
+
+impl<S: Store> Sketched<S> {
+    pub fn new(store: S, relative_accuracy: f64) -> Option<Sketched<S>> {
+        Sketch::new(relative_accuracy)?;
+
+        Some(Sketched {
+            store,
+            relative_accuracy,
+            resolution_ms: DEFAULT_RESOLUTION_MS,
+            max_age_ms: DEFAULT_MAX_AGE_MS,
+            buckets: BTreeMap::new(),
+            clock: Arc::new(SystemClock)
+        })
+    }
+
+    // Windows are rounded out to whole buckets, only takes effect before any event is added
+    pub fn with_resolution_ms(mut self, resolution_ms: i64) -> Sketched<S> {
+        if resolution_ms > 0 && self.buckets.is_empty() {
+            self.resolution_ms = resolution_ms;
+        }
+
+        self
+    }
+
+    pub fn with_max_age_ms(mut self, max_age_ms: i64) -> Sketched<S> {
+        self.max_age_ms = max_age_ms;
+        self
+    }
+
+    // Only ages out the sketches, the wrapped store keeps its own clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Sketched<S> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    // Every matching series over the last win_len_ms merged into one sketch
+    pub fn get_sketch(&self, filter: &Filter, win_len_ms: i64) -> Option<Sketch> {
+        let now = self.clock.now();
+        self.get_sketch_range(filter, now - Duration::milliseconds(win_len_ms), now + Duration::nanoseconds(1))
+    }
+
+    // Every matching series merged over the buckets that overlap [start, end)
+    pub fn get_sketch_range(&self, filter: &Filter, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<Sketch> {
+        let mut merged = Sketch::new(self.relative_accuracy)?;
+
+        for (_, sketch) in self.series_sketches(filter, start, end) {
+            merged.merge(&sketch);
+        }
+
+        if merged.is_empty() { None } else { Some(merged) }
+    }
+
+    // One sketch per matching series over the last win_len_ms
+    pub fn get_series_sketches(&self, filter: &Filter, win_len_ms: i64) -> Vec<(SeriesKey, Sketch)> {
+        let now = self.clock.now();
+        self.series_sketches(filter, now - Duration::milliseconds(win_len_ms), now + Duration::nanoseconds(1))
+            .into_iter()
+            .collect()
+    }
+
+    pub fn get_quantile(&self, filter: &Filter, win_len_ms: i64, q: f64) -> Option<f64> {
+        self.get_sketch(filter, win_len_ms).and_then(|sketch| sketch.quantile(q))
+    }
+
+    fn series_sketches(&self, filter: &Filter, start: DateTime<Utc>, end: DateTime<Utc>) -> BTreeMap<SeriesKey, Sketch> {
+        let mut merged: BTreeMap<SeriesKey, Sketch> = BTreeMap::new();
+        if start >= end { return merged }
+
+        let first = bucket_id(start, self.resolution_ms);
+        let last = bucket_id(end - Duration::nanoseconds(1), self.resolution_ms);
+
+        for (_, series) in self.buckets.range(first..=last) {
+            for (key, sketch) in series.iter().filter(|(key, _)| filter.matches(key)) {
+                match merged.get_mut(key) {
+                    Some(total) => { total.merge(sketch); },
+                    None => { merged.insert(key.clone(), sketch.clone()); }
+                }
+            }
+        }
+
+        merged
+    }
+
+    fn update(&mut self, events: &[Event]) {
+        for event in events {
+            let value = match event.value.as_f64() {
+                Some(value) => value,
+                None => continue
+            };
+
+            let series = self.buckets.entry(bucket_id(event.timestamp, self.resolution_ms)).or_default();
+            let key = SeriesKey::of(event);
+
+            if !series.contains_key(&key) {
+                if let Some(sketch) = Sketch::new(self.relative_accuracy) {
+                    series.insert(key.clone(), sketch);
+                }
+            }
+
+            if let Some(sketch) = series.get_mut(&key) {
+                sketch.add(value);
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let now = self.clock.now();
+        let resolution_ms = self.resolution_ms;
+        let max_age_ms = self.max_age_ms;
+
+        self.buckets.retain(|id, _| {
+            let end = id.saturating_add(1).saturating_mul(resolution_ms);
+            now.timestamp_millis().saturating_sub(end) <= max_age_ms
+        });
+    }
+}
+
+impl<S: Store> Store for Sketched<S> {
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        let accepted = add_accepted(&mut self.store, events);
+        self.update(&accepted);
+        self.prune();
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        self.store.get_window(win_len_ms)
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.store.get_window_of_n(n)
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.store.iter_from(start)
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        self.store.iter_before(end)
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.store.subscribe(filter, capacity)
+    }
+
+    fn stats(&self) -> Stats {
+        self.store.stats()
+    }
+}
+
+fn bucket_id(timestamp: DateTime<Utc>, resolution_ms: i64) -> i64 {
+    timestamp.timestamp_millis().div_euclid(resolution_ms)
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use edge_core::Value;
+    use super::super::InMemory;
+    use super::super::MmapStore;
+    use super::*;
+
+    const MINUTE_MS: i64 = 60 * 1000;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn within(estimate: f64, actual: f64, accuracy: f64) -> bool {
+        (estimate - actual).abs() <= accuracy * actual.abs() + 1e-9
+    }
+
+    #[test]
+    fn quantiles_are_within_accuracy() {
+        let mut sketch = Sketch::new(0.01).unwrap();
+        for value in 1..=1000 {
+            sketch.add(value as f64);
+        }
+        sketch.add(f64::NAN);
+
+        assert_eq!(sketch.count(), 1000);
+        assert!(within(sketch.quantile(0.5).unwrap(), 500.0, 0.01));
+        assert!(within(sketch.quantile(0.99).unwrap(), 990.0, 0.01));
+        assert_eq!(sketch.quantile(0.0), Some(1.0));
+        assert_eq!(sketch.quantile(1.0), Some(1000.0));
+        assert_eq!(sketch.quantile(1.5), None);
+        assert_eq!(sketch.bins().iter().map(|bin| bin.count).sum::<u64>(), 1000);
+        assert!(Sketch::new(1.0).is_none());
+    }
+
+    #[test]
+    fn merges_signed_values() {
+        let mut low = Sketch::new(0.02).unwrap();
+        let mut high = Sketch::new(0.02).unwrap();
+        for value in -50..0 { low.add(value as f64) }
+        for value in 0..50 { high.add(value as f64) }
+
+        assert!(low.merge(&high));
+        assert!(!low.merge(&Sketch::new(0.01).unwrap()));
+        assert_eq!((low.count(), low.min(), low.max()), (100, Some(-50.0), Some(49.0)));
+        assert!(within(low.quantile(0.1).unwrap(), -41.0, 0.02));
+        assert!(within(low.quantile(0.5).unwrap(), -1.0, 0.02));
+        assert_eq!(low.quantile(0.51), Some(0.0));
+        assert!(within(low.quantile(0.9).unwrap(), 39.0, 0.02));
+
+        let bins = low.bins();
+        assert!(bins.windows(2).all(|pair| pair[0].upper <= pair[1].lower));
+    }
+
+    #[test]
+    fn windows_span_buckets_and_sensors() {
+        let clock = Arc::new(ManualClock::new(time(3_600)));
+        let mut store = Sketched::new(InMemory::new(), 0.01).unwrap()
+            .with_max_age_ms(10 * MINUTE_MS)
+            .with_clock(clock.clone());
+
+        for s in 0..600 {
+            store.add_events(vec![
+                Event::new(time(3_000 + s), "temp_sensor_1", Value::Float(s as f64)),
+                Event::new(time(3_000 + s), "temp_sensor_2", Value::Int(1_000 + s))
+            ]);
+        }
+        store.add_events(vec![Event::new(time(3_599), "temp_sensor_1", Value::Text(String::from("off")))]);
+
+        let sensor_1 = Filter::new().sensor("temp_sensor_1");
+        assert_eq!(store.get_sketch(&sensor_1, 5 * MINUTE_MS).unwrap().count(), 300);
+        assert!(within(store.get_quantile(&sensor_1, 10 * MINUTE_MS, 0.5).unwrap(), 299.0, 0.01));
+        assert_eq!(store.get_sketch(&Filter::new(), 10 * MINUTE_MS).unwrap().count(), 1_200);
+        assert_eq!(store.get_series_sketches(&Filter::new(), MINUTE_MS).len(), 2);
+        assert_eq!(store.store().len(), 1_201);
+
+        clock.set(time(3_600 + 30 * 60));
+        store.add_events(vec![Event::new(clock.now(), "temp_sensor_1", Value::Float(1.0))]);
+        assert_eq!(store.get_sketch_range(&sensor_1, time(0), time(3_600)), None);
+        assert_eq!(store.get_quantile(&sensor_1, MINUTE_MS, 0.99), Some(1.0));
+    }
+
+    #[test]
+    fn sketches_only_kept_values() {
+        let path = std::env::temp_dir().join(format!("edge_sketch_{}.mmap", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let ring = MmapStore::open(&path.to_string_lossy(), 10).unwrap();
+        let mut store = Sketched::new(ring, 0.01).unwrap().with_clock(Arc::new(ManualClock::new(time(3_600))));
+
+        // The ring drops events older than the newest it holds
+        store.add_events(vec![Event::new(time(3_500), "temp_sensor_1", Value::Float(1.0))]);
+        store.add_events(vec![Event::new(time(3_400), "temp_sensor_1", Value::Float(100.0))]);
+
+        let sketch = store.get_sketch(&Filter::new(), 10 * MINUTE_MS).unwrap();
+        assert_eq!((sketch.count(), sketch.max()), (1, Some(1.0)));
+        let _ = std::fs::remove_file(&path);
+    }
+}
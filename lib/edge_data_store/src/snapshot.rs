@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use chrono::prelude::*;
+
+use edge_core::Event;
+use edge_core::Retention;
+use super::Store;
+use super::MIN_DATETIME;
+
+pub const FORMAT: &str = "rusty_edge.snapshot";
+pub const VERSION: u32 = 1;
+
+const RESTORE_BATCH: usize = 1024;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// A snapshot is a json lines file, this manifest on the first line and then one event per line
+// oldest first, so it can be read with any json tooling and streamed back in without holding the
+// whole history in memory.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub count: usize,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub series: Vec<SeriesInfo>
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SeriesInfo {
+    pub sensor_id: String,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub count: usize,
+    pub oldest: DateTime<Utc>,
+    pub newest: DateTime<Utc>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+// Writes every event in store to path. The file is written next to path and renamed over it once
+// complete, an interrupted save never leaves a partial snapshot behind.
+pub fn save(store: &dyn Store, path: &str, retention: &Retention,
+            metadata: BTreeMap<String, String>) -> io::Result<Manifest> {
+    println!("Saving snapshot: {}", path);
+
+    let mut series: BTreeMap<(String, BTreeMap<String, String>), SeriesInfo> = BTreeMap::new();
+    let mut count = 0;
+
+    for event in store.iter_from(MIN_DATETIME) {
+        count += 1;
+
+        let info = series.entry((event.sensor_id.clone(), event.tags.clone()))
+            .or_insert_with(|| SeriesInfo {
+                sensor_id: event.sensor_id.clone(),
+                tags: event.tags.clone(),
+                count: 0,
+                oldest: event.timestamp,
+                newest: event.timestamp
+            });
+        info.count += 1;
+        info.newest = event.timestamp;
+    }
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        created: Utc::now(),
+        count,
+        retention: retention.clone(),
+        metadata,
+        series: series.into_values().collect()
+    };
+
+    let partial = format!("{}.partial", path);
+    {
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        write_line(&mut writer, &manifest)?;
+
+        let mut written = 0;
+        for event in store.iter_from(MIN_DATETIME).take(count) {
+            write_line(&mut writer, &event)?;
+            written += 1;
+        }
+
+        if written != count {
+            return Err(invalid_data(format!("Store changed while saving, wrote {} of {} events", written, count)))
+        }
+
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    fs::rename(&partial, path)?;
+
+    Ok(manifest)
+}
+
+pub fn read_manifest(path: &str) -> io::Result<Manifest> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let first = lines.next().unwrap_or_else(|| Err(invalid_data(String::from("Empty snapshot"))))?;
+    let manifest: Manifest = serde_json::from_str(&first)
+        .map_err(|e| invalid_data(format!("Bad snapshot manifest: {:?}", e)))?;
+
+    if manifest.format != FORMAT {
+        return Err(invalid_data(format!("Not a snapshot: {:?}", manifest.format)))
+    }
+
+    if manifest.version > VERSION {
+        return Err(invalid_data(format!("Snapshot version {} is newer than {}", manifest.version, VERSION)))
+    }
+
+    Ok(manifest)
+}
+
+// Adds every event of the snapshot at path to store. The file is checked in full first, so a
+// damaged snapshot is rejected before anything is added.
+pub fn restore(store: &mut dyn Store, path: &str) -> io::Result<Manifest> {
+    println!("Restoring snapshot: {}", path);
+
+    let manifest = read_manifest(path)?;
+    let count = events(path)?.try_fold(0, |count, event| event.map(|_| count + 1))?;
+
+    if count != manifest.count {
+        return Err(invalid_data(format!("Snapshot holds {} of {} events", count, manifest.count)))
+    }
+
+    let mut batch = Vec::with_capacity(RESTORE_BATCH);
+    for event in events(path)? {
+        batch.push(event?);
+
+        if batch.len() == RESTORE_BATCH {
+            store.add_events(std::mem::take(&mut batch));
+        }
+    }
+    store.add_events(batch);
+
+    Ok(manifest)
+}
+
+// The events of a snapshot, oldest first
+pub fn events(path: &str) -> io::Result<impl Iterator<Item = io::Result<Event>>> {
+    let lines = BufReader::new(File::open(Path::new(path))?).lines().skip(1);
+
+    Ok(lines.map(|line| {
+        let line = line?;
+        serde_json::from_str::<Event>(&line).map_err(|e| invalid_data(format!("Bad snapshot event: {:?}", e)))
+    }))
+}
+
+fn write_line<T: serde::Serialize>(writer: &mut impl Write, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, value)?;
+    writer.write_all(b"\n")
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use edge_core::Quality;
+    use edge_core::Value;
+    use super::super::CompressedStore;
+    use super::super::InMemory;
+    use super::super::SeriesStore;
+    use super::super::MAX_DATETIME;
+    use super::*;
+
+    fn test_path(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("edge_snapshot_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("history.snapshot").to_string_lossy().to_string()
+    }
+
+    fn history() -> Vec<Event> {
+        (0..2_500)
+            .map(|s| {
+                let timestamp = Utc.timestamp_opt(1_000_000 + s, 0).unwrap();
+                match s % 3 {
+                    0 => Event::new(timestamp, "temp_sensor_1", Value::Float(s as f64 / 10.0)),
+                    1 => Event::new(timestamp, "temp_sensor_1", Value::Int(s)).with_tag("index", "1"),
+                    _ => Event::new(timestamp, "door_1", Value::Text(String::from("open"))).with_quality(Quality::Bad)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restores_into_another_backend() {
+        let path = test_path("backend");
+        let retention = Retention { max_events: Some(10_000), ..Retention::default() };
+        let mut metadata = BTreeMap::new();
+        metadata.insert(String::from("device"), String::from("pi-17"));
+
+        let mut source = SeriesStore::new();
+        source.add_events(history());
+        let saved = save(&source, &path, &retention, metadata).unwrap();
+
+        assert_eq!(saved.count, 2_500);
+        assert_eq!(saved.series.len(), 3);
+        assert_eq!(saved.series[0].sensor_id, "door_1");
+        assert_eq!(saved.series[0].count, 833);
+
+        let manifest = read_manifest(&path).unwrap();
+        assert_eq!(manifest, saved);
+
+        let mut target = CompressedStore::with_retention(manifest.retention.clone());
+        restore(&mut target, &path).unwrap();
+        assert_eq!(target.get_range(MIN_DATETIME, MAX_DATETIME), history());
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let path = test_path("damaged");
+        let mut source = InMemory::new();
+        source.add_events(history());
+        save(&source, &path, &Retention::default(), BTreeMap::new()).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 200]).unwrap();
+        let mut target = InMemory::new();
+        assert!(restore(&mut target, &path).is_err());
+        assert!(target.is_empty());
+
+        fs::write(&path, contents.replacen("\"version\":1", "\"version\":2", 1)).unwrap();
+        assert_eq!(read_manifest(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::Row;
+use rusqlite::types::Value as SqlValue;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::Quality;
+use edge_core::Retention;
+use edge_core::SystemClock;
+use edge_core::Value;
+use super::Change;
+use super::Evictions;
+use super::Feed;
+use super::Filter;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+
+const PAGE_SIZE: i64 = 256;
+const COLUMNS: &str = "id, sensor_id, timestamp_ns, value_type, value, tags, quality";
+
+// Values keep their sqlite affinity so they can be aggregated directly, value_type keeps bools
+// apart from ints. Tags are a json object and quality is the OPC quality byte.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sensor_id TEXT NOT NULL,
+        timestamp_ns INTEGER NOT NULL,
+        value_type TEXT NOT NULL,
+        value,
+        tags TEXT NOT NULL,
+        quality INTEGER NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_sensor_timestamp ON events (sensor_id, timestamp_ns, id);
+    CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp_ns, id);
+";
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Every sensor can share one database file, each store reads back the events of its own sensor.
+// A store opened for every sensor reads them all.
+pub struct SqliteStore {
+    conn: Connection,
+    sensor_id: Option<String>,
+    retention: Retention,
+    clock: Arc<dyn Clock>,
+    evictions: Evictions,
+    tracker: Tracker,
+    feed: Feed
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl SqliteStore {
+    pub fn open(path: &str, sensor_id: &str) -> Option<SqliteStore> {
+        println!("Opening sqlite store: {}", path);
+
+        let conn = match Connection::open(path) {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Error opening sqlite database: {:?}", e);
+                return None
+            }
+        };
+
+        if let Err(e) = conn.execute_batch(SCHEMA) {
+            println!("Error creating sqlite schema: {:?}", e);
+            return None
+        }
+
+        Some(SqliteStore {
+            conn,
+            sensor_id: Some(sensor_id.to_string()),
+            retention: Retention::default(),
+            clock: Arc::new(SystemClock),
+            evictions: Evictions::default(),
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        })
+    }
+
+    // For reading a database another process may be writing to, the schema must already exist and
+    // adding events fails
+    pub fn open_read_only(path: &str, sensor_id: &str) -> Option<SqliteStore> {
+        SqliteStore::read_only(path, Some(sensor_id.to_string()))
+    }
+
+    // Reads the events of every sensor in the database, oldest first by time and then by when they
+    // were added
+    pub fn open_all_read_only(path: &str) -> Option<SqliteStore> {
+        SqliteStore::read_only(path, None)
+    }
+
+    fn read_only(path: &str, sensor_id: Option<String>) -> Option<SqliteStore> {
+        println!("Opening sqlite store read only: {}", path);
+
+        let conn = match Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY) {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("Error opening sqlite database: {:?}", e);
+                return None
+            }
+        };
+
+        Some(SqliteStore {
+            conn,
+            sensor_id,
+            retention: Retention::default(),
+            clock: Arc::new(SystemClock),
+            evictions: Evictions::default(),
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        })
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> SqliteStore {
+        self.retention = retention;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> SqliteStore {
+        self.clock = clock;
+        self
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    pub fn len(&self) -> usize {
+        let sql = format!("SELECT COUNT(*) FROM events WHERE {}", self.sensor_clause());
+        self.conn.query_row(&sql, params![self.sensor_id], |row| row.get::<_, i64>(0))
+            .map(|count| count as usize)
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Binds to ?1, sensor_id is NULL when reading every sensor
+    fn sensor_clause(&self) -> &'static str {
+        match self.sensor_id {
+            Some(_) => "sensor_id = ?1",
+            None => "?1 IS NULL"
+        }
+    }
+
+    fn insert(&mut self, events: &[Event]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO events (sensor_id, timestamp_ns, value_type, value, tags, quality, size)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+
+            for event in events.iter() {
+                let (value_type, value) = to_sql_value(&event.value);
+                let tags = serde_json::to_string(&event.tags).unwrap_or_else(|_| String::from("{}"));
+
+                stmt.execute(params![event.sensor_id, timestamp_ns(event.timestamp), value_type, value,
+                                     tags, event.quality.code() as i64, event.size_bytes() as i64])?;
+            }
+        }
+
+        tx.commit()
+    }
+
+    fn prune(&mut self) -> rusqlite::Result<()> {
+        if let Some(max_events) = self.retention.max_events {
+            let removed = self.conn.execute(
+                "DELETE FROM events WHERE id IN (
+                     SELECT id FROM events WHERE sensor_id = ?1
+                     ORDER BY timestamp_ns DESC, id DESC LIMIT -1 OFFSET ?2)",
+                params![self.sensor_id, max_events as i64])?;
+            self.evictions.count += removed as u64;
+        }
+
+        if let Some(max_age_ms) = self.retention.max_age_ms {
+            let oldest = self.clock.now() - chrono::Duration::milliseconds(max_age_ms);
+            let removed = self.conn.execute(
+                "DELETE FROM events WHERE sensor_id = ?1 AND timestamp_ns < ?2",
+                params![self.sensor_id, timestamp_ns(oldest)])?;
+            self.evictions.age += removed as u64;
+        }
+
+        if let Some(max_bytes) = self.retention.max_bytes {
+            // Walk back from the newest event until the budget runs out, everything older goes
+            let cutoff = {
+                let mut stmt = self.conn.prepare_cached(
+                    "SELECT timestamp_ns, id, size FROM events WHERE sensor_id = ?1
+                     ORDER BY timestamp_ns DESC, id DESC")?;
+                let mut rows = stmt.query(params![self.sensor_id])?;
+                let mut total = 0;
+                let mut cutoff = None;
+
+                while let Some(row) = rows.next()? {
+                    total += row.get::<_, i64>(2)? as usize;
+                    if total > max_bytes {
+                        cutoff = Some((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?));
+                        break
+                    }
+                }
+
+                cutoff
+            };
+
+            if let Some((timestamp, id)) = cutoff {
+                let removed = self.conn.execute(
+                    "DELETE FROM events WHERE sensor_id = ?1
+                     AND (timestamp_ns < ?2 OR (timestamp_ns = ?2 AND id <= ?3))",
+                    params![self.sensor_id, timestamp, id])?;
+                self.evictions.bytes += removed as u64;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Vec<(i64, i64, Event)> {
+        let result = self.conn.prepare_cached(sql).and_then(|mut stmt| {
+            let rows = stmt.query_map(params, from_row)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+
+        match result {
+            Ok(rows) => rows,
+            Err(e) => {
+                println!("Error reading from sqlite: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn pages(&self, ascending: bool, last: (i64, i64)) -> Pages<'_> {
+        Pages {
+            store: self,
+            ascending,
+            last,
+            page: Vec::new().into_iter(),
+            done: false
+        }
+    }
+}
+
+// Keyset pagination over (timestamp, id), a page is fetched only when the previous one runs out
+struct Pages<'a> {
+    store: &'a SqliteStore,
+    ascending: bool,
+    last: (i64, i64),
+    page: std::vec::IntoIter<(i64, i64, Event)>,
+    done: bool
+}
+
+impl<'a> Iterator for Pages<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            if let Some((timestamp, id, event)) = self.page.next() {
+                self.last = (timestamp, id);
+                return Some(event)
+            }
+
+            if self.done { return None }
+
+            let (timestamp, id) = self.last;
+            let sql = if self.ascending {
+                format!("SELECT {} FROM events WHERE {}
+                         AND (timestamp_ns > ?2 OR (timestamp_ns = ?2 AND id > ?3))
+                         ORDER BY timestamp_ns, id LIMIT ?4", COLUMNS, self.store.sensor_clause())
+            } else {
+                format!("SELECT {} FROM events WHERE {}
+                         AND (timestamp_ns < ?2 OR (timestamp_ns = ?2 AND id < ?3))
+                         ORDER BY timestamp_ns DESC, id DESC LIMIT ?4", COLUMNS, self.store.sensor_clause())
+            };
+
+            let rows = self.store.query(&sql, params![self.store.sensor_id, timestamp, id, PAGE_SIZE]);
+
+            self.done = (rows.len() as i64) < PAGE_SIZE;
+            self.page = rows.into_iter();
+        }
+    }
+}
+
+impl Store for SqliteStore {
+    // Events of other sensors would never be read back or pruned, so they are not written
+    fn add_events(&mut self, events: Vec<Event>) {
+        let (events, others): (Vec<Event>, Vec<Event>) = events.into_iter()
+            .partition(|event| self.sensor_id.as_ref().is_none_or(|sensor_id| *sensor_id == event.sensor_id));
+        if !others.is_empty() {
+            println!("Dropping {} events not from sensor {} in sqlite store", others.len(),
+                     self.sensor_id.as_deref().unwrap_or_default());
+        }
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let evictions = self.evictions;
+
+        match self.insert(&events) {
+            Ok(()) => self.feed.inserted(&events),
+            Err(e) => println!("Error writing to sqlite: {:?}", e)
+        }
+
+        if let Err(e) = self.prune() {
+            println!("Error applying sqlite retention: {:?}", e);
+        }
+        self.feed.evicted(evictions, self.evictions);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+        let start = now - chrono::Duration::milliseconds(win_len_ms);
+        let sql = format!("SELECT {} FROM events WHERE {}
+                           AND timestamp_ns >= ?2 AND timestamp_ns <= ?3
+                           ORDER BY timestamp_ns DESC, id DESC", COLUMNS, self.sensor_clause());
+
+        self.query(&sql, params![self.sensor_id, timestamp_ns(start), timestamp_ns(now)])
+            .into_iter()
+            .map(|(_, _, event)| event)
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        let sql = format!("SELECT {} FROM events WHERE {}
+                           ORDER BY timestamp_ns DESC, id DESC LIMIT ?2", COLUMNS, self.sensor_clause());
+
+        self.query(&sql, params![self.sensor_id, n as i64])
+            .into_iter()
+            .map(|(_, _, event)| event)
+            .collect()
+    }
+
+    // Pages resume strictly after the last row seen, so the starting key is placed just outside
+    // the bound: one nanosecond before start, or at end ahead of every id
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.pages(true, (timestamp_ns(start).saturating_sub(1), i64::MAX)))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.pages(false, (timestamp_ns(end), i64::MIN)))
+    }
+
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    // Read in one aggregate query over the sensor's rows
+    fn stats(&self) -> Stats {
+        let sql = format!("SELECT COUNT(*), COALESCE(SUM(size), 0), MIN(timestamp_ns), MAX(timestamp_ns)
+                           FROM events WHERE {}", self.sensor_clause());
+        let result = self.conn.query_row(
+            &sql,
+            params![self.sensor_id],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?,
+                      row.get::<_, Option<i64>>(2)?, row.get::<_, Option<i64>>(3)?)));
+        let stats = self.tracker.stats(self.clock.now());
+
+        match result {
+            Ok((count, bytes, oldest, newest)) => Stats {
+                count: count as usize,
+                bytes: bytes as usize,
+                oldest: oldest.map(|timestamp| Utc.timestamp_nanos(timestamp)),
+                newest: newest.map(|timestamp| Utc.timestamp_nanos(timestamp)),
+                evictions: self.evictions,
+                ..stats
+            },
+            Err(e) => {
+                println!("Error reading sqlite stats: {:?}", e);
+                Stats { evictions: self.evictions, ..stats }
+            }
+        }
+    }
+}
+
+
+// Conversions
+// -------------------------------------------------------------------------------------------------
+fn timestamp_ns(timestamp: DateTime<Utc>) -> i64 {
+    timestamp.timestamp_nanos_opt().unwrap_or(if timestamp.timestamp() < 0 { i64::MIN } else { i64::MAX })
+}
+
+fn to_sql_value(value: &Value) -> (&'static str, SqlValue) {
+    match value {
+        Value::Int(value) => ("int", SqlValue::Integer(*value)),
+        Value::Float(value) => ("float", SqlValue::Real(*value)),
+        Value::Bool(value) => ("bool", SqlValue::Integer(*value as i64)),
+        Value::Text(value) => ("text", SqlValue::Text(value.clone()))
+    }
+}
+
+fn from_sql_value(value_type: &str, value: SqlValue) -> Value {
+    match (value_type, value) {
+        ("bool", SqlValue::Integer(value)) => Value::Bool(value != 0),
+        (_, SqlValue::Integer(value)) => Value::Int(value),
+        (_, SqlValue::Real(value)) => Value::Float(value),
+        (_, SqlValue::Text(value)) => Value::Text(value),
+        (_, _) => Value::Text(String::new())
+    }
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<(i64, i64, Event)> {
+    let id: i64 = row.get(0)?;
+    let timestamp: i64 = row.get(2)?;
+    let value_type: String = row.get(3)?;
+    let tags: String = row.get(5)?;
+    let quality: i64 = row.get(6)?;
+
+    let event = Event {
+        timestamp: Utc.timestamp_nanos(timestamp),
+        sensor_id: row.get(1)?,
+        value: from_sql_value(&value_type, row.get(4)?),
+        tags: serde_json::from_str::<BTreeMap<String, String>>(&tags).unwrap_or_default(),
+        quality: Quality::from_code(quality as u8),
+        msg_id: None
+    };
+
+    Ok((timestamp, id, event))
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+    use edge_core::ManualClock;
+    use super::super::Cursor;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn at_seconds(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter()
+            .map(|s| Event::new(time(*s), "temp_sensor_1", Value::Int(*s)))
+            .collect()
+    }
+
+    fn values(events: &[Event]) -> Vec<Value> {
+        events.iter().map(|event| event.value.clone()).collect()
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let mut store = SqliteStore::open(":memory:", "temp_sensor_1").unwrap();
+        let event = Event::new(time(10), "temp_sensor_1", Value::Bool(true))
+            .with_tag("building", "3")
+            .with_quality(Quality::Uncertain);
+        store.add_events(vec![event.clone(),
+                              Event::new(time(20), "temp_sensor_1", Value::Float(1.5)),
+                              Event::new(time(30), "temp_sensor_1", Value::Text(String::from("on")))]);
+
+        let window = store.get_window_of_n(10);
+        assert_eq!(window.len(), 3);
+        assert_eq!(window[2], event);
+        assert_eq!(window[1].value, Value::Float(1.5));
+        assert_eq!(window[0].value, Value::Text(String::from("on")));
+    }
+
+    #[test]
+    fn range_queries_span_pages() {
+        let mut store = SqliteStore::open(":memory:", "temp_sensor_1").unwrap();
+        store.add_events((0..600).map(|s| Event::new(time(s), "temp_sensor_1", Value::Int(s))).collect());
+        store.add_events(vec![Event::new(time(100), "temp_sensor_2", Value::Int(-1))]);
+
+        let range = store.get_range(time(100), time(500));
+        assert_eq!(range.len(), 400);
+        assert_eq!(range[0].value, Value::Int(100));
+        assert_eq!(range[399].value, Value::Int(499));
+
+        assert_eq!(values(&store.get_before(time(500), 2)), vec![Value::Int(498), Value::Int(499)]);
+        assert_eq!(values(&store.get_after(time(100), 1)), vec![Value::Int(101)]);
+
+        let mut cursor = Cursor::new();
+        let mut total = 0;
+
+        loop {
+            let events = store.read(&mut cursor, 250);
+            if events.is_empty() { break }
+            total += events.len();
+        }
+
+        assert_eq!(total, 600);
+    }
+
+    #[test]
+    fn drops_other_sensors() {
+        let mut store = SqliteStore::open(":memory:", "temp_sensor_1").unwrap();
+        store.add_events(vec![Event::new(time(10), "temp_sensor_1", Value::Int(1)),
+                              Event::new(time(20), "temp_sensor_2", Value::Int(2))]);
+
+        let rows: i64 = store.connection()
+            .query_row("SELECT COUNT(*) FROM events", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!((store.len(), rows), (1, 1));
+        assert_eq!(store.stats().count, 1);
+    }
+
+    #[test]
+    fn retention_prunes_oldest() {
+        let clock = Arc::new(ManualClock::new(time(100)));
+        let retention = Retention { max_events: Some(5), max_age_ms: Some(60_000), ..Retention::default() };
+        let mut store = SqliteStore::open(":memory:", "temp_sensor_1").unwrap()
+            .with_retention(retention)
+            .with_clock(clock.clone());
+
+        store.add_events(at_seconds(&[10, 50, 60, 70, 80, 90, 100]));
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(100), Value::Int(90), Value::Int(80),
+                                                            Value::Int(70), Value::Int(60)]);
+        assert_eq!(store.evictions().count, 2);
+
+        clock.set(time(135));
+        store.add_events(at_seconds(&[135]));
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.evictions().count, 3);
+        assert_eq!(store.evictions().age, 1);
+        assert_eq!(values(&store.get_window(10_000)), vec![Value::Int(135)]);
+    }
+
+    #[test]
+    fn retention_by_bytes() {
+        let events = at_seconds(&[10, 20, 30]);
+        let retention = Retention { max_bytes: Some(2 * events[0].size_bytes()), ..Retention::default() };
+        let mut store = SqliteStore::open(":memory:", "temp_sensor_1").unwrap().with_retention(retention);
+        store.add_events(events);
+
+        assert_eq!(values(&store.get_window_of_n(10)), vec![Value::Int(30), Value::Int(20)]);
+        assert_eq!(store.evictions().bytes, 1);
+    }
+
+    #[test]
+    fn reopen_file() {
+        let path = std::env::temp_dir().join(format!("edge_sqlite_store_{}.db", process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = fs::remove_file(&path);
+
+        SqliteStore::open(&path, "temp_sensor_1").unwrap().add_events(at_seconds(&[10, 20]));
+        let store = SqliteStore::open(&path, "temp_sensor_1").unwrap();
+        assert_eq!(store.len(), 2);
+
+        let count: i64 = store.connection()
+            .query_row("SELECT COUNT(*) FROM events WHERE value > 15", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let mut reader = SqliteStore::open_read_only(&path, "temp_sensor_1").unwrap();
+        reader.add_events(at_seconds(&[30]));
+        assert_eq!(reader.len(), 2);
+
+        // Every sensor at once, in time order
+        SqliteStore::open(&path, "temp_sensor_2").unwrap().add_events(vec![Event::new(time(15), "temp_sensor_2", Value::Int(15))]);
+        let all = SqliteStore::open_all_read_only(&path).unwrap();
+        assert_eq!(values(&all.get_range(time(0), time(30))), vec![Value::Int(10), Value::Int(15), Value::Int(20)]);
+        assert_eq!((all.len(), all.stats().count, all.get_window_of_n(10).len()), (3, 3, 3));
+        let _ = fs::remove_file(&path);
+        assert!(SqliteStore::open_read_only(&path, "temp_sensor_1").is_none());
+    }
+}
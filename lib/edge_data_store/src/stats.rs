@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use chrono::prelude::*;
+
+use edge_core::Event;
+use super::Evictions;
+use super::Store;
+use super::MAX_DATETIME;
+use super::MIN_DATETIME;
+
+// The insert rate is averaged over this many seconds
+const RATE_WINDOW_S: i64 = 60;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub bytes: usize,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    // Events per second added over the last minute
+    pub insert_rate: f64,
+    pub evictions: Evictions,
+    // Events that arrived older than an event the store had already been given
+    pub out_of_order: u64
+}
+
+// Follows what is added to a store for the parts of its stats it cannot read off its contents
+#[derive(Clone, Debug, Default)]
+pub struct Tracker {
+    newest: Option<DateTime<Utc>>,
+    out_of_order: u64,
+    first_second: Option<i64>,
+    seconds: VecDeque<(i64, u64)>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Stats {
+    // Folds in the stats of another store, e.g. one series of many
+    pub fn merge(&mut self, other: &Stats) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.oldest = match (self.oldest, other.oldest) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b)
+        };
+        self.newest = self.newest.max(other.newest);
+        self.insert_rate += other.insert_rate;
+        self.evictions.count += other.evictions.count;
+        self.evictions.age += other.evictions.age;
+        self.evictions.bytes += other.evictions.bytes;
+        self.out_of_order += other.out_of_order;
+    }
+}
+
+impl Tracker {
+    pub fn new() -> Tracker {
+        Tracker::default()
+    }
+
+    // Called with every batch as it arrives, before the store filters it
+    pub fn added(&mut self, events: &[Event], now: DateTime<Utc>) {
+        if events.is_empty() { return }
+
+        for event in events {
+            match self.newest {
+                Some(newest) if event.timestamp < newest => self.out_of_order += 1,
+                _ => self.newest = Some(event.timestamp)
+            }
+        }
+
+        let second = now.timestamp();
+        self.first_second.get_or_insert(second);
+
+        match self.seconds.back_mut() {
+            Some((last, count)) if *last == second => *count += events.len() as u64,
+            _ => self.seconds.push_back((second, events.len() as u64))
+        }
+
+        while self.seconds.front().is_some_and(|(oldest, _)| *oldest <= second - RATE_WINDOW_S) {
+            self.seconds.pop_front();
+        }
+    }
+
+    // The tracked parts of a store's stats, the store fills in the rest
+    pub fn stats(&self, now: DateTime<Utc>) -> Stats {
+        Stats {
+            insert_rate: self.insert_rate(now),
+            out_of_order: self.out_of_order,
+            ..Stats::default()
+        }
+    }
+
+    pub fn out_of_order(&self) -> u64 {
+        self.out_of_order
+    }
+
+    // Averaged over the time since the first insert while that is under a minute
+    pub fn insert_rate(&self, now: DateTime<Utc>) -> f64 {
+        let first_second = match self.first_second {
+            Some(first_second) => first_second,
+            None => return 0.0
+        };
+
+        let second = now.timestamp();
+        let count: u64 = self.seconds.iter()
+            .filter(|(at, _)| *at > second - RATE_WINDOW_S && *at <= second)
+            .map(|(_, count)| count)
+            .sum();
+        let span = (second - first_second + 1).clamp(1, RATE_WINDOW_S);
+
+        count as f64 / span as f64
+    }
+}
+
+// Oldest and newest timestamps in a store without walking it
+pub fn bounds<S: Store + ?Sized>(store: &S) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let oldest = store.iter_from(MIN_DATETIME).next().map(|event| event.timestamp);
+    let newest = store.iter_before(MAX_DATETIME).next().map(|event| event.timestamp);
+    (oldest, newest)
+}
+
+// Stats read by walking every event, for stores that keep no counts of their own
+pub fn scan<S: Store + ?Sized>(store: &S) -> Stats {
+    let mut stats = Stats::default();
+
+    for event in store.iter_from(MIN_DATETIME) {
+        stats.count += 1;
+        stats.bytes += event.size_bytes();
+        stats.oldest.get_or_insert(event.timestamp);
+        stats.newest = Some(event.timestamp);
+    }
+
+    stats
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use chrono::Duration;
+    use edge_core::ManualClock;
+    use edge_core::Retention;
+    use edge_core::Value;
+    use super::super::CompressedStore;
+    use super::super::InMemory;
+    use super::super::SeriesStore;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn events(seconds: &[i64]) -> Vec<Event> {
+        seconds.iter().map(|s| Event::new(time(*s), "temp_sensor_1", Value::Int(*s))).collect()
+    }
+
+    #[test]
+    fn tracks_rate_and_order() {
+        let mut tracker = Tracker::new();
+        tracker.added(&events(&[10, 12, 11]), time(100));
+        tracker.added(&events(&[9, 13]), time(101));
+
+        assert_eq!(tracker.out_of_order(), 2);
+        assert_eq!(tracker.insert_rate(time(101)), 2.5);
+        assert_eq!(tracker.insert_rate(time(130)), 5.0 / 31.0);
+        assert_eq!(tracker.insert_rate(time(200)), 0.0);
+    }
+
+    #[test]
+    fn stores_agree_with_a_scan() {
+        let clock = Arc::new(ManualClock::new(time(1_000)));
+        let retention = Retention { max_events: Some(4), ..Retention::default() };
+        let mut memory = InMemory::with_retention(retention.clone()).with_clock(clock.clone());
+        let mut compressed = CompressedStore::with_retention(retention).with_clock(clock.clone());
+        let mut series = SeriesStore::new().with_clock(clock.clone());
+
+        for store in [&mut memory as &mut dyn Store, &mut compressed, &mut series] {
+            store.add_events(events(&[990, 992, 991]));
+            clock.advance(Duration::seconds(1));
+            store.add_events(events(&[993, 994, 995]));
+
+            let stats = store.stats();
+            let scanned = scan(store);
+            assert_eq!((stats.count, stats.oldest, stats.newest), (scanned.count, scanned.oldest, scanned.newest));
+            assert_eq!(stats.out_of_order, 1);
+            assert!(stats.insert_rate > 0.0);
+        }
+
+        assert_eq!(memory.stats().count, 4);
+        assert_eq!(memory.stats().bytes, scan(&memory).bytes);
+        assert_eq!(memory.stats().evictions.count, 2);
+    }
+}
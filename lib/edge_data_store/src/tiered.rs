@@ -0,0 +1,326 @@
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use chrono::prelude::*;
+use chrono::Duration;
+
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
+use super::Change;
+use super::Feed;
+use super::Filter;
+use super::InMemory;
+use super::Stats;
+use super::Store;
+use super::Tracker;
+use super::stats::bounds;
+use super::MAX_DATETIME;
+use super::MIN_DATETIME;
+
+const DEFAULT_HOT_MS: i64 = 5 * 60 * 1000;
+const DEFAULT_WARM_MS: i64 = 24 * 60 * 60 * 1000;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Keeps events younger than hot_ms in memory, moves them to the warm store as they age and on to
+// the cold store once they are older than warm_ms. Each tier answers for its own span of time:
+// the cold store for events before archived, the warm store for [archived, spilled) and the hot
+// store from spilled on. Events left behind in the warm store after they are archived are never
+// read again, give it a max_age_ms retention of about warm_ms to free them. Tiers only move
+// when events are added or spill is called. The boundaries are picked back up from what the warm
+// and cold stores hold when the tiered store is built, so persistent tiers survive a restart.
+pub struct Tiered {
+    hot: InMemory,
+    warm: Box<dyn Store + Send>,
+    cold: Box<dyn Store + Send>,
+    hot_ms: i64,
+    warm_ms: i64,
+    spilled: DateTime<Utc>,
+    archived: DateTime<Utc>,
+    clock: Arc<dyn Clock>,
+    tracker: Tracker,
+    feed: Feed
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Tiered {
+    pub fn new(warm: Box<dyn Store + Send>, cold: Box<dyn Store + Send>) -> Tiered {
+        let store = Tiered {
+            hot: InMemory::new(),
+            warm,
+            cold,
+            hot_ms: DEFAULT_HOT_MS,
+            warm_ms: DEFAULT_WARM_MS,
+            spilled: MIN_DATETIME,
+            archived: MIN_DATETIME,
+            clock: Arc::new(SystemClock),
+            tracker: Tracker::new(),
+            feed: Feed::new()
+        };
+
+        store.recover()
+    }
+
+    pub fn with_hot_ms(mut self, hot_ms: i64) -> Tiered {
+        self.hot_ms = hot_ms.max(0);
+        self.warm_ms = self.warm_ms.max(self.hot_ms);
+        self.recover()
+    }
+
+    // Raised to hot_ms if shorter, events go from hot straight to cold then
+    pub fn with_warm_ms(mut self, warm_ms: i64) -> Tiered {
+        self.warm_ms = warm_ms.max(self.hot_ms);
+        self.recover()
+    }
+
+    // Decides when events move between tiers and the span of the window calls, the warm and cold
+    // stores keep their own clocks for retention
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Tiered {
+        self.hot = InMemory::new().with_clock(clock.clone());
+        self.clock = clock;
+        self.recover()
+    }
+
+    pub fn hot(&self) -> &InMemory {
+        &self.hot
+    }
+
+    pub fn warm(&self) -> &dyn Store {
+        self.warm.as_ref()
+    }
+
+    pub fn cold(&self) -> &dyn Store {
+        self.cold.as_ref()
+    }
+
+    // Everything the cold store holds was archived and everything newer in the warm store was
+    // spilled, the hot tier is empty while the store is being built. Nothing moves until the next
+    // add or spill, which uses the final clock.
+    fn recover(mut self) -> Tiered {
+        let after = |newest: Option<DateTime<Utc>>| newest.map(|newest| newest + Duration::nanoseconds(1));
+
+        self.archived = after(bounds(self.cold.as_ref()).1).unwrap_or(MIN_DATETIME);
+        self.spilled = after(bounds(self.warm.as_ref()).1).unwrap_or(MIN_DATETIME).max(self.archived);
+        self
+    }
+
+    // Moves the events that have aged out of their tier, for when no events are being added
+    pub fn spill(&mut self) {
+        let now = self.clock.now();
+        let spilled = self.spilled.max(now - Duration::milliseconds(self.hot_ms));
+        let archived = self.archived.max(now - Duration::milliseconds(self.warm_ms)).min(spilled);
+
+        // Archive before the warm store is written to, so its retention cannot drop events that
+        // have not reached the cold store yet
+        if archived > self.archived {
+            let events = self.warm.get_range(self.archived, archived);
+            if !events.is_empty() {
+                self.cold.add_events(events);
+            }
+        }
+
+        if spilled > self.spilled {
+            let (cold, warm): (Vec<Event>, Vec<Event>) = self.hot.take_before(spilled)
+                .into_iter()
+                .partition(|event| event.timestamp < archived);
+            if !cold.is_empty() { self.cold.add_events(cold) }
+            if !warm.is_empty() { self.warm.add_events(warm) }
+        }
+
+        self.spilled = spilled;
+        self.archived = archived;
+    }
+}
+
+impl Store for Tiered {
+    // Late events go straight to the tier that answers for their time
+    fn add_events(&mut self, events: Vec<Event>) {
+        if events.is_empty() { return }
+
+        self.tracker.added(&events, self.clock.now());
+        let inserted = if self.feed.is_active() { events.clone() } else { Vec::new() };
+        self.spill();
+
+        let (mut hot, mut warm, mut cold) = (Vec::new(), Vec::new(), Vec::new());
+        for event in events {
+            if event.timestamp < self.archived {
+                cold.push(event);
+            } else if event.timestamp < self.spilled {
+                warm.push(event);
+            } else {
+                hot.push(event);
+            }
+        }
+
+        if !cold.is_empty() { self.cold.add_events(cold) }
+        if !warm.is_empty() { self.warm.add_events(warm) }
+        self.hot.add_events(hot);
+        self.feed.inserted(&inserted);
+    }
+
+    fn get_window(&self, win_len_ms: i64) -> Vec<Event> {
+        let now = self.clock.now();
+
+        self.iter_before(now + Duration::nanoseconds(1))
+            .take_while(|event| (now - event.timestamp).num_milliseconds() <= win_len_ms)
+            .collect()
+    }
+
+    fn get_window_of_n(&self, n: u64) -> Vec<Event> {
+        self.iter_before(MAX_DATETIME).take(n as usize).collect()
+    }
+
+    fn iter_from<'a>(&'a self, start: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        Box::new(self.cold.iter_range(start, self.archived)
+            .chain(self.warm.iter_range(start.max(self.archived), self.spilled))
+            .chain(self.hot.iter_from(start.max(self.spilled))))
+    }
+
+    fn iter_before<'a>(&'a self, end: DateTime<Utc>) -> Box<dyn Iterator<Item = Event> + 'a> {
+        let archived = self.archived;
+
+        Box::new(self.hot.iter_before(end)
+            .chain(self.warm.iter_before(end.min(self.spilled)).take_while(move |event| event.timestamp >= archived))
+            .chain(self.cold.iter_before(end.min(archived))))
+    }
+
+    // Only carries inserts, evictions are published by the feed of the cold store
+    fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Change> {
+        self.feed.subscribe(filter, capacity)
+    }
+
+    // Counts and bytes add up the tiers, so events archived but not yet dropped by the warm store's
+    // retention are counted twice. Only events evicted from the cold store are lost.
+    fn stats(&self) -> Stats {
+        let (hot, warm, cold) = (self.hot.stats(), self.warm.stats(), self.cold.stats());
+
+        Stats {
+            count: hot.count + warm.count + cold.count,
+            bytes: hot.bytes + warm.bytes + cold.bytes,
+            oldest: cold.oldest.or(warm.oldest).or(hot.oldest),
+            newest: hot.newest.or(warm.newest).or(cold.newest),
+            evictions: cold.evictions,
+            ..self.tracker.stats(self.clock.now())
+        }
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use edge_core::ManualClock;
+    use edge_core::Retention;
+    use edge_core::Value;
+    use std::fs;
+    use std::process;
+    use edge_core::SyncPolicy;
+    use super::super::CompressedStore;
+    use super::super::FileStore;
+    use super::super::SqliteStore;
+    use super::*;
+
+    fn time(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(seconds, 0).unwrap()
+    }
+
+    fn events(seconds: impl Iterator<Item = i64>) -> Vec<Event> {
+        seconds.map(|s| Event::new(time(s), "temp_sensor_1", Value::Int(s))).collect()
+    }
+
+    fn seconds(events: &[Event]) -> Vec<i64> {
+        events.iter().map(|event| event.timestamp.timestamp()).collect()
+    }
+
+    fn tiered(clock: Arc<ManualClock>) -> Tiered {
+        let warm = InMemory::with_retention(Retention { max_age_ms: Some(100_000), ..Retention::default() })
+            .with_clock(clock.clone());
+        let cold = CompressedStore::new().with_clock(clock.clone());
+
+        Tiered::new(Box::new(warm), Box::new(cold))
+            .with_hot_ms(10_000)
+            .with_warm_ms(100_000)
+            .with_clock(clock)
+    }
+
+    #[test]
+    fn events_move_down_the_tiers() {
+        let clock = Arc::new(ManualClock::new(time(0)));
+        let mut store = tiered(clock.clone());
+
+        for s in 0..300 {
+            clock.set(time(s));
+            store.add_events(events(s..s + 1));
+        }
+
+        assert_eq!(seconds(&store.hot().get_range(MIN_DATETIME, MAX_DATETIME)), (289..300).collect::<Vec<i64>>());
+        assert_eq!(store.warm().get_range(time(199), MAX_DATETIME).len(), 90);
+        assert_eq!(store.cold().get_range(MIN_DATETIME, MAX_DATETIME).len(), 199);
+
+        assert_eq!(seconds(&store.get_range(MIN_DATETIME, MAX_DATETIME)), (0..300).collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.get_window_of_n(300)), (0..300).rev().collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.get_window(15_000)), (284..300).rev().collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.get_before(time(201), 3)), vec![198, 199, 200]);
+        assert_eq!(seconds(&store.get_after(time(288), 3)), vec![289, 290, 291]);
+    }
+
+    #[test]
+    fn late_events_land_in_their_tier() {
+        let clock = Arc::new(ManualClock::new(time(1_000)));
+        let mut store = tiered(clock.clone());
+        store.add_events(events(995..1_000));
+
+        clock.set(time(1_200));
+        store.spill();
+        assert!(store.hot().is_empty());
+
+        store.add_events(events(vec![500, 1_150, 1_195].into_iter()));
+        assert_eq!(seconds(&store.cold().get_range(MIN_DATETIME, MAX_DATETIME)), vec![500, 995, 996, 997, 998, 999]);
+        assert_eq!(seconds(&store.warm().get_range(MIN_DATETIME, MAX_DATETIME)), vec![1_150]);
+        assert_eq!(seconds(&store.get_window_of_n(3)), vec![1_195, 1_150, 999]);
+        assert_eq!(store.stats().out_of_order, 1);
+    }
+
+    #[test]
+    fn reopens_persistent_tiers() {
+        let dir = std::env::temp_dir().join(format!("edge_tiered_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let warm_dir = dir.join("temp_sensor_1").to_string_lossy().to_string();
+        let cold_path = dir.join("temp_sensor_1.db").to_string_lossy().to_string();
+
+        let clock = Arc::new(ManualClock::new(time(0)));
+        let open = |clock: Arc<ManualClock>| {
+            let warm = FileStore::open(&warm_dir, SyncPolicy::EveryWrite).unwrap().with_clock(clock.clone());
+            let cold = SqliteStore::open(&cold_path, "temp_sensor_1").unwrap().with_clock(clock.clone());
+            Tiered::new(Box::new(warm), Box::new(cold))
+                .with_hot_ms(10_000)
+                .with_warm_ms(100_000)
+                .with_clock(clock)
+        };
+
+        {
+            let mut store = open(clock.clone());
+            for s in 0..150 {
+                clock.set(time(s));
+                store.add_events(events(s..s + 1));
+            }
+        }
+
+        // Hot events were lost with the process, the rest is read back and keeps moving
+        clock.set(time(200));
+        let mut store = open(clock.clone());
+        assert_eq!(seconds(&store.get_range(MIN_DATETIME, MAX_DATETIME)), (0..139).collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.get_window_of_n(2)), vec![138, 137]);
+        assert_eq!(store.cold().get_range(MIN_DATETIME, MAX_DATETIME).len(), 49);
+
+        store.spill();
+        assert_eq!(seconds(&store.get_range(MIN_DATETIME, MAX_DATETIME)), (0..139).collect::<Vec<i64>>());
+        assert_eq!(seconds(&store.cold().get_range(MIN_DATETIME, MAX_DATETIME)), (0..100).collect::<Vec<i64>>());
+        assert_eq!(store.stats().newest, Some(time(138)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -6,9 +6,18 @@ extern crate serde_json;
 extern crate chrono;
 extern crate edge_core;
 extern crate edge_data_store;
+extern crate tokio;
+extern crate tiny_http;
 
+use std::fmt;
+use std::sync::Arc;
 use chrono::prelude::*;
 
+use edge_core::Event;
+use edge_core::Value;
+use edge_data_store::AsyncStore;
+use edge_data_store::Stats;
+
 pub mod protocol;
 pub mod deserializer;
 pub mod router;
@@ -16,6 +25,8 @@ pub mod service;
 
 pub use self::router::Router;
 pub use self::service::Service;
+pub use self::protocol::ProtocolClient;
+pub use self::protocol::MsgCallback;
 
 
 // Data types
@@ -30,7 +41,13 @@ pub struct Route {
 pub struct Msg {
     pub timestamp: DateTime<Utc>,
     pub version: String,
-    pub data: MsgData
+    // Publishers older than per-sensor streams leave it out, see Service::with_client
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sensor_id: String,
+    pub data: MsgData,
+    // Set by devices that retry, repeats of a message carry the same id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,13 +74,88 @@ pub enum ErrorKind {
     General,
     Thread,
     Mqtt,
+    Http,
+    Store,
 }
 
-#[derive(Debug)]
+// Events for the stream are queued on writer and added to the store in order by a task of the
+// service's runtime
 pub struct Stream {
     pub name: String,
     pub sensor_id: String,
-    //pub store: T
+    pub store: Arc<dyn AsyncStore>,
+    pub writer: tokio::sync::mpsc::Sender<Vec<Event>>
+}
+
+// Storage health of one stream, see Store::stats
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamStats {
+    pub service_name: String,
+    pub stream_name: String,
+    pub sensor_id: String,
+    pub stats: Stats
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stream")
+            .field("name", &self.name)
+            .field("sensor_id", &self.sensor_id)
+            .finish()
+    }
+}
+
+
+// Conversions
+// -------------------------------------------------------------------------------------------------
+impl Msg {
+    // Simple data yields one event per value, tagged with its index when there is more than one,
+    // descriptive data tags each value with its id, window data carries its own timestamps and
+    // anything else is stored as a single text value. Every event carries the message id.
+    pub fn to_events(&self) -> Vec<Event> {
+        let mut events = match &self.data {
+            MsgData::SimpleData { values } => {
+                values.iter()
+                    .enumerate()
+                    .map(|(index, value)| {
+                        let event = Event::new(self.timestamp, &self.sensor_id, Value::Float(*value));
+
+                        if values.len() > 1 {
+                            event.with_tag("index", &index.to_string())
+                        } else {
+                            event
+                        }
+                    })
+                    .collect()
+            },
+            MsgData::DescriptiveData { ids, values } => {
+                ids.iter()
+                    .zip(values.iter())
+                    .map(|(id, value)| {
+                        Event::new(self.timestamp, &self.sensor_id, Value::Float(*value))
+                            .with_tag("id", id)
+                    })
+                    .collect()
+            },
+            MsgData::WindowData { timestamps, values } => {
+                timestamps.iter()
+                    .zip(values.iter())
+                    .map(|(timestamp, value)| Event::new(*timestamp, &self.sensor_id, Value::Float(*value)))
+                    .collect()
+            },
+            MsgData::Other { value } => {
+                vec![Event::new(self.timestamp, &self.sensor_id, Value::Text(value.clone()))]
+            }
+        };
+
+        if let Some(msg_id) = &self.msg_id {
+            for event in events.iter_mut() {
+                event.msg_id = Some(msg_id.clone());
+            }
+        }
+
+        events
+    }
 }
 
 
@@ -71,8 +163,68 @@ pub struct Stream {
 // -------------------------------------------------------------------------------------------------
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn msg(data: MsgData) -> Msg {
+        Msg {
+            timestamp: Utc::now(),
+            version: "0.1.0".to_string(),
+            sensor_id: String::from("temp_sensor_1"),
+            data,
+            msg_id: None
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn simple_data_to_events() {
+        let events = msg(MsgData::SimpleData { values: vec![10.0, 12.0] }).to_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].sensor_id, "temp_sensor_1");
+        assert_eq!(events[1].value, Value::Float(12.0));
+        assert_eq!(events[1].tags.get("index"), Some(&String::from("1")));
+
+        let events = msg(MsgData::SimpleData { values: vec![10.0] }).to_events();
+        assert!(events[0].tags.is_empty());
+    }
+
+    #[test]
+    fn descriptive_data_to_events() {
+        let ids = vec![String::from("a"), String::from("b")];
+        let events = msg(MsgData::DescriptiveData { ids, values: vec![10.0, 12.0] }).to_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].tags.get("id"), Some(&String::from("a")));
+        assert_eq!(events[1].value, Value::Float(12.0));
+    }
+
+    #[test]
+    fn window_data_to_events() {
+        let timestamps = vec![Utc::now(), Utc::now()];
+        let data = MsgData::WindowData { timestamps: timestamps.clone(), values: vec![1.0, 2.0] };
+        let events = msg(data).to_events();
+        assert_eq!(events[0].timestamp, timestamps[0]);
+        assert_eq!(events[1].timestamp, timestamps[1]);
+        assert_eq!(events[1].value, Value::Float(2.0));
+    }
+
+    #[test]
+    fn msg_id_carried_by_events() {
+        let mut msg = msg(MsgData::SimpleData { values: vec![10.0, 12.0] });
+        msg.msg_id = Some(String::from("42"));
+        assert!(msg.to_events().iter().all(|event| event.msg_id == msg.msg_id));
+
+        let json = r#"{"timestamp":"2019-05-01T00:00:00Z","version":"0.1.0","sensor_id":"a","data":{"msg_type":"other","value":"on"}}"#;
+        assert_eq!(serde_json::from_str::<Msg>(json).unwrap().msg_id, None);
+    }
+
+    #[test]
+    fn other_data_to_events() {
+        let events = msg(MsgData::Other { value: String::from("on") }).to_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].value, Value::Text(String::from("on")));
+    }
 }
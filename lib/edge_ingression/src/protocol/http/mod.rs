@@ -0,0 +1,3 @@
+pub mod server;
+
+pub use self::server::Server;
\ No newline at end of file
@@ -0,0 +1,401 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use tiny_http::Header;
+use tiny_http::Response;
+
+use super::super::super::ProtocolError;
+use super::super::super::ErrorKind;
+use super::super::super::Msg;
+use super::super::MsgCallback;
+use super::super::ProtocolClient;
+use edge_core::Protocol;
+use edge_core::ServiceInfo;
+
+const SENSOR_SEGMENT: &str = "{sensor_id}";
+// Larger bodies are refused with 413 rather than read into memory
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// Accepts msgs POSTed as json, one object or an array of them, and replies with the status of
+// each msg in the order they were sent
+pub struct Server {
+    host: String,
+    port: u32,
+    routes: Routes,
+    callback: Option<Arc<MsgCallback>>,
+    http: Mutex<Option<Arc<tiny_http::Server>>>
+}
+
+#[derive(Clone, Debug)]
+struct Routes {
+    paths: Vec<Vec<String>>,
+    sensor_header: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MsgStatus {
+    pub index: usize,
+    pub accepted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Reply {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub results: Vec<MsgStatus>
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+impl Server {
+    pub fn new(service_info: &ServiceInfo) -> Option<Server> {
+        println!("Creating new HTTP server...");
+
+        let (port, paths, sensor_header) = match &service_info.protocol {
+            Protocol::Http { port, paths, sensor_header } => (port, paths, sensor_header),
+            _ => {
+                println!("Not an HTTP service: {:?}", service_info.name);
+                return None
+            }
+        };
+
+        if paths.is_empty() {
+            println!("HTTP service has no paths: {:?}", service_info.name);
+            return None
+        }
+
+        Some(Server {
+            host: service_info.host.clone(),
+            port: *port,
+            routes: Routes {
+                paths: paths.iter().map(|path| segments(path)).collect(),
+                sensor_header: sensor_header.clone()
+            },
+            callback: None,
+            http: Mutex::new(None)
+        })
+    }
+
+    // The port actually bound, differs from the config when that asks for port 0
+    pub fn local_port(&self) -> Option<u16> {
+        self.listener()
+            .and_then(|http| http.server_addr().to_ip())
+            .map(|addr| addr.port())
+    }
+
+    fn listener(&self) -> Option<Arc<tiny_http::Server>> {
+        match self.http.lock() {
+            Ok(http) => http.clone(),
+            Err(_) => None
+        }
+    }
+}
+
+impl ProtocolClient for Server {
+    fn set_msg_callback(&mut self, callback: MsgCallback) {
+        self.callback = Some(Arc::new(callback));
+    }
+
+    fn connect(&mut self) -> Result<(), ProtocolError> {
+        if self.is_connected() {
+            println!("HTTP server already listening");
+            return Ok(());
+        }
+
+        let addr = [self.host.as_str(), ":", &self.port.to_string()].concat();
+        println!("HTTP server listening on: {}", addr);
+
+        match tiny_http::Server::http(addr.as_str()) {
+            Ok(http) => {
+                if let Ok(mut listener) = self.http.lock() {
+                    *listener = Some(Arc::new(http));
+                }
+
+                return Ok(());
+            },
+            Err(e) => {
+                let error = ErrorKind::Http;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: format!("Error listening on {}: {}", addr, e)
+                });
+                return result;
+            }
+        }
+    }
+
+    // Serves requests on a thread of its own until disconnect
+    fn subscribe(&mut self) -> Result<(), ProtocolError> {
+        let (http, callback) = match (self.listener(), self.callback.clone()) {
+            (Some(http), Some(callback)) => (http, callback),
+            _ => {
+                let error = ErrorKind::Http;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: String::from("HTTP server is not listening or has no msg callback")
+                });
+                return result;
+            }
+        };
+        let routes = self.routes.clone();
+
+        thread::spawn(move || {
+            for mut request in http.incoming_requests() {
+                let (status, reply) = match read_body(&mut request) {
+                    Ok(body) => {
+                        let sensor = routes.sensor_header.as_ref().and_then(|name| {
+                            request.headers().iter()
+                                .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+                                .map(|header| header.value.as_str().to_string())
+                        });
+
+                        handle(&routes, callback.as_ref(), request.method().as_str(), request.url(), sensor, &body)
+                    },
+                    Err(reply) => reply
+                };
+
+                let mut response = Response::from_string(reply).with_status_code(status);
+                if let Ok(header) = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]) {
+                    response = response.with_header(header);
+                }
+
+                if let Err(e) = request.respond(response) {
+                    println!("Error replying to HTTP request: {:?}", e);
+                }
+            }
+
+            println!("HTTP server stopped");
+        });
+
+        return Ok(());
+    }
+
+    fn publish(&self, _topic: Option<&str>, _msg: &Msg) -> Result<(), ProtocolError> {
+        let error = ErrorKind::Http;
+        let result = Result::Err(ProtocolError{
+            kind: error,
+            msg: String::from("HTTP services only receive msgs")
+        });
+
+        return result;
+    }
+
+    fn disconnect(&self) -> Result<(), ProtocolError> {
+        println!("Stopping HTTP server...");
+
+        if let Ok(mut listener) = self.http.lock() {
+            if let Some(http) = listener.take() {
+                http.unblock();
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn is_connected(&self) -> bool {
+        self.listener().is_some()
+    }
+}
+
+// Reads at most MAX_BODY_BYTES, whether or not the client sent a Content-Length
+fn read_body(request: &mut tiny_http::Request) -> Result<String, (u16, String)> {
+    let too_large = || (413, error_body(&format!("Body is larger than {} bytes", MAX_BODY_BYTES)));
+    if request.body_length().is_some_and(|length| length > MAX_BODY_BYTES) {
+        return Err(too_large());
+    }
+
+    let mut body = String::new();
+    match request.as_reader().take(MAX_BODY_BYTES as u64 + 1).read_to_string(&mut body) {
+        Ok(length) if length > MAX_BODY_BYTES => Err(too_large()),
+        Ok(_) => Ok(body),
+        Err(e) => Err((400, error_body(&format!("Error reading body: {}", e))))
+    }
+}
+
+// The status code and json body replying to one request. The stream is named by a path segment
+// first, then the sensor header, then the msg itself.
+fn handle(routes: &Routes, callback: &MsgCallback, method: &str, url: &str, sensor_header: Option<String>,
+          body: &str) -> (u16, String) {
+    let path = segments(url.split('?').next().unwrap_or(""));
+
+    let sensor = match routes.paths.iter().find_map(|pattern| match_path(pattern, &path)) {
+        Some(sensor) => sensor.or(sensor_header),
+        None => return (404, error_body(&format!("No route for path: {}", url)))
+    };
+
+    if method != "POST" {
+        return (405, error_body(&format!("Method not allowed: {}", method)));
+    }
+
+    let items = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(items)) => items,
+        Ok(item @ serde_json::Value::Object(_)) => vec![item],
+        Ok(_) => return (400, error_body("Body is not a msg or an array of msgs")),
+        Err(e) => return (400, error_body(&format!("Error parsing body: {}", e)))
+    };
+
+    let results: Vec<MsgStatus> = items.into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let result = match serde_json::from_value::<Msg>(item) {
+                Ok(mut msg) => {
+                    if let Some(sensor) = &sensor {
+                        msg.sensor_id = sensor.clone();
+                    }
+
+                    callback(msg).map_err(|e| e.msg)
+                },
+                Err(e) => Err(format!("Invalid msg: {}", e))
+            };
+
+            match result {
+                Ok(_) => MsgStatus { index, accepted: true, error: None },
+                Err(e) => MsgStatus { index, accepted: false, error: Some(e) }
+            }
+        })
+        .collect();
+
+    let accepted = results.iter().filter(|status| status.accepted).count();
+    let rejected = results.len() - accepted;
+    let status = match (accepted, rejected) {
+        (_, 0) => 200,
+        (0, _) => 422,
+        _ => 207
+    };
+
+    let reply = Reply { accepted, rejected, results };
+    (status, serde_json::to_string(&reply).unwrap_or_default())
+}
+
+// Some(None) when the path matches without naming a sensor
+fn match_path(pattern: &[String], path: &[String]) -> Option<Option<String>> {
+    if pattern.len() != path.len() { return None }
+
+    let mut sensor = None;
+    for (expected, actual) in pattern.iter().zip(path.iter()) {
+        if expected == SENSOR_SEGMENT {
+            sensor = Some(actual.clone());
+        } else if expected != actual {
+            return None
+        }
+    }
+
+    Some(sensor)
+}
+
+fn segments(path: &str) -> Vec<String> {
+    path.split('/').filter(|segment| !segment.is_empty()).map(String::from).collect()
+}
+
+fn error_body(error: &str) -> String {
+    serde_json::json!({ "error": error }).to_string()
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+    use edge_core::DeserializerType;
+    use super::*;
+
+    const MSG: &str = r#"{"timestamp": "2019-05-01T12:00:00Z", "version": "0.1.0", "sensor_id": "temp_sensor_1",
+                          "data": {"msg_type": "simple_data", "values": [10.0]}}"#;
+
+    fn routes() -> Routes {
+        Routes {
+            paths: vec![segments("/msgs"), segments("/sensors/{sensor_id}/msgs")],
+            sensor_header: Some(String::from("X-Sensor-Id"))
+        }
+    }
+
+    // Accepts msgs for temp_sensor_* only
+    fn callback() -> MsgCallback {
+        Box::new(|msg: Msg| {
+            if msg.sensor_id.starts_with("temp_sensor_") {
+                Ok(())
+            } else {
+                Err(ProtocolError { kind: ErrorKind::Store, msg: format!("No stream found for sensor: {}", msg.sensor_id) })
+            }
+        })
+    }
+
+    fn reply(body: &str) -> Reply {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn replies_per_msg() {
+        let batch = format!("[{}, {{\"bad\": true}}]", MSG);
+
+        let (status, body) = handle(&routes(), &callback(), "POST", "/msgs?debug=1", None, MSG);
+        assert_eq!((status, reply(&body).accepted), (200, 1));
+
+        let (status, body) = handle(&routes(), &callback(), "POST", "/msgs", None, &batch);
+        let body = reply(&body);
+        assert_eq!((status, body.accepted, body.rejected), (207, 1, 1));
+        assert!(body.results[1].error.as_ref().unwrap().starts_with("Invalid msg"));
+
+        let (status, body) = handle(&routes(), &callback(), "POST", "/sensors/pump_1/msgs", None, MSG);
+        assert_eq!(status, 422);
+        assert_eq!(reply(&body).results[0].error, Some(String::from("No stream found for sensor: pump_1")));
+
+        let (status, _) = handle(&routes(), &callback(), "POST", "/msgs", Some(String::from("temp_sensor_2")), MSG);
+        assert_eq!(status, 200);
+        assert_eq!(handle(&routes(), &callback(), "POST", "/other", None, MSG).0, 404);
+        assert_eq!(handle(&routes(), &callback(), "GET", "/msgs", None, MSG).0, 405);
+        assert_eq!(handle(&routes(), &callback(), "POST", "/msgs", None, "42").0, 400);
+    }
+
+    #[test]
+    fn serves_posts() {
+        let service_info = ServiceInfo {
+            name: String::from("Edge HTTP"),
+            debug: true,
+            host: String::from("127.0.0.1"),
+            protocol: Protocol::Http { port: 0, paths: vec![String::from("/sensors/{sensor_id}")], sensor_header: None },
+            deserializer: DeserializerType::Json
+        };
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+
+        let mut server = Server::new(&service_info).unwrap();
+        server.set_msg_callback(Box::new(move |msg| {
+            tx.lock().unwrap().send(msg).unwrap();
+            Ok(())
+        }));
+        server.connect().unwrap();
+        server.subscribe().unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", server.local_port().unwrap())).unwrap();
+        write!(stream, "POST /sensors/temp_sensor_9 HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               MSG.len(), MSG).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(rx.recv().unwrap().sensor_id, "temp_sensor_9");
+
+        let mut stream = TcpStream::connect(("127.0.0.1", server.local_port().unwrap())).unwrap();
+        write!(stream, "POST /sensors/temp_sensor_9 HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+               MAX_BODY_BYTES + 1).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"));
+        assert!(server.publish(None, &serde_json::from_str(MSG).unwrap()).is_err());
+
+        server.disconnect().unwrap();
+        assert!(!server.is_connected());
+    }
+}
@@ -1 +1,77 @@
-pub mod mqtt;
\ No newline at end of file
+pub mod mqtt;
+pub mod http;
+
+use super::Msg;
+use super::ProtocolError;
+use edge_core::Protocol;
+use edge_core::ServiceInfo;
+
+// Called with every message a client receives, from whichever thread the transport delivers on.
+// An error means the service rejected the message, e.g. there is no stream for its sensor.
+pub type MsgCallback = Box<dyn Fn(Msg) -> Result<(), ProtocolError> + Send + Sync>;
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+// A transport a Service receives messages over. The service hands the client its callback before
+// connecting, a new transport only needs a Protocol variant, a client and an arm in create_client.
+pub trait ProtocolClient: Send {
+    fn connect(&mut self) -> Result<(), ProtocolError>;
+
+    // Starts delivering messages from the sources in the service's protocol config
+    fn subscribe(&mut self) -> Result<(), ProtocolError>;
+
+    // No topic sends to the default destination in the protocol config
+    fn publish(&self, topic: Option<&str>, msg: &Msg) -> Result<(), ProtocolError>;
+
+    fn disconnect(&self) -> Result<(), ProtocolError>;
+    fn is_connected(&self) -> bool;
+    fn set_msg_callback(&mut self, callback: MsgCallback);
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+// Lets a service pick its client from config at runtime
+impl<C: ProtocolClient + ?Sized> ProtocolClient for Box<C> {
+    fn connect(&mut self) -> Result<(), ProtocolError> {
+        self.as_mut().connect()
+    }
+
+    fn subscribe(&mut self) -> Result<(), ProtocolError> {
+        self.as_mut().subscribe()
+    }
+
+    fn publish(&self, topic: Option<&str>, msg: &Msg) -> Result<(), ProtocolError> {
+        self.as_ref().publish(topic, msg)
+    }
+
+    fn disconnect(&self) -> Result<(), ProtocolError> {
+        self.as_ref().disconnect()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.as_ref().is_connected()
+    }
+
+    fn set_msg_callback(&mut self, callback: MsgCallback) {
+        self.as_mut().set_msg_callback(callback)
+    }
+}
+
+pub fn create_client(service_info: &ServiceInfo) -> Option<Box<dyn ProtocolClient>> {
+    match &service_info.protocol {
+        Protocol::Mqtt { .. } => {
+            match mqtt::Client::new(service_info) {
+                Some(client) => Some(Box::new(client)),
+                None => None
+            }
+        },
+        Protocol::Http { .. } => {
+            match http::Server::new(service_info) {
+                Some(server) => Some(Box::new(server)),
+                None => None
+            }
+        }
+    }
+}
@@ -1,17 +1,21 @@
 use std::time::Duration;
-use std::sync::mpsc::Sender;
 
 use super::super::super::ProtocolError;
 use super::super::super::ErrorKind;
 use super::super::super::Msg;
 use super::super::super::deserializer::json::Json;
+use super::super::MsgCallback;
+use super::super::ProtocolClient;
 use edge_core::Protocol;
 use edge_core::DeserializerType;
 use edge_core::ServiceInfo;
 
 
 pub struct Client {
-    paho: paho_mqtt::AsyncClient
+    paho: paho_mqtt::AsyncClient,
+    deserializer: DeserializerType,
+    pub_topic: String,
+    sub_topics: Vec<String>
 }
 
 unsafe impl Send for Client {}
@@ -26,11 +30,19 @@ fn on_connect_failure(_paho_client: &paho_mqtt::AsyncClient, _msgid: u16, rc: i3
 
 
 impl Client {
-    pub fn new(service_info: &ServiceInfo, transmitter: Sender<Msg>) -> Option<Client> {
+    pub fn new(service_info: &ServiceInfo) -> Option<Client> {
         println!("Creating new MQTT client...");
 
+        let (port, pub_topic, sub_topics) = match &service_info.protocol {
+            Protocol::Mqtt { port, pub_topic, sub_topics } => (port, pub_topic, sub_topics),
+            _ => {
+                println!("Not an MQTT service: {:?}", service_info.name);
+                return None
+            }
+        };
+
         let conn_str = ["tcp://", &service_info.host, ":", 
-                    &service_info.protocol.port.to_string()].concat();
+                    &port.to_string()].concat();
 
         println!("MQTT connection string: {}", conn_str);
 
@@ -48,19 +60,27 @@ impl Client {
         };
 
         let mut client = Client {
-            paho: paho
+            paho: paho,
+            deserializer: service_info.deserializer.clone(),
+            pub_topic: pub_topic.clone(),
+            sub_topics: sub_topics.clone()
         };
 
         client.paho.set_connection_lost_callback(|_paho_client: &paho_mqtt::AsyncClient| {
             println!("Connection lost to the MQTT broker");
         });
+        
+        Some(client)
+    }
+}
 
-        let deserializer = match service_info.deserializer {
-            DeserializerType::Json => { Json{} },
-            _ => return None
+impl ProtocolClient for Client {
+    fn set_msg_callback(&mut self, callback: MsgCallback) {
+        let deserializer = match self.deserializer {
+            DeserializerType::Json => { Json{} }
         };
 
-        client.paho.set_message_callback(move |_paho_client, msg| {
+        self.paho.set_message_callback(move |_paho_client, msg| {
             if let Some(msg) = msg {
                 let topic = msg.topic();
                 let payload_str = msg.payload_str();
@@ -68,7 +88,9 @@ impl Client {
 
                 match deserializer.parse_msg(&payload_str) {
                     Some(msg) => {
-                        transmitter.send(msg);
+                        if let Err(e) = callback(msg) {
+                            println!("Dropping MQTT msg: {}", e.msg);
+                        }
                     },
                     None => {
                         
@@ -76,11 +98,9 @@ impl Client {
                 }
             }
         });
-        
-        Some(client)
     }
 
-    pub fn connect(&mut self) -> Result<(), ProtocolError> {
+    fn connect(&mut self) -> Result<(), ProtocolError> {
         if !self.paho.is_connected() {
             let lwt = paho_mqtt::Message::new("test", "Lost connect to MQTT broker", 1);
 
@@ -94,7 +114,12 @@ impl Client {
             let result = self.paho.connect_with_callbacks(conn_opts, on_connect_success, on_connect_failure);
 
             if let Err(e) = result.wait() {
-                println!("Error connecting to MQTT broker: {:?}", e);
+                let error = ErrorKind::Mqtt;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: format!("Error connecting to MQTT broker: {:?}", e)
+                });
+                return result;
             }
 
             return Ok(());
@@ -105,18 +130,28 @@ impl Client {
         return Ok(());
     }
 
-    pub fn start_subscriber(&mut self, protocol: Protocol) -> Result<(), ProtocolError> {
+    fn subscribe(&mut self) -> Result<(), ProtocolError> {
         println!("Subscribing to MQTT topics...");
-        let subscriptions = protocol.sub_topics;
-        let qos = [1, 1];
-        self.paho.subscribe_many(&subscriptions, &qos);
+        let qos = vec![1; self.sub_topics.len()];
+        let result = self.paho.subscribe_many(&self.sub_topics, &qos);
+
+        if let Err(e) = result.wait() {
+            let error = ErrorKind::Mqtt;
+            let result = Result::Err(ProtocolError{
+                kind: error,
+                msg: format!("Error subscribing to MQTT topics: {:?}", e)
+            });
+            return result;
+        }
+
         println!("MQTT client waiting for messages...");
 
         return Ok(());
     }
 
-    pub fn send_msg(&self, topic: &str, msg: &Msg) -> Result<(), ProtocolError> {
+    fn publish(&self, topic: Option<&str>, msg: &Msg) -> Result<(), ProtocolError> {
         println!("MQTT client sending a msg...");
+        let topic = topic.unwrap_or(&self.pub_topic);
 
         let msg_str = match serde_json::to_string(&msg) {
             Ok(msg_str) => {
@@ -160,7 +195,7 @@ impl Client {
         return result;
     }
 
-    pub fn disconnect(&self) -> Result<(), ProtocolError> {
+    fn disconnect(&self) -> Result<(), ProtocolError> {
         println!("Attempting to disconnect from MQTT broker...");
         if self.paho.is_connected() {
             self.paho.disconnect(None);
@@ -172,7 +207,7 @@ impl Client {
         return Ok(());
     }
 
-    pub fn is_connected(&self) -> bool {
+    fn is_connected(&self) -> bool {
         self.paho.is_connected()
     }
 }
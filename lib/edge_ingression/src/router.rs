@@ -1,23 +1,34 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
 
 use super::Service;
 use super::Msg;
 use super::Route;
+use super::StreamStats;
+use edge_core::Clock;
+use edge_core::SystemClock;
 use edge_core::StreamInfo;
 use edge_core::ServiceInfo;
+use edge_data_store::Change;
+use edge_data_store::Filter;
 
 pub struct Router {
-    services: HashMap<String, Service>
+    services: HashMap<String, Service>,
+    clock: Arc<dyn Clock>
 }
 
 
 impl Router {
     pub fn new() -> Router {
-        let router = Router {
-            services: HashMap::new()
-        };
+        Router::with_clock(Arc::new(SystemClock))
+    }
 
-        return router
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Router {
+        Router {
+            services: HashMap::new(),
+            clock
+        }
     }
 
     pub fn get_route_names(&self) -> Vec<String> {
@@ -94,6 +105,43 @@ impl Router {
         }
     }
 
+    pub fn subscribe(&self, service_name: &str, sensor_id: &str, filter: Filter, capacity: usize) -> Option<Receiver<Change>> {
+        match self.services.get(service_name) {
+            Some(service) => {
+                match service.subscribe(sensor_id, filter, capacity) {
+                    Ok(receiver) => Some(receiver),
+                    Err(e) => {
+                        println!("Error subscribing to sensor: {:?} {:?}", sensor_id, e.msg);
+                        None
+                    }
+                }
+            },
+            _ => {
+                println!("Service not found: {:?}", service_name);
+                None
+            },
+        }
+    }
+
+    // Storage health of every route, ordered by service then stream
+    pub fn get_route_stats(&self) -> Vec<StreamStats> {
+        let mut route_stats = Vec::<StreamStats>::new();
+
+        for (_, service) in self.services.iter() {
+            match service.get_stream_stats() {
+                Ok(stats) => {
+                    route_stats.extend(stats);
+                },
+                Err(e) => {
+                    println!("Error reading stats of service: {:?} {:?}", service.name, e.msg);
+                }
+            }
+        }
+
+        route_stats.sort_by(|a, b| a.service_name.cmp(&b.service_name));
+        return route_stats
+    }
+
     pub fn num_routes(&self) -> usize {
         let mut total = 0;
 
@@ -130,7 +178,7 @@ impl Router {
                 println!("Creating service: {:?}", service_info.name);
                 let key = service_info.name.clone();
 
-                match Service::new(service_info.name.clone(), service_info) {
+                match Service::with_clock(service_info.name.clone(), service_info, self.clock.clone()) {
                     Some(service) => {
                         println!("Service created");
                         self.services.insert(key, service);
@@ -159,8 +207,10 @@ impl Router {
     }
 
     pub fn start(&mut self) {
-        for (_, service) in self.services.iter_mut() {
-            service.start();
+        for (name, service) in self.services.iter_mut() {
+            if let Err(e) = service.start() {
+                println!("Error starting service {:?}: {}", name, e.msg);
+            }
         }
     }
 
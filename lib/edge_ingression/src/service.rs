@@ -1,47 +1,139 @@
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
 use std::sync::mpsc::{Receiver, channel};
+use tokio::runtime::Builder;
+use tokio::runtime::Handle;
+use tokio::runtime::Runtime;
 
-use super::protocol::mqtt::Client;
+use super::protocol;
+use super::protocol::ProtocolClient;
 use super::ProtocolError;
 use super::ErrorKind;
 use super::Msg;
 use super::Stream;
+use super::StreamStats;
+use edge_core::Clock;
+use edge_core::Event;
+use edge_core::SystemClock;
 use edge_core::StreamInfo;
 use edge_core::ServiceInfo;
 use edge_core::StoreType;
+use edge_core::Retention;
+use edge_data_store::AsyncStore;
+use edge_data_store::Blocking;
+use edge_data_store::Change;
+use edge_data_store::Filter;
 use edge_data_store::Store;
 use edge_data_store::InMemory;
-
-
-pub struct Service {
+use edge_data_store::CompressedStore;
+use edge_data_store::RedisStore;
+use edge_data_store::FileStore;
+use edge_data_store::SqliteStore;
+use edge_data_store::MmapStore;
+use edge_data_store::Tiered;
+use edge_data_store::Deduped;
+
+// Batches queued for a stream before the receiver thread waits for its store to catch up
+const WRITE_BACKLOG: usize = 1024;
+
+// Receives messages over client and stores them in the stream of their sensor. The client is
+// picked from the service's protocol config unless one is given to with_client.
+pub struct Service<C: ProtocolClient = Box<dyn ProtocolClient>> {
     pub name: String,
     service_info: ServiceInfo,
     streams: Arc<Mutex<HashMap<String, Stream>>>,
-    client: Client,
+    client: C,
     rx: Arc<Mutex<Receiver<Msg>>>,
+    clock: Arc<dyn Clock>,
+    runtime: Runtime
 }
 
 impl Service {
     pub fn new(name: String, service_info: ServiceInfo) -> Option<Service> {
-        println!("Creating new service...");
-        let (tx, rx) = channel();
+        Service::with_clock(name, service_info, Arc::new(SystemClock))
+    }
 
-        let client = match Client::new(&service_info, tx) {
+    pub fn with_clock(name: String, service_info: ServiceInfo, clock: Arc<dyn Clock>) -> Option<Service> {
+        let client = match protocol::create_client(&service_info) {
             Some(client) => client,
             None => {
                 return None
             },
         };
 
+        Service::with_client(name, service_info, client, clock)
+    }
+}
+
+impl<C: ProtocolClient> Service<C> {
+    pub fn with_client(name: String, service_info: ServiceInfo, mut client: C, clock: Arc<dyn Clock>) -> Option<Service<C>> {
+        println!("Creating new {} service...", service_info.protocol.name());
+        let (tx, rx) = channel();
+        let streams: Arc<Mutex<HashMap<String, Stream>>> = Arc::new(Mutex::new(HashMap::new()));
+        let known_streams = streams.clone();
+
+        // Msgs for sensors without a stream are rejected here, so the client can tell the sender.
+        // A msg without a sensor id goes to the service's stream when it only has the one.
+        client.set_msg_callback(Box::new(move |mut msg| {
+            match known_streams.lock() {
+                Ok(streams) => {
+                    if msg.sensor_id.is_empty() {
+                        match streams.keys().next() {
+                            Some(sensor_id) if streams.len() == 1 => msg.sensor_id = sensor_id.clone(),
+                            _ => {
+                                return Err(ProtocolError{
+                                    kind: ErrorKind::Store,
+                                    msg: format!("Msg has no sensor id and there are {} streams", streams.len())
+                                });
+                            }
+                        }
+                    }
+
+                    if !streams.contains_key(&msg.sensor_id) {
+                        return Err(ProtocolError{
+                            kind: ErrorKind::Store,
+                            msg: format!("No stream found for sensor: {}", msg.sensor_id)
+                        });
+                    }
+                },
+                Err(_) => {
+                    return Err(ProtocolError{
+                        kind: ErrorKind::Thread,
+                        msg: String::from("Error requesting stream lock")
+                    });
+                }
+            }
+
+            match tx.send(msg) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(ProtocolError{
+                    kind: ErrorKind::Thread,
+                    msg: String::from("Service receiver has stopped")
+                })
+            }
+        }));
+
+        // Store calls run on its blocking pool, so a slow store only holds up its own stream
+        let runtime = match Builder::new_multi_thread().worker_threads(1).thread_name("edge_store").build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                println!("Error creating store runtime: {:?}", e);
+                return None
+            }
+        };
+
         let mqtt_service = Service {
             name:  name,
             service_info: service_info,
-            streams: Arc::new(Mutex::new(HashMap::new())),
+            streams,
             client: client,
-            rx: Arc::new(Mutex::new(rx))
+            rx: Arc::new(Mutex::new(rx)),
+            clock,
+            runtime
         };
 
         return Some(mqtt_service);
@@ -51,11 +143,14 @@ impl Service {
         &self.name
     }
 
+    pub fn get_info(&self) -> &ServiceInfo {
+        &self.service_info
+    }
+
     pub fn start(&mut self) -> Result<(), ProtocolError> {
         println!("Starting service...");
-        self.client.connect();
-        let protocol = self.service_info.protocol.clone();
-        self.client.start_subscriber(protocol);
+        self.client.connect()?;
+        self.client.subscribe()?;
         self.rx_msgs();
 
         return Ok(());
@@ -69,25 +164,33 @@ impl Service {
             match rx_clone.lock() {
                 Ok(rx) => {
                     println!("Starting service receiver thread...");
-                    let mut iter = rx.iter();
 
-                    loop {
-                        let msg = iter.next();
+                    for msg in rx.iter() {
                         println!("Service received msg: {:?}", msg);
 
-                        match streams_clone.lock() {
+                        // The lock is only held to find the stream, not while storing
+                        let writer = match streams_clone.lock() {
                             Ok(streams) => {
-                                match streams.get("temp_sensor_1") {
-                                    Some(stream) => {
-                                        println!(">>>>>> GOT STREAM: {:?}", stream);
-                                    },
+                                match streams.get(&msg.sensor_id) {
+                                    Some(stream) => Some((stream.name.clone(), stream.writer.clone())),
                                     None => {
-                                        println!("No stream found for data");
+                                        println!("No stream found for sensor: {:?}", msg.sensor_id);
+                                        None
                                     }
-                                };
+                                }
                             },
                             Err(_) => {
                                 println!("Error locking streams");
+                                None
+                            }
+                        };
+
+                        if let Some((name, writer)) = writer {
+                            let events = msg.to_events();
+                            println!("Storing {} events in stream: {:?}", events.len(), name);
+
+                            if writer.blocking_send(events).is_err() {
+                                println!("Stream was removed: {:?}", name);
                             }
                         }
                     }
@@ -125,14 +228,7 @@ impl Service {
     }
 
     pub fn send_msg(&self, topic: Option<&str>, msg: &Msg) -> Result<(), ProtocolError> {
-        match topic {
-            Some(topic) => {
-                self.client.send_msg(&topic.to_string(), msg)
-            },
-            _ => {
-                self.client.send_msg(&self.service_info.protocol.pub_topic.to_string(), msg)
-            },
-        }
+        self.client.publish(topic, msg)
     }
 
     pub fn is_connected(&self) -> bool {
@@ -140,13 +236,36 @@ impl Service {
     }
 
     pub fn add_stream(&mut self, stream_info: StreamInfo) -> Result<(), ProtocolError> {
+        let store = match self.create_store(&stream_info) {
+            Some(store) => store,
+            None => {
+                let error = ErrorKind::Store;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: String::from("Error creating stream store")
+                });
+                return result;
+            }
+        };
+
+        let store: Arc<dyn AsyncStore> = Arc::new(Blocking::new(store, self.runtime.handle().clone()));
+        let (writer, mut queue) = tokio::sync::mpsc::channel::<Vec<Event>>(WRITE_BACKLOG);
+        let writer_store = store.clone();
+
+        // Ends once the stream is removed and its queue drained
+        self.runtime.spawn(async move {
+            while let Some(events) = queue.recv().await {
+                writer_store.add_events(events).await;
+            }
+        });
+
         match self.streams.lock() {
             Ok(mut streams) => {
-                // let store = self.create_store(stream_info.store_type);
                 let stream = Stream {
                     name: stream_info.name.to_string(),
                     sensor_id: stream_info.sensor_id.to_string(),
-                    //store: store
+                    store,
+                    writer
                 };
 
                 println!("Adding stream: {:?} to service: {:?}", stream.sensor_id, self.name);
@@ -165,18 +284,106 @@ impl Service {
         }
     }
 
-/*
-    fn create_store(&self, store_type: StoreType) -> Store {
-        match store_type {
-            StoreType::InMemory => {
-                return InMemory::new()
+    fn create_store(&self, stream_info: &StreamInfo) -> Option<Box<dyn Store + Send>> {
+        let store = self.open_store(stream_info)?;
+
+        match stream_info.dedup {
+            Some(dedup) => Some(Box::new(Deduped::new(store, dedup).with_clock(self.clock.clone()))),
+            None => Some(store)
+        }
+    }
+
+    fn open_store(&self, stream_info: &StreamInfo) -> Option<Box<dyn Store + Send>> {
+        println!("Creating {:?} store for sensor: {:?}", stream_info.store_type, stream_info.sensor_id);
+
+        match &stream_info.store_type {
+            StoreType::InProcessMemory => {
+                let store = InMemory::with_retention(stream_info.retention.clone())
+                    .with_clock(self.clock.clone());
+                Some(Box::new(store))
+            },
+            StoreType::CompressedMemory { block_ms } => {
+                let store = CompressedStore::with_retention(stream_info.retention.clone())
+                    .with_block_ms(*block_ms)
+                    .with_clock(self.clock.clone());
+                Some(Box::new(store))
+            },
+            StoreType::Redis { url } => {
+                match RedisStore::new(url, &stream_info.sensor_id) {
+                    Some(store) => {
+                        let store = store.with_retention(stream_info.retention.clone())
+                            .with_clock(self.clock.clone());
+                        Some(Box::new(store))
+                    },
+                    None => None
+                }
             },
-            StoreType::Redis => {
+            StoreType::File { path, sync } => {
+                let dir = Path::new(path).join(&stream_info.sensor_id);
+
+                match FileStore::open(&dir.to_string_lossy(), *sync) {
+                    Some(store) => {
+                        let store = store.with_retention(stream_info.retention.clone())
+                            .with_clock(self.clock.clone());
+                        Some(Box::new(store))
+                    },
+                    None => None
+                }
+            },
+            StoreType::Sqlite { path } => {
+                match SqliteStore::open(path, &stream_info.sensor_id) {
+                    Some(store) => {
+                        let store = store.with_retention(stream_info.retention.clone())
+                            .with_clock(self.clock.clone());
+                        Some(Box::new(store))
+                    },
+                    None => None
+                }
+            },
+            StoreType::Mmap { path, capacity } => {
+                if let Err(e) = fs::create_dir_all(path) {
+                    println!("Error creating mmap store dir: {:?}", e);
+                    return None
+                }
 
+                let file = Path::new(path).join(format!("{}.mmap", stream_info.sensor_id));
+
+                match MmapStore::open(&file.to_string_lossy(), *capacity) {
+                    Some(store) => {
+                        let store = store.with_retention(stream_info.retention.clone())
+                            .with_clock(self.clock.clone());
+                        Some(Box::new(store))
+                    },
+                    None => None
+                }
+            },
+            StoreType::Tiered { path, sync, hot_ms, warm_ms } => {
+                let dir = Path::new(path).join(&stream_info.sensor_id);
+                let archive = Path::new(path).join(format!("{}.db", stream_info.sensor_id));
+
+                // Opening the file store creates path for the archive
+                let warm = FileStore::open(&dir.to_string_lossy(), *sync);
+                let cold = SqliteStore::open(&archive.to_string_lossy(), &stream_info.sensor_id);
+
+                match (warm, cold) {
+                    (Some(warm), Some(cold)) => {
+                        // The stream's retention applies to the archive, the file store only
+                        // holds events until they are archived
+                        let warm = warm.with_retention(Retention { max_age_ms: Some(*warm_ms), ..Retention::default() })
+                            .with_clock(self.clock.clone());
+                        let cold = cold.with_retention(stream_info.retention.clone())
+                            .with_clock(self.clock.clone());
+                        let store = Tiered::new(Box::new(warm), Box::new(cold))
+                            .with_hot_ms(*hot_ms)
+                            .with_warm_ms(*warm_ms)
+                            .with_clock(self.clock.clone());
+                        Some(Box::new(store))
+                    },
+                    _ => None
+                }
             }
         }
     }
-*/
 
     pub fn remove_stream(&mut self, key: &str) -> Result<(), ProtocolError> {
         match self.streams.lock() {
@@ -237,6 +444,99 @@ impl Service {
         }
     }
 
+    // The store of the stream for sensor_id, await its queries on runtime()
+    pub fn store(&self, sensor_id: &str) -> Result<Arc<dyn AsyncStore>, ProtocolError> {
+        match self.streams.lock() {
+            Ok(streams) => {
+                match streams.get(sensor_id) {
+                    Some(stream) => Ok(stream.store.clone()),
+                    None => {
+                        let error = ErrorKind::Store;
+                        let result = Result::Err(ProtocolError{
+                            kind: error,
+                            msg: format!("No stream found for sensor: {}", sensor_id)
+                        });
+                        return result;
+                    }
+                }
+            },
+            Err(_) => {
+                let error = ErrorKind::Thread;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: String::from("Error requesting stream lock")
+                });
+                return result;
+            }
+        }
+    }
+
+    pub fn runtime(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+
+    // Changes to the store of the stream for sensor_id, see Store::subscribe
+    pub fn subscribe(&self, sensor_id: &str, filter: Filter, capacity: usize) -> Result<Receiver<Change>, ProtocolError> {
+        match self.streams.lock() {
+            Ok(streams) => {
+                match streams.get(sensor_id) {
+                    Some(stream) => {
+                        println!("Subscribing to stream: {:?} on service: {:?}", stream.name, self.name);
+                        Ok(stream.store.subscribe(filter, capacity))
+                    },
+                    None => {
+                        let error = ErrorKind::Store;
+                        let result = Result::Err(ProtocolError{
+                            kind: error,
+                            msg: format!("No stream found for sensor: {}", sensor_id)
+                        });
+                        return result;
+                    }
+                }
+            },
+            Err(_) => {
+                let error = ErrorKind::Thread;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: String::from("Error requesting stream lock")
+                });
+                return result;
+            }
+        }
+    }
+
+    // The stores are read after the stream lock is released, a store busy with a write is waited on
+    // without holding up the receiver
+    pub fn get_stream_stats(&self) -> Result<Vec<StreamStats>, ProtocolError> {
+        let streams: Vec<(String, String, Arc<dyn AsyncStore>)> = match self.streams.lock() {
+            Ok(streams) => {
+                streams.values()
+                    .map(|stream| (stream.name.clone(), stream.sensor_id.clone(), stream.store.clone()))
+                    .collect()
+            },
+            Err(_) => {
+                let error = ErrorKind::Thread;
+                let result = Result::Err(ProtocolError{
+                    kind: error,
+                    msg: String::from("Error requesting stream lock")
+                });
+                return result;
+            }
+        };
+
+        let mut stats: Vec<StreamStats> = streams.into_iter()
+            .map(|(stream_name, sensor_id, store)| StreamStats {
+                service_name: self.name.clone(),
+                stream_name,
+                sensor_id,
+                stats: store.stats()
+            })
+            .collect();
+        stats.sort_by(|a, b| a.stream_name.cmp(&b.stream_name));
+
+        return Result::Ok(stats)
+    }
+
     pub fn num_streams(&self) -> Result<usize, ProtocolError> {
         match self.streams.lock() {
             Ok(streams) => {
@@ -254,4 +554,113 @@ impl Service {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::prelude::*;
+    use edge_core::DeserializerType;
+    use edge_core::Protocol;
+    use edge_core::ConflictPolicy;
+    use edge_core::Dedup;
+    use edge_core::DedupKey;
+    use edge_core::Retention;
+    use super::protocol::MsgCallback;
+    use super::super::MsgData;
+    use super::*;
+
+    // Hands every published message straight back to the service
+    struct Loopback {
+        callback: Option<MsgCallback>,
+        connected: bool
+    }
+
+    impl ProtocolClient for Loopback {
+        fn connect(&mut self) -> Result<(), ProtocolError> {
+            self.connected = true;
+            Ok(())
+        }
+
+        fn subscribe(&mut self) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        fn publish(&self, _topic: Option<&str>, msg: &Msg) -> Result<(), ProtocolError> {
+            let payload = serde_json::to_string(msg).unwrap();
+
+            if let Some(callback) = &self.callback {
+                callback(serde_json::from_str(&payload).unwrap())?;
+            }
+
+            Ok(())
+        }
+
+        fn disconnect(&self) -> Result<(), ProtocolError> {
+            Ok(())
+        }
+
+        fn is_connected(&self) -> bool {
+            self.connected
+        }
+
+        fn set_msg_callback(&mut self, callback: MsgCallback) {
+            self.callback = Some(callback);
+        }
+    }
+
+    #[test]
+    fn stores_msgs_from_any_client() {
+        let service_info = ServiceInfo {
+            name: String::from("Loopback"),
+            debug: true,
+            host: String::from("localhost"),
+            protocol: Protocol::Mqtt { port: 1883, pub_topic: String::from("test/"), sub_topics: Vec::new() },
+            deserializer: DeserializerType::Json
+        };
+        let client = Loopback { callback: None, connected: false };
+        let mut service = Service::with_client(String::from("Loopback"), service_info, client, Arc::new(SystemClock)).unwrap();
+
+        service.add_stream(StreamInfo {
+            name: String::from("Temp sensor"),
+            sensor_id: String::from("temp_sensor_1"),
+            store_type: StoreType::InProcessMemory,
+            retention: Retention::default(),
+            dedup: Some(Dedup::new(DedupKey::MsgId, ConflictPolicy::KeepFirst))
+        }).unwrap();
+        service.start().unwrap();
+        assert!(service.is_connected());
+
+        let msg = Msg {
+            timestamp: Utc.timestamp_opt(1_556_712_000, 0).unwrap(),
+            version: "0.1.0".to_string(),
+            sensor_id: String::from("temp_sensor_1"),
+            data: MsgData::SimpleData { values: vec![10.0, 12.0] },
+            msg_id: Some(String::from("1"))
+        };
+        service.send_msg(None, &msg).unwrap();
+        service.send_msg(None, &msg).unwrap();
+
+        // Stored in order, the redelivery never reaches the store
+        let next = Msg { data: MsgData::SimpleData { values: vec![11.0] }, msg_id: Some(String::from("2")), ..msg };
+        service.send_msg(None, &next).unwrap();
+
+        // Publishers that predate sensor ids reach the only stream
+        let legacy = Msg { sensor_id: String::new(), msg_id: Some(String::from("3")), ..next };
+        service.send_msg(None, &legacy).unwrap();
+
+        let mut stats = Vec::new();
+        for _ in 0..100 {
+            stats = service.get_stream_stats().unwrap();
+            if stats[0].stats.count == 4 { break }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(stats[0].stats.count, 4);
+        assert_eq!(stats[0].stream_name, "Temp sensor");
+    }
+}
@@ -1,54 +1,64 @@
 extern crate edge_ingression;
 
+use std::sync::Arc;
 use chrono::prelude::*;
 
+use edge_core::Clock;
+use edge_core::ManualClock;
 use edge_core::Protocol;
 use edge_core::ServiceInfo;
 use edge_core::DeserializerType;
 use edge_core::StreamInfo;
 use edge_core::StoreType;
+use edge_core::Retention;
 use edge_ingression::Msg;
 use edge_ingression::Router;
 use edge_ingression::MsgData;
 
 
 
-fn send_simple_data(router: &Router, service_name: &str, topic: &str) {
+fn send_simple_data(router: &Router, clock: &dyn Clock, service_name: &str, topic: &str) {
     let sensor_data = MsgData::SimpleData{ values: vec![10.0, 12.0] };
-    let utc: DateTime<Utc> = Utc::now();
+    let utc: DateTime<Utc> = clock.now();
 
     let msg = Msg {
         timestamp: utc,
         version: "0.1.0".to_string(),
-        data: sensor_data  
+        sensor_id: String::from("temp_sensor_1"),
+        data: sensor_data,
+        msg_id: None
     };
 
     router.send_msg(service_name, topic, &msg);
 }
 
-fn send_descriptive_data(router: &Router, service_name: &str, topic: &str) {
+fn send_descriptive_data(router: &Router, clock: &dyn Clock, service_name: &str, topic: &str) {
     let sensor_data = MsgData::DescriptiveData{ ids: vec![String::from("a"), String::from("b")], 
                                                 values: vec![10.0, 12.0] };
-    let utc: DateTime<Utc> = Utc::now();
+    let utc: DateTime<Utc> = clock.now();
 
     let msg = Msg {
         timestamp: utc,
         version: "0.1.0".to_string(),
-        data: sensor_data  
+        sensor_id: String::from("temp_sensor_1"),
+        data: sensor_data,
+        msg_id: None
     };
 
     router.send_msg(service_name, topic, &msg);
 }
 
-fn send_window_data(router: &Router, service_name: &str, topic: &str) {
-    let sensor_data = MsgData::WindowData{ timestamps: vec![Utc::now(), Utc::now()], 
+fn send_window_data(router: &Router, clock: &dyn Clock, service_name: &str, topic: &str) {
+    let sensor_data = MsgData::WindowData{ timestamps: vec![clock.now(), clock.now()], 
                                            values: vec![10.0, 12.0] };
-    let utc: DateTime<Utc> = Utc::now();
+    let utc: DateTime<Utc> = clock.now();
 
     let msg = Msg {
         timestamp: utc,
         version: "0.1.0".to_string(),
-        data: sensor_data  
+        sensor_id: String::from("temp_sensor_1"),
+        data: sensor_data,
+        msg_id: None
     };
 
     router.send_msg(service_name, topic, &msg);
@@ -57,8 +67,7 @@ fn send_window_data(router: &Router, service_name: &str, topic: &str) {
 
 #[test]
 fn test_mqtt_service() {
-    let protocol = Protocol {
-        name: String::from("mqtt"),
+    let protocol = Protocol::Mqtt {
         port: 1883,
         pub_topic: String::from("test/"),
         sub_topics: vec![String::from("test/"), 
@@ -77,10 +86,13 @@ fn test_mqtt_service() {
     let simple_stream = StreamInfo {
         name: String::from("Temp sensor"),
         sensor_id: String::from("temp_sensor_1"),
-        store_type: StoreType::InProcessMemory
+        store_type: StoreType::InProcessMemory,
+        retention: Retention::default(),
+        dedup: None
     };
 
-    let mut router = Router::new();
+    let clock = Arc::new(ManualClock::new(Utc.timestamp_opt(1_556_712_000, 0).unwrap()));
+    let mut router = Router::with_clock(clock.clone());
     let service_name = service_info.name.clone();
     router.add_service(service_info);
     let route = router.add_route(service_name.as_str(), simple_stream);
@@ -89,15 +101,15 @@ fn test_mqtt_service() {
 
     let topic = "test/";
     println!("Msg #1");
-    send_simple_data(&router, service_name.as_str(), topic);
+    send_simple_data(&router, clock.as_ref(), service_name.as_str(), topic);
     println!("");
 
     println!("Msg #2");
-    send_descriptive_data(&router, service_name.as_str(), topic);
+    send_descriptive_data(&router, clock.as_ref(), service_name.as_str(), topic);
     println!("");
 
     println!("Msg #3");
-    send_window_data(&router, service_name.as_str(), topic);
+    send_window_data(&router, clock.as_ref(), service_name.as_str(), topic);
     println!("");
 
     loop {}
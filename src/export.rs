@@ -0,0 +1,142 @@
+use chrono::prelude::*;
+
+use edge_data_store::export;
+use edge_data_store::snapshot;
+use edge_data_store::FileStore;
+use edge_data_store::Filter;
+use edge_data_store::Format;
+use edge_data_store::InMemory;
+use edge_data_store::MmapStore;
+use edge_data_store::Selection;
+use edge_data_store::SqliteStore;
+use edge_data_store::Store;
+
+pub const USAGE: &str = "Usage: rusty_edge export --source <snapshot:PATH|file:DIR|sqlite:PATH|mmap:PATH> --out PATH
+                         [--sensor ID] [--tag KEY=VALUE]... [--start RFC3339] [--end RFC3339]
+                         [--format csv|parquet]";
+
+
+// Data types
+// -------------------------------------------------------------------------------------------------
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub source: String,
+    pub out: String,
+    pub format: Format,
+    pub selection: Selection
+}
+
+
+// Implementation
+// -------------------------------------------------------------------------------------------------
+// Runs the export subcommand, args are those after "export". Returns the number of events written.
+pub fn run(args: &[String]) -> Result<usize, String> {
+    let args = parse(args)?;
+    let store = open_source(&args.source, &args.selection.filter)?;
+
+    export::export(store.as_ref(), &args.selection, args.format, &args.out)
+        .map_err(|e| format!("Failed to export to {}: {}", args.out, e))
+}
+
+pub fn parse(args: &[String]) -> Result<Args, String> {
+    let mut source = None;
+    let mut out = None;
+    let mut format = None;
+    let mut selection = Selection::all();
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(format!("Missing value for {}", flag))?;
+
+        match flag.as_str() {
+            "--source" => source = Some(value.clone()),
+            "--out" => out = Some(value.clone()),
+            "--format" => format = Some(Format::from_name(value).ok_or(format!("Unknown format: {}", value))?),
+            "--sensor" => selection.filter = selection.filter.sensor(value),
+            "--tag" => {
+                let (key, tag) = value.split_once('=').ok_or(format!("Tag is not KEY=VALUE: {}", value))?;
+                selection.filter = selection.filter.tag(key, tag);
+            },
+            "--start" => selection.start = parse_time(value)?,
+            "--end" => selection.end = parse_time(value)?,
+            _ => return Err(format!("Unknown option: {}", flag))
+        }
+    }
+
+    let source = source.ok_or("Missing --source")?;
+    let out = out.ok_or("Missing --out")?;
+    let format = match format {
+        Some(format) => format,
+        None => Format::from_path(&out).ok_or(format!("Cannot tell the format of {}, use --format", out))?
+    };
+
+    Ok(Args { source, out, format, selection })
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("Bad time {}: {}", value, e))
+}
+
+// Stores are opened read only so an export can run beside the service writing them. A sqlite
+// database holds the events of every sensor, only the one given by --sensor is read when set.
+fn open_source(source: &str, filter: &Filter) -> Result<Box<dyn Store>, String> {
+    let (kind, path) = source.split_once(':').ok_or(format!("Source is not KIND:PATH: {}", source))?;
+
+    match kind {
+        "snapshot" => {
+            let mut store = InMemory::new();
+            snapshot::restore(&mut store, path).map_err(|e| format!("Failed to read snapshot {}: {}", path, e))?;
+            Ok(Box::new(store))
+        },
+        "file" => {
+            let store = FileStore::open_read_only(path).ok_or(format!("Failed to open file store: {}", path))?;
+            Ok(Box::new(store))
+        },
+        "mmap" => {
+            let store = MmapStore::open_read_only(path).ok_or(format!("Failed to open mmap store: {}", path))?;
+            Ok(Box::new(store))
+        },
+        "sqlite" => {
+            let store = match &filter.sensor_id {
+                Some(sensor_id) => SqliteStore::open_read_only(path, sensor_id),
+                None => SqliteStore::open_all_read_only(path)
+            };
+            Ok(Box::new(store.ok_or(format!("Failed to open sqlite store: {}", path))?))
+        },
+        _ => Err(format!("Unknown source: {}", kind))
+    }
+}
+
+
+// Tests
+// -------------------------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_selection() {
+        let parsed = parse(&args("--source file:/data --out temps.csv --sensor temp_sensor_1 --tag index=1 \
+                                  --start 2019-05-01T00:00:00Z --end 2019-05-02T00:00:00+00:00")).unwrap();
+
+        assert_eq!(parsed.format, Format::Csv);
+        assert_eq!(parsed.selection.filter, Filter::new().sensor("temp_sensor_1").tag("index", "1"));
+        assert_eq!(parsed.selection.start, Utc.with_ymd_and_hms(2019, 5, 1, 0, 0, 0).unwrap());
+        assert_eq!(parsed.selection.end, Utc.with_ymd_and_hms(2019, 5, 2, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_args() {
+        assert!(parse(&args("--out temps.csv")).is_err());
+        assert!(parse(&args("--source file:/data --out temps.txt")).is_err());
+        assert!(parse(&args("--source file:/data --out temps.txt --format xml")).is_err());
+        assert!(parse(&args("--source file:/data --out temps.csv --start yesterday")).is_err());
+        assert!(run(&args("--source sqlite:/data/edge.db --out temps.csv")).is_err());
+    }
+}
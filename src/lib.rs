@@ -1,5 +1,26 @@
+pub mod export;
+
 extern crate edge_ingression as ingression;
+extern crate edge_data_store;
+extern crate edge_core;
+extern crate chrono;
+
+use edge_core::DeserializerType;
+use edge_core::Protocol;
+use edge_core::ServiceInfo;
+
+pub fn mqtt_start() {
+    let service_info = ServiceInfo {
+        name: String::from("test"),
+        debug: true,
+        host: String::from("localhost"),
+        protocol: Protocol::Mqtt {
+            port: 1883,
+            pub_topic: String::from("test/"),
+            sub_topics: vec![String::from("test/")]
+        },
+        deserializer: DeserializerType::Json
+    };
 
-pub fn mqtt_start() -> () {
-    ingression::mqtt::client::Client::new("test".to_string());
-}
\ No newline at end of file
+    ingression::protocol::mqtt::client::Client::new(&service_info);
+}
@@ -1,6 +1,23 @@
-
+use std::env;
+use std::process;
 
 fn main() {
-    println!("Starting program...");
-    rusty_edge::mqtt_start();
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("export") => {
+            match rusty_edge::export::run(&args[2..]) {
+                Ok(count) => println!("Exported {} events", count),
+                Err(e) => {
+                    println!("Error exporting: {}", e);
+                    println!("{}", rusty_edge::export::USAGE);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => {
+            println!("Starting program...");
+            rusty_edge::mqtt_start();
+        }
+    }
 }